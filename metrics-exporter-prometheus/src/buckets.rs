@@ -0,0 +1,97 @@
+//! Generators for histogram bucket bounds.
+
+use crate::BuildError;
+
+/// Creates `count` bucket bounds, where the lowest bound is `start`, and each bound is `width` more
+/// than the previous one.
+///
+/// The bounds can be given to [`PrometheusBuilder::set_buckets`][crate::PrometheusBuilder::set_buckets]
+/// and related methods, and match those of `LinearBuckets` in the Prometheus Go client library.
+///
+/// ## Errors
+///
+/// If `count` is zero, or `width` is not positive, an error variant will be returned.
+pub fn linear_buckets(start: f64, width: f64, count: usize) -> Result<Vec<f64>, BuildError> {
+    if count == 0 {
+        return Err(BuildError::EmptyBucketsOrQuantiles);
+    }
+    if width.is_nan() || width <= 0.0 {
+        return Err(BuildError::InvalidBucketParameters("width must be positive"));
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    Ok((0..count).map(|i| start + i as f64 * width).collect())
+}
+
+/// Creates `count` bucket bounds, where the lowest bound is `start`, and each bound is `factor`
+/// times the previous one.
+///
+/// The bounds can be given to [`PrometheusBuilder::set_buckets`][crate::PrometheusBuilder::set_buckets]
+/// and related methods, and match those of `ExponentialBuckets` in the Prometheus Go client
+/// library.
+///
+/// ## Errors
+///
+/// If `count` is zero, `start` is not positive, or `factor` is not greater than one, an error
+/// variant will be returned.
+pub fn exponential_buckets(start: f64, factor: f64, count: usize) -> Result<Vec<f64>, BuildError> {
+    if count == 0 {
+        return Err(BuildError::EmptyBucketsOrQuantiles);
+    }
+    if start.is_nan() || start <= 0.0 {
+        return Err(BuildError::InvalidBucketParameters("start must be positive"));
+    }
+    if factor.is_nan() || factor <= 1.0 {
+        return Err(BuildError::InvalidBucketParameters("factor must be greater than 1"));
+    }
+
+    let mut bound = start;
+    let mut buckets = Vec::with_capacity(count);
+    for _ in 0..count {
+        buckets.push(bound);
+        bound *= factor;
+    }
+    Ok(buckets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exponential_buckets, linear_buckets};
+    use crate::BuildError;
+
+    #[test]
+    fn test_linear_buckets() {
+        // Reference values from the examples of the Go client library.
+        assert_eq!(
+            linear_buckets(-15.0, 5.0, 6).unwrap(),
+            vec![-15.0, -10.0, -5.0, 0.0, 5.0, 10.0]
+        );
+        assert_eq!(linear_buckets(1.0, 0.5, 3).unwrap(), vec![1.0, 1.5, 2.0]);
+
+        assert!(matches!(linear_buckets(0.0, 1.0, 0), Err(BuildError::EmptyBucketsOrQuantiles)));
+        for width in [0.0, -1.0, f64::NAN] {
+            assert!(matches!(
+                linear_buckets(0.0, width, 3),
+                Err(BuildError::InvalidBucketParameters(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_exponential_buckets() {
+        // Reference values from the examples and tests of the Go client library.
+        assert_eq!(exponential_buckets(100.0, 1.2, 3).unwrap(), vec![100.0, 120.0, 144.0]);
+        assert_eq!(exponential_buckets(1.0, 2.0, 5).unwrap(), vec![1.0, 2.0, 4.0, 8.0, 16.0]);
+
+        assert!(matches!(
+            exponential_buckets(1.0, 2.0, 0),
+            Err(BuildError::EmptyBucketsOrQuantiles)
+        ));
+        for (start, factor) in [(0.0, 2.0), (-1.0, 2.0), (1.0, 1.0), (1.0, 0.5), (f64::NAN, 2.0)] {
+            assert!(matches!(
+                exponential_buckets(start, factor, 3),
+                Err(BuildError::InvalidBucketParameters(_))
+            ));
+        }
+    }
+}
@@ -1,10 +1,17 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
 
-use crate::{distribution::Distribution, PrometheusRecorder};
+use crate::{
+    distribution::{Distribution, DistributionKind},
+    formatting::with_namespace,
+    PrometheusRecorder,
+};
 
-use crate::formatting::sanitize_metric_name;
-use indexmap::IndexMap;
-use metrics::SetRecorderError;
+use metrics::{SetRecorderError, SharedString, Unit};
 use thiserror::Error;
 
 /// Matches a metric name in a specific way.
@@ -31,14 +38,120 @@ impl Matcher {
         }
     }
 
-    /// Creates a sanitized version of this matcher.
-    pub(crate) fn sanitized(self) -> Matcher {
+    /// Creates a version of this matcher sanitized with the given function.
+    pub(crate) fn sanitized<F>(self, sanitize: F) -> Matcher
+    where
+        F: Fn(&str) -> String,
+    {
         match self {
-            Matcher::Prefix(prefix) => Matcher::Prefix(sanitize_metric_name(prefix.as_str())),
-            Matcher::Suffix(suffix) => Matcher::Suffix(sanitize_metric_name(suffix.as_str())),
-            Matcher::Full(full) => Matcher::Full(sanitize_metric_name(full.as_str())),
+            Matcher::Prefix(prefix) => Matcher::Prefix(sanitize(prefix.as_str())),
+            Matcher::Suffix(suffix) => Matcher::Suffix(sanitize(suffix.as_str())),
+            Matcher::Full(full) => Matcher::Full(sanitize(full.as_str())),
         }
     }
+
+    /// Creates a version of this matcher which matches names prefixed with the given namespace.
+    ///
+    /// Suffix matchers are unaffected by the namespace, and so are returned as-is.
+    pub(crate) fn namespaced(self, namespace: Option<&str>) -> Matcher {
+        match self {
+            Matcher::Prefix(prefix) => Matcher::Prefix(with_namespace(namespace, prefix)),
+            Matcher::Suffix(suffix) => Matcher::Suffix(suffix),
+            Matcher::Full(full) => Matcher::Full(with_namespace(namespace, full)),
+        }
+    }
+}
+
+/// A custom function for sanitizing metric names.
+#[derive(Clone)]
+pub(crate) struct NameSanitizer(Arc<dyn Fn(&str) -> String + Send + Sync>);
+
+impl NameSanitizer {
+    pub(crate) fn new<F>(sanitize: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        Self(Arc::new(sanitize))
+    }
+
+    pub(crate) fn sanitize(&self, name: &str) -> String {
+        (self.0)(name)
+    }
+}
+
+impl fmt::Debug for NameSanitizer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NameSanitizer").finish_non_exhaustive()
+    }
+}
+
+/// Function type of a [`NameRewriter`].
+type RewriteFn = dyn for<'a> Fn(&'a str) -> Cow<'a, str> + Send + Sync;
+
+/// A custom function for rewriting metric names after sanitization.
+#[derive(Clone)]
+pub(crate) struct NameRewriter(Arc<RewriteFn>);
+
+impl NameRewriter {
+    pub(crate) fn new<F>(rewrite: F) -> Self
+    where
+        F: Fn(&str) -> Cow<'_, str> + Send + Sync + 'static,
+    {
+        Self(Arc::new(rewrite))
+    }
+
+    pub(crate) fn rewrite(&self, name: String) -> String {
+        match (self.0)(&name) {
+            Cow::Borrowed(rewritten) if rewritten == name => name,
+            rewritten => rewritten.into_owned(),
+        }
+    }
+}
+
+impl fmt::Debug for NameRewriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NameRewriter").finish_non_exhaustive()
+    }
+}
+
+/// Policy for handling a metric name which is registered as more than one kind of metric.
+///
+/// The Prometheus exposition format only allows a single `# TYPE` line per metric name, so a name
+/// which is used for e.g. both a counter and a gauge produces output that scrapers will reject.
+/// The kind a name was first registered as always takes precedence, and the policy determines what
+/// happens to metrics of any other kind registered under the same name.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum KindCollisionPolicy {
+    /// Logs a warning the first time the collision is registered, but otherwise renders the metrics
+    /// as-is.
+    ///
+    /// Warnings are only logged when the `tracing` feature is enabled, which it is by default as
+    /// part of the `http-listener` and `push-gateway` features.
+    #[default]
+    Warn,
+    /// Drops the metrics of any other kind, so that they are not rendered.
+    Drop,
+    /// Renames the metrics of any other kind by appending their kind to the name, such as
+    /// `foo_gauge` for a gauge named `foo` when `foo` was first registered as a counter.
+    Rename,
+}
+
+/// Policy for handling differing descriptions given for the same metric name.
+///
+/// Descriptions are rendered as the `# HELP` line of a metric family, of which there can only be
+/// one.  Different metrics can end up with the same name once sanitized or rewritten, such as
+/// `foo.bar` and `foo-bar`, and each may be described differently, so the policy determines which
+/// description is rendered.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DescriptionCollisionPolicy {
+    /// Keeps the description that was given first, ignoring any later ones.
+    #[default]
+    First,
+    /// Joins all distinct descriptions with `; `, in the order they were first given.
+    ///
+    /// Descriptions which were already given are ignored, so describing a metric repeatedly with
+    /// the same description does not grow it.
+    Concatenate,
 }
 
 /// Errors that could occur while building or installing a Prometheus recorder/exporter.
@@ -71,18 +184,281 @@ pub enum BuildError {
     #[error("attempted to build exporter with no exporters enabled; did you disable default features and forget to enable either the `http-listener` or `push-gateway` features?")]
     MissingExporterConfiguration,
 
+    /// The parameters given to a bucket generator were invalid.
+    #[error("invalid bucket generator parameters: {0}")]
+    InvalidBucketParameters(&'static str),
+
     /// Bucket bounds or quantiles were empty.
     #[error("bucket bounds/quantiles cannot be empty")]
     EmptyBucketsOrQuantiles,
 
+    /// Bucket bounds contained NaN, or, for already bucketed histograms, the same bound twice.
+    #[error("bucket bounds must not be NaN, nor given more than once: {0:?}")]
+    InvalidBuckets(Vec<f64>),
+
+    /// Bucket counts of an already bucketed histogram were not cumulative, or exceeded its count.
+    #[error("bucket counts must be cumulative, and at most the total count: {0:?}")]
+    NonCumulativeBuckets(Vec<(f64, u64)>),
+
+    /// A quantile was outside of the range of 0.0 to 1.0, inclusive, or was NaN.
+    #[error("quantiles must be between 0.0 and 1.0, inclusive: {0}")]
+    InvalidQuantile(f64),
+
+    /// The replacement character for sanitization was not a letter or underscore.
+    #[error("sanitization replacement must be an ASCII letter or underscore: {0:?}")]
+    InvalidSanitizeReplacement(char),
+
+    /// The relative accuracy of summaries was not between 0.0 and 1.0, exclusive.
+    #[error("summary relative accuracy must be between 0.0 and 1.0, exclusive: {0}")]
+    InvalidRelativeAccuracy(f64),
+
+    /// Both histogram buckets and summary quantiles were set for the same metric matcher.
+    #[error("both buckets and quantiles were set for the same metric matcher: {matcher:?}")]
+    ConflictingDistributionConfig {
+        /// The matcher which both were set for.
+        matcher: Matcher,
+    },
+
+    /// The number of age buckets of summaries was zero, or too large.
+    #[error("summary age bucket count must be between 1 and {}: {0}", u32::MAX)]
+    InvalidSummaryAgeBuckets(usize),
+
     /// Bucket duration cannot be zero
     #[error("bucket durations cannot be set to zero")]
     ZeroBucketDuration,
 }
 
-#[derive(Debug)]
+/// An exemplar, such as a trace ID, attached to a histogram sample.
+///
+/// Recorded via
+/// [`PrometheusHandle::record_histogram_with_exemplar`][crate::PrometheusHandle::record_histogram_with_exemplar],
+/// and only rendered in the `OpenMetrics` text format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exemplar {
+    /// Labels of the exemplar, each already formatted as `key="value"`.
+    pub labels: Vec<String>,
+    /// Value of the sample the exemplar is attached to.
+    pub value: f64,
+    /// Time at which the sample was recorded.
+    pub timestamp: SystemTime,
+}
+
+/// A point-in-time snapshot of all metrics, ordered by metric name and then by labels.
+///
+/// Metric names and labels are sanitized exactly as they would be when rendering, with each label
+/// already formatted as `key="value"`, and global labels included.
+///
+/// Obtained via [`PrometheusHandle::snapshot`][crate::PrometheusHandle::snapshot], in order to
+/// export metrics in a custom format.
+#[derive(Clone, Debug)]
 pub struct Snapshot {
-    pub counters: HashMap<String, HashMap<Vec<String>, u64>>,
-    pub gauges: HashMap<String, HashMap<Vec<String>, f64>>,
-    pub distributions: HashMap<String, IndexMap<Vec<String>, Distribution>>,
+    /// Counter values, by metric name and then by labels.
+    pub counters: BTreeMap<String, BTreeMap<Vec<String>, u64>>,
+    /// Gauge values, by metric name and then by labels.
+    pub gauges: BTreeMap<String, BTreeMap<Vec<String>, f64>>,
+    /// Aggregated histogram distributions, by metric name and then by labels.
+    pub distributions: BTreeMap<String, BTreeMap<Vec<String>, Distribution>>,
+    /// Histogram families which are kept without any series, by metric name, along with the kind
+    /// of distribution they would be rendered as.
+    ///
+    /// See [`PrometheusBuilder::keep_registered_families`][crate::PrometheusBuilder::keep_registered_families].
+    pub empty_distributions: BTreeMap<String, DistributionKind>,
+    /// Metric descriptions, by metric name.
+    pub descriptions: HashMap<String, SharedString>,
+    /// Metric units, by metric name.
+    pub units: HashMap<String, Unit>,
+    /// Exemplars attached to histogram samples, by metric name and then by labels.
+    ///
+    /// Each series holds at most one exemplar per bucket, being the most recent one recorded.
+    pub exemplars: HashMap<String, HashMap<Vec<String>, Vec<Exemplar>>>,
+    /// Creation times of counter, histogram, and summary series, by metric name and then by labels.
+    ///
+    /// See [`PrometheusBuilder::with_created_timestamps`][crate::PrometheusBuilder::with_created_timestamps].
+    pub created: HashMap<String, HashMap<Vec<String>, SystemTime>>,
+}
+
+impl Snapshot {
+    /// Retains only the metric families whose name satisfies the given predicate.
+    pub fn retain_names<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str) -> bool,
+    {
+        self.counters.retain(|name, _| f(name));
+        self.gauges.retain(|name, _| f(name));
+        self.distributions.retain(|name, _| f(name));
+        self.empty_distributions.retain(|name, _| f(name));
+    }
+
+    /// Checks whether the snapshot has no metric families at all.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+            && self.gauges.is_empty()
+            && self.distributions.is_empty()
+            && self.empty_distributions.is_empty()
+    }
+
+    /// Removes the first metric family, in the order families are rendered, returning it as a
+    /// snapshot of its own, along with its description, unit, and exemplars.
+    pub(crate) fn pop_family(&mut self) -> Option<Snapshot> {
+        let mut family = Snapshot {
+            counters: BTreeMap::new(),
+            gauges: BTreeMap::new(),
+            distributions: BTreeMap::new(),
+            empty_distributions: BTreeMap::new(),
+            descriptions: HashMap::new(),
+            units: HashMap::new(),
+            exemplars: HashMap::new(),
+            created: HashMap::new(),
+        };
+
+        let name = if let Some((name, by_labels)) = self.counters.pop_first() {
+            family.counters.insert(name.clone(), by_labels);
+            name
+        } else if let Some((name, by_labels)) = self.gauges.pop_first() {
+            family.gauges.insert(name.clone(), by_labels);
+            name
+        } else if let Some((name, by_labels)) = self.distributions.pop_first() {
+            family.distributions.insert(name.clone(), by_labels);
+            name
+        } else {
+            let (name, kind) = self.empty_distributions.pop_first()?;
+            family.empty_distributions.insert(name.clone(), kind);
+            name
+        };
+
+        // A name may be shared by families of different kinds, so its metadata is left in place
+        // for any others.
+        if let Some(description) = self.descriptions.get(&name) {
+            family.descriptions.insert(name.clone(), description.clone());
+        }
+        if let Some(unit) = self.units.get(&name) {
+            family.units.insert(name.clone(), *unit);
+        }
+        if let Some(exemplars) = self.exemplars.get(&name) {
+            family.exemplars.insert(name.clone(), exemplars.clone());
+        }
+        if let Some(created) = self.created.get(&name) {
+            family.created.insert(name, created.clone());
+        }
+        Some(family)
+    }
+
+    /// Merges the metrics of another snapshot into this one.
+    ///
+    /// The series of metric families present in both snapshots are combined.  When a metric family
+    /// is a different kind of metric in each snapshot, or a series is present in both, the metrics of
+    /// this snapshot take precedence, and the collision is logged.
+    pub(crate) fn merge(&mut self, other: Snapshot) {
+        fn merge_families<V, F>(
+            families: &mut BTreeMap<String, BTreeMap<Vec<String>, V>>,
+            other: BTreeMap<String, BTreeMap<Vec<String>, V>>,
+            is_other_kind: F,
+        ) where
+            F: Fn(&str) -> bool,
+        {
+            for (name, by_labels) in other {
+                if is_other_kind(&name) {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(name, "Metric name registered as a different kind of metric by another recorder; skipping.");
+                    continue;
+                }
+
+                let existing = families.entry(name.clone()).or_default();
+                for (labels, value) in by_labels {
+                    if let Entry::Vacant(entry) = existing.entry(labels) {
+                        entry.insert(value);
+                    } else {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(
+                            name,
+                            "Metric series also present in another recorder; skipping."
+                        );
+                    }
+                }
+            }
+        }
+
+        let counters = self.counters.keys().cloned().collect::<HashSet<_>>();
+        let gauges = self.gauges.keys().cloned().collect::<HashSet<_>>();
+        let distributions = self.distributions.keys().cloned().collect::<HashSet<_>>();
+        merge_families(&mut self.counters, other.counters, |name| {
+            gauges.contains(name) || distributions.contains(name)
+        });
+        merge_families(&mut self.gauges, other.gauges, |name| {
+            counters.contains(name) || distributions.contains(name)
+        });
+        merge_families(&mut self.distributions, other.distributions, |name| {
+            counters.contains(name) || gauges.contains(name)
+        });
+
+        for (name, kind) in other.empty_distributions {
+            if !counters.contains(&name) && !gauges.contains(&name) {
+                self.empty_distributions.entry(name).or_insert(kind);
+            }
+        }
+
+        for (name, description) in other.descriptions {
+            self.descriptions.entry(name).or_insert(description);
+        }
+        for (name, unit) in other.units {
+            self.units.entry(name).or_insert(unit);
+        }
+        for (name, by_labels) in other.exemplars {
+            let existing = self.exemplars.entry(name).or_default();
+            for (labels, exemplars) in by_labels {
+                existing.entry(labels).or_insert(exemplars);
+            }
+        }
+        for (name, by_labels) in other.created {
+            let existing = self.created.entry(name).or_default();
+            for (labels, created) in by_labels {
+                existing.entry(labels).or_insert(created);
+            }
+        }
+    }
+
+    /// Adds the given labels, as pairs of sanitized key and formatted label, to every series which
+    /// doesn't already have a label with the same key.
+    ///
+    /// If `sort_labels` is `false`, the labels are appended after those already present.
+    pub(crate) fn add_labels(&mut self, extra: &[(String, String)], sort_labels: bool) {
+        fn add<'a, M, V>(
+            families: impl Iterator<Item = &'a mut M>,
+            extra: &[(String, String)],
+            sort_labels: bool,
+        ) where
+            M: Default
+                + IntoIterator<Item = (Vec<String>, V)>
+                + std::iter::FromIterator<(Vec<String>, V)>
+                + 'a,
+        {
+            for by_labels in families {
+                *by_labels = std::mem::take(by_labels)
+                    .into_iter()
+                    .map(|(mut labels, value)| {
+                        for (key, label) in extra {
+                            if !labels.iter().any(|existing| label_key(existing) == key) {
+                                labels.push(label.clone());
+                            }
+                        }
+                        if sort_labels {
+                            labels.sort_by(|a, b| label_key(a).cmp(label_key(b)));
+                        }
+                        (labels, value)
+                    })
+                    .collect();
+            }
+        }
+
+        add(self.counters.values_mut(), extra, sort_labels);
+        add(self.gauges.values_mut(), extra, sort_labels);
+        add(self.distributions.values_mut(), extra, sort_labels);
+        add(self.exemplars.values_mut(), extra, sort_labels);
+        add(self.created.values_mut(), extra, sort_labels);
+    }
+}
+
+/// Gets the key of a label formatted as `key="value"`.
+fn label_key(label: &str) -> &str {
+    label.split_once('=').map_or(label, |(key, _)| key)
 }
@@ -13,6 +13,10 @@ const DEFAULT_SUMMARY_BUCKET_COUNT: NonZeroU32 = match NonZeroU32::new(3) {
     None => [][0],
 };
 const DEFAULT_SUMMARY_BUCKET_DURATION: Duration = Duration::from_secs(20);
+// These match the defaults of `Summary::with_defaults`.
+const DEFAULT_SUMMARY_RELATIVE_ACCURACY: f64 = 0.0001;
+const SUMMARY_MAX_BUCKETS: u32 = 32_768;
+const SUMMARY_MIN_VALUE: f64 = 1.0e-9;
 
 /// Distribution type.
 #[derive(Clone, Debug)]
@@ -28,17 +32,22 @@ pub enum Distribution {
     /// Computes and exposes value quantiles directly to Prometheus i.e. 50% of
     /// requests were faster than 200ms, and 99% of requests were faster than
     /// 1000ms, etc.
+    ///
+    /// Holds the rolling summary of samples, the quantiles to expose, and the sum of all samples.
     Summary(RollingSummary, Arc<Vec<Quantile>>, f64),
 }
 
 impl Distribution {
     /// Creates a histogram distribution.
     ///
+    /// The buckets are sorted in ascending order, as Prometheus requires them to be rendered.
+    ///
     /// # Panics
     ///
     /// Panics if `buckets` is empty.
     pub fn new_histogram(buckets: &[f64]) -> Distribution {
-        let hist = Histogram::new(buckets).expect("buckets should never be empty");
+        let buckets = sorted_buckets(buckets.to_vec());
+        let hist = Histogram::new(&buckets).expect("buckets should never be empty");
         Distribution::Histogram(hist)
     }
 
@@ -67,6 +76,17 @@ impl Distribution {
     }
 }
 
+/// The kind of distribution to use for histograms by default.
+///
+/// See [`PrometheusBuilder::set_default_distribution`][crate::PrometheusBuilder::set_default_distribution].
+#[derive(Clone, Debug, PartialEq)]
+pub enum DistributionKind {
+    /// Render histograms as Prometheus histograms, with the given bucket bounds.
+    Histogram(Vec<f64>),
+    /// Render histograms as Prometheus summaries, with the given quantiles.
+    Summary(Vec<f64>),
+}
+
 /// Builds distributions for metric names based on a set of configured overrides.
 #[derive(Debug)]
 pub struct DistributionBuilder {
@@ -75,30 +95,91 @@ pub struct DistributionBuilder {
     bucket_duration: Option<Duration>,
     bucket_count: Option<NonZeroU32>,
     bucket_overrides: Option<Vec<(Matcher, Vec<f64>)>>,
+    quantile_overrides: Option<Vec<(Matcher, Arc<Vec<Quantile>>)>>,
+    summary_relative_accuracy: Option<f64>,
+    summary_max_age: Option<Duration>,
 }
 
 impl DistributionBuilder {
     /// Creates a new instance of `DistributionBuilder`.
+    ///
+    /// Buckets are sorted in ascending order, and deduplicated, regardless of the order given.
     pub fn new(
         quantiles: Vec<Quantile>,
         bucket_duration: Option<Duration>,
         buckets: Option<Vec<f64>>,
         bucket_count: Option<NonZeroU32>,
         bucket_overrides: Option<HashMap<Matcher, Vec<f64>>>,
+        quantile_overrides: Option<HashMap<Matcher, Vec<Quantile>>>,
     ) -> DistributionBuilder {
         DistributionBuilder {
             quantiles: Arc::new(quantiles),
             bucket_duration,
-            buckets,
+            buckets: buckets.map(sorted_buckets),
             bucket_count,
             bucket_overrides: bucket_overrides.map(|entries| {
-                let mut matchers = entries.into_iter().collect::<Vec<_>>();
+                let mut matchers = entries
+                    .into_iter()
+                    .map(|(matcher, buckets)| (matcher, sorted_buckets(buckets)))
+                    .collect::<Vec<_>>();
+                matchers.sort_by(|a, b| a.0.cmp(&b.0));
+                matchers
+            }),
+            quantile_overrides: quantile_overrides.map(|entries| {
+                let mut matchers = entries
+                    .into_iter()
+                    .map(|(matcher, quantiles)| (matcher, Arc::new(quantiles)))
+                    .collect::<Vec<_>>();
                 matchers.sort_by(|a, b| a.0.cmp(&b.0));
                 matchers
             }),
+            summary_relative_accuracy: None,
+            summary_max_age: None,
         }
     }
 
+    /// Sets the relative accuracy of the summaries created by this builder.
+    ///
+    /// See [`RollingSummary::with_relative_accuracy`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `accuracy` is not between 0.0 and 1.0, exclusive.
+    #[must_use]
+    pub fn with_summary_relative_accuracy(mut self, accuracy: f64) -> DistributionBuilder {
+        assert!(accuracy > 0.0 && accuracy < 1.0);
+        self.summary_relative_accuracy = Some(accuracy);
+        self
+    }
+
+    /// Sets the maximum age of the samples reflected by the summaries created by this builder.
+    ///
+    /// The age is split evenly between the buckets of each summary, taking precedence over the
+    /// bucket duration the builder was created with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_age` is zero.
+    #[must_use]
+    pub fn with_summary_max_age(mut self, max_age: Duration) -> DistributionBuilder {
+        assert!(!max_age.is_zero());
+        self.summary_max_age = Some(max_age);
+        self
+    }
+
+    fn new_summary(&self, quantiles: Arc<Vec<Quantile>>) -> Distribution {
+        let b_count = self.bucket_count.map_or(DEFAULT_SUMMARY_BUCKET_COUNT, |c| c);
+        let b_duration = match self.summary_max_age {
+            // Buckets can't be shorter than a nanosecond, even if there are more of them than that.
+            Some(max_age) => (max_age / b_count.get()).max(Duration::from_nanos(1)),
+            None => self.bucket_duration.map_or(DEFAULT_SUMMARY_BUCKET_DURATION, |d| d),
+        };
+        let accuracy = self.summary_relative_accuracy.unwrap_or(DEFAULT_SUMMARY_RELATIVE_ACCURACY);
+
+        let summary = RollingSummary::with_relative_accuracy(b_count, b_duration, accuracy);
+        Distribution::Summary(summary, quantiles, 0.0)
+    }
+
     /// Returns a distribution for the given metric key.
     pub fn get_distribution(&self, name: &str) -> Distribution {
         if let Some(ref overrides) = self.bucket_overrides {
@@ -113,10 +194,41 @@ impl DistributionBuilder {
             return Distribution::new_histogram(buckets);
         }
 
-        let b_duration = self.bucket_duration.map_or(DEFAULT_SUMMARY_BUCKET_DURATION, |d| d);
-        let b_count = self.bucket_count.map_or(DEFAULT_SUMMARY_BUCKET_COUNT, |c| c);
+        if let Some(ref overrides) = self.quantile_overrides {
+            for (matcher, quantiles) in overrides {
+                if matcher.matches(name) {
+                    return self.new_summary(quantiles.clone());
+                }
+            }
+        }
 
-        Distribution::new_summary(self.quantiles.clone(), b_duration, b_count)
+        self.new_summary(self.quantiles.clone())
+    }
+
+    /// Returns the kind of distribution the given metric name would receive, along with the bucket
+    /// bounds or quantiles it would use.
+    ///
+    /// This follows the same rules as [`get_distribution`][Self::get_distribution], without
+    /// creating a distribution, which is useful for verifying how overrides apply to a metric.
+    pub fn kind_for(&self, name: &str) -> DistributionKind {
+        let bucket_override = self.bucket_overrides.as_ref().and_then(|overrides| {
+            overrides.iter().find(|(matcher, _)| matcher.matches(name)).map(|(_, buckets)| buckets)
+        });
+        if let Some(buckets) = bucket_override.or(self.buckets.as_ref()) {
+            return DistributionKind::Histogram(buckets.clone());
+        }
+
+        let quantiles = self
+            .quantile_overrides
+            .as_ref()
+            .and_then(|overrides| {
+                overrides
+                    .iter()
+                    .find(|(matcher, _)| matcher.matches(name))
+                    .map(|(_, quantiles)| quantiles)
+            })
+            .unwrap_or(&self.quantiles);
+        DistributionKind::Summary(quantiles.iter().map(Quantile::value).collect())
     }
 
     /// Returns the distribution type for the given metric key.
@@ -137,6 +249,13 @@ impl DistributionBuilder {
     }
 }
 
+/// Sorts histogram bucket bounds in ascending order, removing any duplicates.
+pub(crate) fn sorted_buckets(mut buckets: Vec<f64>) -> Vec<f64> {
+    buckets.sort_by(f64::total_cmp);
+    buckets.dedup();
+    buckets
+}
+
 #[derive(Clone, Debug)]
 struct Bucket {
     begin: Instant,
@@ -159,6 +278,8 @@ pub struct RollingSummary {
     // Total samples since creation of this summary.  This is separate from the Summary since it is
     // never reset.
     count: usize,
+    // Relative accuracy of the summary of each bucket.
+    accuracy: f64,
 }
 
 impl Default for RollingSummary {
@@ -171,8 +292,34 @@ impl RollingSummary {
     /// Create a new `RollingSummary` with the given number of `buckets` and `bucket-duration`.
     ///
     /// The summary will store quantiles over `buckets * bucket_duration` seconds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bucket_duration` is zero.
     pub fn new(buckets: std::num::NonZeroU32, bucket_duration: Duration) -> RollingSummary {
+        RollingSummary::with_relative_accuracy(
+            buckets,
+            bucket_duration,
+            DEFAULT_SUMMARY_RELATIVE_ACCURACY,
+        )
+    }
+
+    /// Create a new `RollingSummary` with the given number of `buckets` and `bucket-duration`, whose
+    /// quantiles are estimated with the given relative `accuracy`.
+    ///
+    /// An accuracy of 0.01 means that estimated quantiles are within 1% of their true value.  A
+    /// tighter accuracy uses more memory.  [`new`][Self::new] uses an accuracy of 0.0001.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bucket_duration` is zero, or if `accuracy` is not between 0.0 and 1.0, exclusive.
+    pub fn with_relative_accuracy(
+        buckets: std::num::NonZeroU32,
+        bucket_duration: Duration,
+        accuracy: f64,
+    ) -> RollingSummary {
         assert!(!bucket_duration.is_zero());
+        assert!(accuracy > 0.0 && accuracy < 1.0);
         let max_bucket_duration = bucket_duration * buckets.get();
         let max_buckets = buckets.get() as usize;
 
@@ -182,9 +329,14 @@ impl RollingSummary {
             bucket_duration,
             max_bucket_duration,
             count: 0,
+            accuracy,
         }
     }
 
+    fn new_summary(&self) -> Summary {
+        Summary::new(self.accuracy, SUMMARY_MAX_BUCKETS, SUMMARY_MIN_VALUE)
+    }
+
     /// Add a sample `value` to the `RollingSummary` at the time `now`.
     ///
     /// Any values that expire at the `value_ts` are removed from the `RollingSummary`.
@@ -214,7 +366,7 @@ impl RollingSummary {
         }
 
         if self.buckets.is_empty() {
-            let mut summary = Summary::with_defaults();
+            let mut summary = self.new_summary();
             summary.add(value);
             self.buckets.push(Bucket { begin: now, summary });
             return;
@@ -225,7 +377,7 @@ impl RollingSummary {
         // bucket would have been removed.
         let reftime = self.buckets[0].begin;
 
-        let mut summary = Summary::with_defaults();
+        let mut summary = self.new_summary();
         summary.add(value);
 
         // If the value is newer than the first bucket then count upwards to the new bucket time.
@@ -250,9 +402,14 @@ impl RollingSummary {
     /// The snapshot `Summary::count()` contains the total number of values considered in the
     /// Snapshot, which is not the full count of the `RollingSummary`.  Use `RollingSummary::count()`
     /// instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the buckets were somehow created with inconsistent summary configurations, which
+    /// should never happen.
     pub fn snapshot(&self, now: Instant) -> Summary {
         let cutoff = now.checked_sub(self.max_bucket_duration);
-        let mut acc = Summary::with_defaults();
+        let mut acc = self.new_summary();
         self.buckets
             .iter()
             .filter(|b| if let Some(cutoff) = cutoff { b.begin > cutoff } else { true })
@@ -286,6 +443,30 @@ mod tests {
 
     use quanta::Clock;
 
+    #[test]
+    fn unordered_buckets_are_sorted() {
+        let overrides = HashMap::from([(Matcher::Prefix("size".into()), vec![10.0, 1.0, 5.0])]);
+        let builder = DistributionBuilder::new(
+            Vec::new(),
+            None,
+            Some(vec![1.0, 0.5, 2.0, 0.5]),
+            None,
+            Some(overrides),
+            None,
+        );
+
+        for (name, expected) in [("latency", vec![0.5, 1.0, 2.0]), ("size", vec![1.0, 5.0, 10.0])] {
+            let Distribution::Histogram(histogram) = builder.get_distribution(name) else {
+                panic!("expected a histogram for {}", name);
+            };
+            let bounds = histogram.buckets().into_iter().map(|(le, _)| le).collect::<Vec<_>>();
+            assert_eq!(bounds, expected);
+            assert!(
+                matches!(builder.kind_for(name), DistributionKind::Histogram(b) if b == expected)
+            );
+        }
+    }
+
     #[test]
     fn new_rolling_summary() {
         let summary = RollingSummary::default();
@@ -406,4 +587,40 @@ mod tests {
         assert_eq!(2, summary.count());
         assert!(!summary.is_empty());
     }
+
+    #[test]
+    #[should_panic(expected = "accuracy > 0.0 && accuracy < 1.0")]
+    fn invalid_summary_relative_accuracy() {
+        let builder = DistributionBuilder::new(Vec::new(), None, None, None, None, None);
+        let _ = builder.with_summary_relative_accuracy(1.0);
+    }
+
+    #[test]
+    fn kind_for() {
+        let bucket_overrides =
+            HashMap::from([(Matcher::Suffix("_seconds".into()), vec![0.1, 1.0])]);
+        let quantile_overrides = HashMap::from([(
+            Matcher::Prefix("rpc_".into()),
+            metrics_util::parse_quantiles(&[0.5]),
+        )]);
+        let builder = DistributionBuilder::new(
+            metrics_util::parse_quantiles(&[0.9, 0.99]),
+            None,
+            None,
+            None,
+            Some(bucket_overrides),
+            Some(quantile_overrides),
+        );
+
+        assert_eq!(
+            builder.kind_for("latency_seconds"),
+            DistributionKind::Histogram(vec![0.1, 1.0])
+        );
+        assert_eq!(builder.kind_for("rpc_size"), DistributionKind::Summary(vec![0.5]));
+        assert_eq!(builder.kind_for("size"), DistributionKind::Summary(vec![0.9, 0.99]));
+
+        // Default buckets take precedence over quantiles for any metric not otherwise matched.
+        let builder = DistributionBuilder::new(Vec::new(), None, Some(vec![5.0]), None, None, None);
+        assert_eq!(builder.kind_for("size"), DistributionKind::Histogram(vec![5.0]));
+    }
 }
@@ -0,0 +1,491 @@
+//! Encoders for rendering snapshots of metrics into an output format.
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use metrics::{SharedString, Unit};
+use quanta::{Clock, Instant};
+
+use crate::common::{Exemplar, Snapshot};
+use crate::distribution::{Distribution, DistributionKind};
+use crate::formatting::{
+    write_help_line, write_metric_line_with_timestamp, write_metric_sample,
+    write_openmetrics_help_line, write_type_line, write_unit_line, FloatValue,
+};
+
+/// The `Content-Type` of payloads in the Prometheus text-based exposition format.
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// The `Content-Type` of payloads in the `OpenMetrics` text format.
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Encodes a [`Snapshot`] of metrics into a textual payload.
+///
+/// [`PrometheusTextEncoder`] is used when rendering through
+/// [`PrometheusHandle::render`][crate::PrometheusHandle::render], but other output formats can be
+/// supported by implementing this trait and rendering through
+/// [`PrometheusHandle::render_with`][crate::PrometheusHandle::render_with].
+pub trait Encoder {
+    /// Encodes the given snapshot, writing the payload to `out`.
+    ///
+    /// ## Errors
+    ///
+    /// If writing to `out` fails, an error is returned.
+    fn encode(&self, snapshot: &Snapshot, out: &mut dyn fmt::Write) -> fmt::Result;
+}
+
+/// Exposition format to render metrics in.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) enum Format {
+    /// The Prometheus text-based exposition format, version 0.0.4.
+    #[default]
+    Prometheus,
+    /// The OpenMetrics text format, version 1.0.0.
+    OpenMetrics,
+}
+
+impl Format {
+    /// Picks the format to render, based on the value of an HTTP `Accept` header.
+    ///
+    /// `OpenMetrics` is only picked if it is explicitly accepted, as not all scrapers support it.
+    pub(crate) fn negotiate(accept: &str) -> Self {
+        let accepts_openmetrics = accept.split(',').any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default();
+            // A quality of zero means the media type is explicitly not acceptable.
+            let rejected = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .any(|q| q.parse::<f64>().map_or(false, |q| q <= 0.0));
+
+            media_type.eq_ignore_ascii_case("application/openmetrics-text") && !rejected
+        });
+
+        if accepts_openmetrics {
+            Format::OpenMetrics
+        } else {
+            Format::Prometheus
+        }
+    }
+
+    /// Gets the `Content-Type` of payloads rendered in this format.
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => PROMETHEUS_CONTENT_TYPE,
+            Format::OpenMetrics => OPENMETRICS_CONTENT_TYPE,
+        }
+    }
+
+    fn write_help_line<W>(self, buffer: &mut W, name: &str, desc: &str) -> fmt::Result
+    where
+        W: fmt::Write + ?Sized,
+    {
+        match self {
+            Format::Prometheus => write_help_line(buffer, name, desc),
+            Format::OpenMetrics => write_openmetrics_help_line(buffer, name, desc),
+        }
+    }
+
+    fn write_unit_line<W>(self, buffer: &mut W, name: &str, unit: Unit) -> fmt::Result
+    where
+        W: fmt::Write + ?Sized,
+    {
+        // The Prometheus text format has no notion of units, and OpenMetrics requires the unit to be
+        // a suffix of the metric family name, so we only emit it when that holds.
+        let unit = unit.as_str();
+        if self == Format::OpenMetrics
+            && name.strip_suffix(unit).map_or(false, |prefix| prefix.ends_with('_'))
+        {
+            write_unit_line(buffer, name, unit)?;
+        }
+
+        Ok(())
+    }
+
+    /// Formats the given time as a sample timestamp.
+    fn timestamp(self, now: SystemTime) -> String {
+        let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        match self {
+            // The Prometheus text format uses milliseconds, while OpenMetrics uses seconds.
+            Format::Prometheus => since_epoch.as_millis().to_string(),
+            Format::OpenMetrics => {
+                format!("{}.{:03}", since_epoch.as_secs(), since_epoch.subsec_millis())
+            }
+        }
+    }
+
+    fn write_family_separator<W>(self, buffer: &mut W) -> fmt::Result
+    where
+        W: fmt::Write + ?Sized,
+    {
+        // OpenMetrics does not allow empty lines anywhere in the exposition.
+        if self == Format::Prometheus {
+            buffer.write_char('\n')?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Encodes metrics in the Prometheus text-based [exposition format], or the [OpenMetrics] text
+/// format.
+///
+/// [exposition format]: https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+/// [OpenMetrics]: https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusTextEncoder {
+    format: Format,
+    counter_total_suffix: bool,
+    timestamps: bool,
+    compact_output: bool,
+    skip_metadata: bool,
+    unit_in_help: bool,
+    clock: Option<Clock>,
+}
+
+impl PrometheusTextEncoder {
+    /// Creates a new `PrometheusTextEncoder` for the Prometheus text-based exposition format.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a new `PrometheusTextEncoder` for the `OpenMetrics` text format.
+    ///
+    /// Counters are always rendered with the `_total` suffix, and the payload is terminated with
+    /// `# EOF`.
+    pub fn openmetrics() -> Self {
+        Self { format: Format::OpenMetrics, ..Self::default() }
+    }
+
+    pub(crate) fn with_format(format: Format) -> Self {
+        Self { format, ..Self::default() }
+    }
+
+    /// Sets the clock summaries are snapshotted with, rather than the thread's clock.
+    #[must_use]
+    pub(crate) fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Sets whether or not counters are rendered with the `_total` suffix.
+    ///
+    /// See [`PrometheusBuilder::counter_total_suffix`][crate::PrometheusBuilder::counter_total_suffix].
+    #[must_use]
+    pub fn counter_total_suffix(mut self, enabled: bool) -> Self {
+        self.counter_total_suffix = enabled;
+        self
+    }
+
+    /// Sets whether or not each sample is rendered with a timestamp.
+    ///
+    /// See [`PrometheusBuilder::with_timestamps`][crate::PrometheusBuilder::with_timestamps].
+    #[must_use]
+    pub fn with_timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
+
+    /// Sets whether or not the blank line between metric families is omitted.
+    ///
+    /// See [`PrometheusBuilder::compact_output`][crate::PrometheusBuilder::compact_output].
+    #[must_use]
+    pub fn compact_output(mut self, enabled: bool) -> Self {
+        self.compact_output = enabled;
+        self
+    }
+
+    /// Writes a histogram bucket line, given as its upper bound, count, and exemplar, if any.
+    fn write_bucket_line(
+        &self,
+        output: &mut dyn fmt::Write,
+        name: &str,
+        labels: &[String],
+        (le, count, exemplar): (f64, u64, Option<&Exemplar>),
+        timestamp: Option<&str>,
+    ) -> fmt::Result {
+        let le = Some(("le", FloatValue(le)));
+        write_metric_sample(output, name, Some("bucket"), labels, le, count, timestamp)?;
+        if let Some(exemplar) = exemplar {
+            write!(output, " # {{{}}} {} ", exemplar.labels.join(","), FloatValue(exemplar.value))?;
+            output.write_str(&self.format.timestamp(exemplar.timestamp))?;
+        }
+
+        output.write_char('\n')
+    }
+
+    /// Writes the creation time of a series, if it has one.
+    ///
+    /// Creation times are an `OpenMetrics` extension, so they're only rendered there.
+    fn write_created_line(
+        &self,
+        output: &mut dyn fmt::Write,
+        name: &str,
+        labels: &[String],
+        created: Option<&SystemTime>,
+        timestamp: Option<&str>,
+    ) -> fmt::Result {
+        let Some(created) = created.filter(|_| self.format == Format::OpenMetrics) else {
+            return Ok(());
+        };
+
+        let created = self.format.timestamp(*created);
+        write_metric_line_with_timestamp::<_, &str, _>(
+            output,
+            name,
+            Some("created"),
+            labels,
+            None,
+            created,
+            timestamp,
+        )
+    }
+
+    /// Sets whether or not the `# HELP`, `# TYPE`, and `# UNIT` metadata lines are rendered.
+    ///
+    /// See [`PrometheusBuilder::emit_metadata`][crate::PrometheusBuilder::emit_metadata].
+    #[must_use]
+    pub fn emit_metadata(mut self, enabled: bool) -> Self {
+        self.skip_metadata = !enabled;
+        self
+    }
+
+    /// Sets whether or not the unit of a metric is appended to its description in the `# HELP` line.
+    ///
+    /// See [`PrometheusBuilder::append_unit_to_help`][crate::PrometheusBuilder::append_unit_to_help].
+    #[must_use]
+    pub fn append_unit_to_help(mut self, enabled: bool) -> Self {
+        self.unit_in_help = enabled;
+        self
+    }
+
+    /// Writes the metadata lines of a metric family, unless metadata is skipped.
+    fn write_metadata(
+        &self,
+        output: &mut dyn fmt::Write,
+        name: &str,
+        metric_type: &str,
+        desc: Option<&SharedString>,
+        unit: Option<&Unit>,
+    ) -> fmt::Result {
+        if self.skip_metadata {
+            return Ok(());
+        }
+
+        match (desc, unit) {
+            (Some(desc), Some(unit)) if self.unit_in_help => {
+                let desc = format!("{desc} (unit: {})", unit.as_str());
+                self.format.write_help_line(output, name, &desc)?;
+            }
+            (Some(desc), _) => self.format.write_help_line(output, name, desc)?,
+            (None, _) => {}
+        }
+
+        write_type_line(output, name, metric_type)?;
+        if let Some(unit) = unit {
+            self.format.write_unit_line(output, name, *unit)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_family_separator(&self, output: &mut dyn fmt::Write) -> fmt::Result {
+        if self.compact_output {
+            return Ok(());
+        }
+
+        self.format.write_family_separator(output)
+    }
+}
+
+impl Encoder for PrometheusTextEncoder {
+    #[allow(clippy::too_many_lines)]
+    fn encode(&self, snapshot: &Snapshot, output: &mut dyn fmt::Write) -> fmt::Result {
+        let format = self.format;
+        let timestamp = self.timestamps.then(|| format.timestamp(SystemTime::now()));
+        let timestamp = timestamp.as_deref();
+        let now = self.clock.as_ref().map_or_else(Instant::now, Clock::now);
+
+        let Snapshot {
+            counters,
+            gauges,
+            distributions,
+            empty_distributions,
+            descriptions,
+            units,
+            exemplars,
+            created,
+        } = snapshot;
+        let created_for = |name: &str, labels: &Vec<String>| created.get(name)?.get(labels);
+
+        for (family, by_labels) in counters {
+            let desc = descriptions.get(family.as_str());
+            let unit = units.get(family.as_str());
+            // OpenMetrics requires the counter family name to omit the `_total` suffix, while the
+            // samples themselves must carry it.
+            let (name, suffix) = match format {
+                Format::Prometheus if self.counter_total_suffix => {
+                    (with_total_suffix(family), None)
+                }
+                Format::Prometheus => (family.clone(), None),
+                Format::OpenMetrics => (without_total_suffix(family), Some("total")),
+            };
+
+            self.write_metadata(output, &name, "counter", desc, unit)?;
+            for (labels, value) in by_labels {
+                write_metric_line_with_timestamp::<_, &str, u64>(
+                    output, &name, suffix, labels, None, *value, timestamp,
+                )?;
+                let created = created_for(family, labels);
+                self.write_created_line(output, &name, labels, created, timestamp)?;
+            }
+            self.write_family_separator(output)?;
+        }
+
+        for (name, by_labels) in gauges {
+            let (desc, unit) = (descriptions.get(name.as_str()), units.get(name.as_str()));
+            self.write_metadata(output, name, "gauge", desc, unit)?;
+            for (labels, value) in by_labels {
+                write_metric_line_with_timestamp::<_, &str, _>(
+                    output,
+                    name,
+                    None,
+                    labels,
+                    None,
+                    FloatValue(*value),
+                    timestamp,
+                )?;
+            }
+            self.write_family_separator(output)?;
+        }
+
+        for (name, by_labels) in distributions {
+            // All distributions for a given metric name are built the same way, so the first one
+            // tells us the type of the whole family.
+            let Some(first) = by_labels.values().next() else { continue };
+            let distribution_type = match first {
+                Distribution::Histogram(_) => "histogram",
+                Distribution::Summary(..) => "summary",
+            };
+
+            let (desc, unit) = (descriptions.get(name.as_str()), units.get(name.as_str()));
+            self.write_metadata(output, name, distribution_type, desc, unit)?;
+            for (labels, distribution) in by_labels {
+                let (sum, count) = match distribution {
+                    Distribution::Summary(summary, quantiles, sum) => {
+                        let snapshot = summary.snapshot(now);
+                        for quantile in quantiles.iter() {
+                            let value = snapshot.quantile(quantile.value()).unwrap_or(0.0);
+                            write_metric_line_with_timestamp(
+                                output,
+                                name,
+                                None,
+                                labels,
+                                Some(("quantile", FloatValue(quantile.value()))),
+                                FloatValue(value),
+                                timestamp,
+                            )?;
+                        }
+
+                        (*sum, summary.count() as u64)
+                    }
+                    Distribution::Histogram(histogram) => {
+                        // The `+Inf` bucket is always written explicitly below, so skip it here if
+                        // it was configured as a bucket bound to avoid emitting it twice.
+                        let buckets = histogram
+                            .buckets()
+                            .into_iter()
+                            .filter(|(le, _)| *le != f64::INFINITY)
+                            .collect::<Vec<_>>();
+                        let bounds = buckets.iter().map(|(le, _)| *le).collect::<Vec<_>>();
+                        // Exemplars are an OpenMetrics extension, so they're only rendered there.
+                        let exemplars = exemplars
+                            .get(name.as_str())
+                            .and_then(|by_labels| by_labels.get(labels))
+                            .filter(|_| format == Format::OpenMetrics);
+                        let exemplar_for = |index| {
+                            exemplars?
+                                .iter()
+                                .find(|exemplar| bucket_index(&bounds, exemplar.value) == index)
+                        };
+
+                        for (index, (le, count)) in buckets.iter().enumerate() {
+                            let bucket = (*le, *count, exemplar_for(index));
+                            self.write_bucket_line(output, name, labels, bucket, timestamp)?;
+                        }
+                        let bucket = (f64::INFINITY, histogram.count(), exemplar_for(bounds.len()));
+                        self.write_bucket_line(output, name, labels, bucket, timestamp)?;
+
+                        (histogram.sum(), histogram.count())
+                    }
+                };
+
+                write_metric_line_with_timestamp::<_, &str, _>(
+                    output,
+                    name,
+                    Some("sum"),
+                    labels,
+                    None,
+                    FloatValue(sum),
+                    timestamp,
+                )?;
+                write_metric_line_with_timestamp::<_, &str, u64>(
+                    output,
+                    name,
+                    Some("count"),
+                    labels,
+                    None,
+                    count,
+                    timestamp,
+                )?;
+                let created = created_for(name, labels);
+                self.write_created_line(output, name, labels, created, timestamp)?;
+            }
+
+            self.write_family_separator(output)?;
+        }
+
+        for (name, kind) in empty_distributions {
+            if distributions.contains_key(name) {
+                continue;
+            }
+
+            let distribution_type = match kind {
+                DistributionKind::Histogram(_) => "histogram",
+                DistributionKind::Summary(_) => "summary",
+            };
+            let (desc, unit) = (descriptions.get(name.as_str()), units.get(name.as_str()));
+            self.write_metadata(output, name, distribution_type, desc, unit)?;
+            self.write_family_separator(output)?;
+        }
+
+        if format == Format::OpenMetrics {
+            output.write_str("# EOF\n")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Gets the index of the histogram bucket that the given value falls into.
+///
+/// Bounds of infinity are ignored, such that an index equal to the number of finite bounds refers to
+/// the `+Inf` bucket.
+pub(crate) fn bucket_index(bounds: &[f64], value: f64) -> usize {
+    let finite = bounds.iter().filter(|bound| **bound != f64::INFINITY);
+    finite.clone().position(|bound| value <= *bound).unwrap_or_else(|| finite.count())
+}
+
+/// Appends the `_total` suffix to the given counter name, unless it is already present.
+pub(crate) fn with_total_suffix(name: &str) -> String {
+    if name.ends_with("_total") {
+        name.to_owned()
+    } else {
+        format!("{name}_total")
+    }
+}
+
+/// Removes the `_total` suffix from the given counter name, if present.
+fn without_total_suffix(name: &str) -> String {
+    name.strip_suffix("_total").unwrap_or(name).to_owned()
+}
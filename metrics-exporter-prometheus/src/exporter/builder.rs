@@ -1,10 +1,11 @@
-use std::collections::HashMap;
-#[cfg(feature = "push-gateway")]
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::convert::TryFrom;
 #[cfg(feature = "http-listener")]
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::num::NonZeroU32;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::{Mutex, RwLock};
 #[cfg(any(feature = "http-listener", feature = "push-gateway"))]
 use std::thread;
 use std::time::Duration;
@@ -19,11 +20,14 @@ use quanta::Clock;
 use metrics_util::{
     parse_quantiles,
     registry::{GenerationalStorage, Recency, Registry},
-    MetricKindMask, Quantile,
+    MetricKind, MetricKindMask, Quantile,
 };
 
-use crate::common::Matcher;
-use crate::distribution::DistributionBuilder;
+use crate::common::{
+    DescriptionCollisionPolicy, KindCollisionPolicy, Matcher, NameRewriter, NameSanitizer,
+};
+use crate::distribution::{sorted_buckets, DistributionBuilder, DistributionKind};
+use crate::formatting::{sanitize_metric_name_with, sanitize_strict_with};
 use crate::recorder::{Inner, PrometheusRecorder};
 use crate::registry::AtomicStorage;
 use crate::{common::BuildError, PrometheusHandle};
@@ -39,15 +43,52 @@ pub struct PrometheusBuilder {
     exporter_config: ExporterConfig,
     #[cfg(feature = "http-listener")]
     allowed_addresses: Option<Vec<IpNet>>,
+    #[cfg(feature = "http-listener")]
+    basic_auth: Option<(String, String)>,
     quantiles: Vec<Quantile>,
     bucket_duration: Option<Duration>,
     bucket_count: Option<NonZeroU32>,
+    summary_relative_accuracy: Option<f64>,
+    summary_max_age: Option<Duration>,
     buckets: Option<Vec<f64>>,
     bucket_overrides: Option<HashMap<Matcher, Vec<f64>>>,
+    quantile_overrides: Option<HashMap<Matcher, Vec<Quantile>>>,
     idle_timeout: Option<Duration>,
     upkeep_timeout: Duration,
     recency_mask: MetricKindMask,
     global_labels: Option<IndexMap<String, String>>,
+    dropped_labels: Vec<String>,
+    sort_labels: bool,
+    drop_empty_labels: bool,
+    max_label_value_len: Option<usize>,
+    counter_total_suffix: bool,
+    strict_names: bool,
+    lowercase_names: bool,
+    lowercase_label_keys: bool,
+    name_sanitizer: Option<NameSanitizer>,
+    name_rewriter: Option<NameRewriter>,
+    sanitize_replacement: char,
+    namespace: Option<String>,
+    overwrite_descriptions: bool,
+    description_collision_policy: DescriptionCollisionPolicy,
+    timestamps: bool,
+    created_timestamps: bool,
+    compact_output: bool,
+    emit_metadata: bool,
+    append_unit_to_help: bool,
+    kind_collision_policy: KindCollisionPolicy,
+    monotonic_counters: bool,
+    delta_counters: bool,
+    log_on_anomaly: bool,
+    self_metrics: bool,
+    keep_registered_families: bool,
+    #[cfg(feature = "process-metrics")]
+    process_metrics: bool,
+    aggregate_histograms: bool,
+    count_out_of_bounds: bool,
+    cardinality_limit: Option<usize>,
+    denied_metrics: Vec<Matcher>,
+    clock: Option<Clock>,
 }
 
 impl PrometheusBuilder {
@@ -71,21 +112,60 @@ impl PrometheusBuilder {
             exporter_config,
             #[cfg(feature = "http-listener")]
             allowed_addresses: None,
+            #[cfg(feature = "http-listener")]
+            basic_auth: None,
             quantiles,
             bucket_duration: None,
             bucket_count: None,
+            summary_relative_accuracy: None,
+            summary_max_age: None,
             buckets: None,
             bucket_overrides: None,
+            quantile_overrides: None,
             idle_timeout: None,
             upkeep_timeout,
             recency_mask: MetricKindMask::NONE,
             global_labels: None,
+            dropped_labels: Vec::new(),
+            sort_labels: true,
+            drop_empty_labels: false,
+            max_label_value_len: None,
+            counter_total_suffix: false,
+            strict_names: false,
+            lowercase_names: false,
+            lowercase_label_keys: false,
+            name_sanitizer: None,
+            name_rewriter: None,
+            sanitize_replacement: '_',
+            namespace: None,
+            overwrite_descriptions: false,
+            description_collision_policy: DescriptionCollisionPolicy::First,
+            timestamps: false,
+            created_timestamps: false,
+            compact_output: false,
+            emit_metadata: true,
+            append_unit_to_help: false,
+            kind_collision_policy: KindCollisionPolicy::default(),
+            monotonic_counters: false,
+            delta_counters: false,
+            log_on_anomaly: false,
+            self_metrics: false,
+            keep_registered_families: false,
+            #[cfg(feature = "process-metrics")]
+            process_metrics: false,
+            aggregate_histograms: false,
+            count_out_of_bounds: false,
+            cardinality_limit: None,
+            denied_metrics: Vec::new(),
+            clock: None,
         }
     }
 
     /// Configures the exporter to expose an HTTP listener that functions as a [scrape endpoint].
     ///
-    /// The HTTP listener that is spawned will respond to GET requests on any request path.
+    /// The HTTP listener that is spawned will respond to GET requests on `/metrics` with a content
+    /// type of `text/plain; version=0.0.4`, and to GET requests on `/health` with `OK`.  Requests
+    /// to any other path, or using any other method, will receive a `404 Not Found` response.
     ///
     /// Running in HTTP listener mode is mutually exclusive with the push gateway i.e. enabling the
     /// HTTP listener will disable the push gateway, and vise versa.
@@ -108,6 +188,11 @@ impl PrometheusBuilder {
     /// Running in push gateway mode is mutually exclusive with the HTTP listener i.e. enabling the
     /// push gateway will disable the HTTP listener, and vise versa.
     ///
+    /// The rendered metrics are sent to `endpoint` on every `interval`, so the endpoint should
+    /// include the job name, and any grouping labels, in the path: for example,
+    /// `http://127.0.0.1:9091/metrics/job/my_job/instance/my_instance`.  If `username` is given, the
+    /// requests are sent with basic authentication credentials.
+    ///
     /// Defaults to disabled.
     ///
     /// ## Errors
@@ -142,7 +227,12 @@ impl PrometheusBuilder {
     /// Configures the exporter to expose an HTTP listener that functions as a [scrape endpoint],
     /// listening on a Unix Domain socket at the given path
     ///
-    /// The HTTP listener that is spawned will respond to GET requests on any request path.
+    /// The HTTP listener that is spawned will respond to GET requests on `/metrics` with a content
+    /// type of `text/plain; version=0.0.4`, and to GET requests on `/health` with `OK`.  Requests
+    /// to any other path, or using any other method, will receive a `404 Not Found` response.
+    ///
+    /// Any existing file at the given path is replaced when the listener is created, and the socket
+    /// file is removed once the exporter future is dropped.
     ///
     /// Running in HTTP listener mode is mutually exclusive with the push gateway i.e. enabling the
     /// HTTP listener will disable the push gateway, and vise versa.
@@ -193,6 +283,29 @@ impl PrometheusBuilder {
         Ok(self)
     }
 
+    /// Requires HTTP Basic authentication for requests to the scrape endpoint.
+    ///
+    /// If a client makes a request to the scrape endpoint without an `Authorization` header
+    /// matching the given credentials, they will receive a 401 Unauthorized response.  Credentials
+    /// are compared in constant time.
+    ///
+    /// Basic authentication only encodes the credentials, so unless the scrape endpoint is served
+    /// over a secure transport, such as behind a TLS-terminating proxy, they can be observed by
+    /// anyone able to see the traffic.
+    ///
+    /// Defaults to no authentication.
+    #[cfg(feature = "http-listener")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "http-listener")))]
+    #[must_use]
+    pub fn with_basic_auth(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        self.basic_auth = Some((username.into(), password.into()));
+        self
+    }
+
     /// Sets the quantiles to use when rendering histograms.
     ///
     /// Quantiles represent a scale of 0 to 1, where percentiles represent a scale of 1 to 100, so
@@ -203,20 +316,49 @@ impl PrometheusBuilder {
     ///
     /// If buckets are set (via [`set_buckets`][Self::set_buckets] or
     /// [`set_buckets_for_metric`][Self::set_buckets_for_metric]) then all histograms will be exposed
-    /// as summaries instead.
+    /// as Prometheus histograms instead.
     ///
     /// ## Errors
     ///
-    /// If `quantiles` is empty, an error variant will be thrown.
+    /// If `quantiles` is empty, or any quantile is outside of the range of 0.0 to 1.0, an error
+    /// variant will be thrown.
     pub fn set_quantiles(mut self, quantiles: &[f64]) -> Result<Self, BuildError> {
-        if quantiles.is_empty() {
-            return Err(BuildError::EmptyBucketsOrQuantiles);
-        }
+        validate_quantiles(quantiles)?;
 
         self.quantiles = parse_quantiles(quantiles);
         Ok(self)
     }
 
+    /// Sets the quantiles to use for summaries matching a specific pattern.
+    ///
+    /// The match pattern can be a full match (equality), prefix match, or suffix match, and matchers
+    /// are applied in the same order as for [`set_buckets_for_metric`][Self::set_buckets_for_metric].
+    ///
+    /// Matching metrics use these quantiles instead of those set via
+    /// [`set_quantiles`][Self::set_quantiles].  Metrics that match no override fall back to the
+    /// default quantiles.  If the metric is rendered as a histogram, because buckets apply to it,
+    /// these quantiles are not used.
+    ///
+    /// Setting both quantiles and buckets for the same matcher, via
+    /// [`set_buckets_for_metric`][Self::set_buckets_for_metric], is ambiguous, and makes
+    /// [`build`][Self::build] fail.
+    ///
+    /// ## Errors
+    ///
+    /// If `quantiles` is empty, or any quantile is outside of the range of 0.0 to 1.0, an error
+    /// variant will be thrown.
+    pub fn set_quantiles_for_metric(
+        mut self,
+        matcher: Matcher,
+        quantiles: &[f64],
+    ) -> Result<Self, BuildError> {
+        validate_quantiles(quantiles)?;
+
+        let overrides = self.quantile_overrides.get_or_insert_with(HashMap::new);
+        overrides.insert(matcher, parse_quantiles(quantiles));
+        Ok(self)
+    }
+
     /// Sets the bucket width when using summaries.
     ///
     /// Summaries are rolling, which means that they are divided into buckets of a fixed duration
@@ -245,6 +387,69 @@ impl PrometheusBuilder {
         Ok(self)
     }
 
+    /// Sets the maximum age of the samples reflected by summaries.
+    ///
+    /// Summaries only reflect the samples recorded within this sliding time window, like `MaxAge`
+    /// in the official Prometheus client libraries, with older samples rotated out as the summary
+    /// rolls forward.  The window is split evenly between the age buckets, whose number is set by
+    /// [`set_summary_age_buckets`][Self::set_summary_age_buckets], such that a maximum age of 10
+    /// minutes with 5 age buckets rotates out 2 minutes worth of samples at a time.  This takes
+    /// precedence over [`set_bucket_duration`][Self::set_bucket_duration].
+    ///
+    /// Defaults to 60 seconds, being 3 buckets of 20 seconds.
+    ///
+    /// ## Errors
+    ///
+    /// If the duration given is zero, an error variant will be thrown.
+    pub fn set_summary_max_age(mut self, max_age: Duration) -> Result<Self, BuildError> {
+        if max_age.is_zero() {
+            return Err(BuildError::ZeroBucketDuration);
+        }
+
+        self.summary_max_age = Some(max_age);
+        Ok(self)
+    }
+
+    /// Sets the number of age buckets that the time window of summaries is split into.
+    ///
+    /// More buckets rotate out fewer samples at a time, so that quantiles change more smoothly, at
+    /// the cost of memory.  This is equivalent to [`set_bucket_count`][Self::set_bucket_count].
+    ///
+    /// Defaults to 3.
+    ///
+    /// ## Errors
+    ///
+    /// If the count given is zero, or does not fit in a `u32`, an error variant will be thrown.
+    pub fn set_summary_age_buckets(mut self, count: usize) -> Result<Self, BuildError> {
+        let count = u32::try_from(count)
+            .ok()
+            .and_then(NonZeroU32::new)
+            .ok_or(BuildError::InvalidSummaryAgeBuckets(count))?;
+
+        self.bucket_count = Some(count);
+        Ok(self)
+    }
+
+    /// Sets the relative accuracy of quantiles when using summaries.
+    ///
+    /// Summaries estimate quantiles using a sketch, such that an accuracy of 0.01 means estimated
+    /// quantiles are within 1% of their true value.  A tighter accuracy uses more memory per
+    /// summary, while a looser accuracy saves memory.
+    ///
+    /// Defaults to 0.0001.
+    ///
+    /// ## Errors
+    ///
+    /// If the accuracy is not between 0.0 and 1.0, exclusive, an error variant will be thrown.
+    pub fn set_summary_relative_accuracy(mut self, accuracy: f64) -> Result<Self, BuildError> {
+        if !(accuracy > 0.0 && accuracy < 1.0) {
+            return Err(BuildError::InvalidRelativeAccuracy(accuracy));
+        }
+
+        self.summary_relative_accuracy = Some(accuracy);
+        Ok(self)
+    }
+
     /// Sets the bucket count when using summaries.
     ///
     /// Summaries are rolling, which means that they are divided into buckets of a fixed duration
@@ -269,18 +474,115 @@ impl PrometheusBuilder {
     /// Sets the buckets to use when rendering histograms.
     ///
     /// Buckets values represent the higher bound of each buckets.  If buckets are set, then all
-    /// histograms will be rendered as true Prometheus histograms, instead of summaries.
+    /// histograms will be rendered as true Prometheus histograms, instead of summaries.  The values
+    /// are sorted in ascending order, and any duplicates are removed.
     ///
     /// ## Errors
     ///
-    /// If `values` is empty, an error variant will be thrown.
+    /// If `values` is empty, or contains NaN, an error variant will be thrown.
     pub fn set_buckets(mut self, values: &[f64]) -> Result<Self, BuildError> {
-        if values.is_empty() {
-            return Err(BuildError::EmptyBucketsOrQuantiles);
+        self.buckets = Some(validate_buckets(values)?);
+        Ok(self)
+    }
+
+    /// Sets the kind of distribution used for all histograms by default.
+    ///
+    /// This is a shorthand for either [`set_buckets`][Self::set_buckets], when using
+    /// [`DistributionKind::Histogram`], or for [`set_quantiles`][Self::set_quantiles] while clearing
+    /// any default buckets, when using [`DistributionKind::Summary`].  Per-metric overrides, set via
+    /// [`set_buckets_for_metric`][Self::set_buckets_for_metric], still take precedence over the
+    /// default.
+    ///
+    /// ## Errors
+    ///
+    /// If the given buckets or quantiles are empty, any bucket is NaN, or any quantile is outside of
+    /// the range of 0.0 to 1.0, an error variant will be thrown.
+    pub fn set_default_distribution(self, kind: DistributionKind) -> Result<Self, BuildError> {
+        match kind {
+            DistributionKind::Histogram(buckets) => self.set_buckets(&buckets),
+            DistributionKind::Summary(quantiles) => {
+                let mut builder = self.set_quantiles(&quantiles)?;
+                builder.buckets = None;
+                Ok(builder)
+            }
         }
+    }
 
-        self.buckets = Some(values.to_vec());
-        Ok(self)
+    /// Sets whether or not each histogram is also rendered as an aggregate across all of its labels.
+    ///
+    /// When enabled, each histogram gets an additional series, labeled only with the global labels,
+    /// whose buckets, sum, and count are the sums of those of every other series of that histogram.
+    /// This saves aggregating them at query time, for example for capacity planning.  Summaries are
+    /// never aggregated, as quantiles cannot be meaningfully summed, nor are histograms which are
+    /// already recorded without any labels of their own.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn aggregate_histograms(mut self, enabled: bool) -> Self {
+        self.aggregate_histograms = enabled;
+        self
+    }
+
+    /// Sets whether or not samples exceeding the highest bucket of a histogram are counted.
+    ///
+    /// When enabled, each sample recorded to a histogram which is greater than its highest
+    /// configured bucket increments a counter named after the histogram with an
+    /// `_out_of_bounds_total` suffix, with the same labels.  Such samples still only land in the
+    /// `+Inf` bucket, as usual, but the counter makes misconfigured bucket ranges visible.
+    /// Summaries, and histograms set via
+    /// [`PrometheusHandle::set_histogram_buckets`][crate::PrometheusHandle::set_histogram_buckets],
+    /// are not counted.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn count_out_of_bounds(mut self, enabled: bool) -> Self {
+        self.count_out_of_bounds = enabled;
+        self
+    }
+
+    /// Sets the clock the recorder reads the time from.
+    ///
+    /// The clock is used to track when metrics were last updated, for the idle timeout, for the
+    /// time of the last scrape, to timestamp histogram samples, which summaries age out by, and to
+    /// time sections of code with
+    /// [`PrometheusHandle::time_histogram`][crate::PrometheusHandle::time_histogram].  Passing a
+    /// mocked clock, as created by [`Clock::mock`], allows advancing time deterministically, such
+    /// as in tests.
+    ///
+    /// Defaults to [`Clock::new`].
+    #[must_use]
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Sets the maximum number of distinct series the recorder will hold.
+    ///
+    /// Once the limit is reached, registering a series which doesn't already exist returns a no-op
+    /// handle, such that the series is dropped, and increments the `prometheus_dropped_series_total`
+    /// counter, which itself is exempt from the limit.  Existing series continue to be updated, and
+    /// new series are accepted again once others are removed, such as by the idle timeout.
+    ///
+    /// Defaults to no limit.
+    #[must_use]
+    pub fn with_cardinality_limit(mut self, limit: usize) -> Self {
+        self.cardinality_limit = Some(limit);
+        self
+    }
+
+    /// Denies the export of metrics matching a specific pattern.
+    ///
+    /// The match pattern can be a full match (equality), prefix match, or suffix match, and is
+    /// matched against the sanitized metric name, in the same way as for
+    /// [`set_buckets_for_metric`][Self::set_buckets_for_metric].  Matching metrics are never
+    /// stored: registering them returns no-op handles, which don't count towards the cardinality
+    /// limit, and their descriptions are ignored.
+    ///
+    /// This can be called multiple times, and a metric is denied if any of the patterns match it.
+    #[must_use]
+    pub fn deny_metric(mut self, matcher: Matcher) -> Self {
+        self.denied_metrics.push(matcher);
+        self
     }
 
     /// Sets the bucket for a specific pattern.
@@ -292,24 +594,24 @@ impl PrometheusBuilder {
     ///
     /// Buckets values represent the higher bound of each buckets.  If buckets are set, then any
     /// histograms that match will be rendered as true Prometheus histograms, instead of summaries.
+    /// The values are sorted in ascending order, and any duplicates are removed.
     ///
-    /// This option changes the observer's output of histogram-type metric into summaries.
-    /// It only affects matching metrics if [`set_buckets`][Self::set_buckets] was not used.
+    /// Matching metrics always use these buckets, even if default buckets were also set via
+    /// [`set_buckets`][Self::set_buckets].  Metrics that match no override fall back to the default
+    /// buckets, if set, or are otherwise rendered as summaries.
     ///
     /// ## Errors
     ///
-    /// If `values` is empty, an error variant will be thrown.
+    /// If `values` is empty, or contains NaN, an error variant will be thrown.
     pub fn set_buckets_for_metric(
         mut self,
         matcher: Matcher,
         values: &[f64],
     ) -> Result<Self, BuildError> {
-        if values.is_empty() {
-            return Err(BuildError::EmptyBucketsOrQuantiles);
-        }
+        let values = validate_buckets(values)?;
 
         let buckets = self.bucket_overrides.get_or_insert_with(HashMap::new);
-        buckets.insert(matcher.sanitized(), values.to_vec());
+        buckets.insert(matcher, values);
         Ok(self)
     }
 
@@ -334,10 +636,27 @@ impl PrometheusBuilder {
         self
     }
 
+    /// Sets whether or not metric families are rendered even when none of their series are.
+    ///
+    /// When a metric family has no series to render, such as when all of them were removed by the
+    /// [idle timeout][Self::idle_timeout], it normally disappears from the output entirely.  When
+    /// enabled, every metric name registered with the recorder is still rendered with its metadata,
+    /// but without any samples, which keeps the family visible to dashboards and tooling.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn keep_registered_families(mut self, enabled: bool) -> Self {
+        self.keep_registered_families = enabled;
+        self
+    }
+
     /// Sets the upkeep interval.
     ///
-    /// The upkeep task handles periodic maintenance operations, such as draining histogram data,
-    /// to ensure that all recorded data is up-to-date and prevent unbounded memory growth.
+    /// The upkeep task handles periodic maintenance operations, such as draining histogram data and
+    /// removing metrics which have exceeded the [idle timeout][Self::idle_timeout], to ensure that
+    /// all recorded data is up-to-date and prevent unbounded memory growth, even if metrics are
+    /// rarely rendered.  The task is spawned by [`build`][Self::build] and
+    /// [`install`][Self::install], and stops once the recorder and all handles to it are dropped.
     #[must_use]
     pub fn upkeep_timeout(mut self, timeout: Duration) -> Self {
         self.upkeep_timeout = timeout;
@@ -360,107 +679,514 @@ impl PrometheusBuilder {
         self
     }
 
-    /// Builds the recorder and exporter and installs them globally.
-    ///
-    /// When called from within a Tokio runtime, the exporter future is spawned directly
-    /// into the runtime.  Otherwise, a new single-threaded Tokio runtime is created
-    /// on a background thread, and the exporter is spawned there.
+    /// Sets whether or not labels are sorted by key when rendering.
     ///
-    /// ## Errors
+    /// When enabled, labels are sorted by key, such that the same set of labels always identifies
+    /// the same series, regardless of the order they were given in.  When disabled, labels are
+    /// rendered in the order they were given in, after any global labels, which may be preferable
+    /// for readability, but can make a single series appear as several if the order isn't
+    /// consistent.
     ///
-    /// If there is an error while either building the recorder and exporter, or installing the
-    /// recorder and exporter, an error variant will be returned describing the error.
-    #[cfg(any(feature = "http-listener", feature = "push-gateway"))]
-    #[cfg_attr(docsrs, doc(cfg(any(feature = "http-listener", feature = "push-gateway"))))]
-    pub fn install(self) -> Result<(), BuildError> {
-        use tokio::runtime;
+    /// Defaults to `true`.
+    #[must_use]
+    pub fn sort_labels(mut self, enabled: bool) -> Self {
+        self.sort_labels = enabled;
+        self
+    }
 
-        let recorder = if let Ok(handle) = runtime::Handle::try_current() {
-            let (recorder, exporter) = {
-                let _g = handle.enter();
-                self.build()?
-            };
+    /// Sets whether or not labels with an empty value are dropped.
+    ///
+    /// Prometheus treats a label with an empty value the same as the label being absent, so a series
+    /// with such a label would be merged with the series without it once ingested.  When enabled,
+    /// these labels are dropped, such that the series are merged consistently by the exporter
+    /// itself, in the same way as for [`drop_labels`][Self::drop_labels].  This applies to global
+    /// labels as well.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn drop_empty_labels(mut self, enabled: bool) -> Self {
+        self.drop_empty_labels = enabled;
+        self
+    }
 
-            handle.spawn(exporter);
+    /// Sets the maximum length of label values, in characters.
+    ///
+    /// Label values longer than the limit, such as full URLs, are cut off and marked with a
+    /// trailing ellipsis (`…`), which bounds the size of the exposition.  The limit applies to the
+    /// escaped value, and escape sequences are never split.  Truncation can merge series: label
+    /// values which only differ after the limit are rendered as the same series, in the same way as
+    /// for [`drop_labels`][Self::drop_labels].
+    ///
+    /// Defaults to no limit.
+    #[must_use]
+    pub fn max_label_value_len(mut self, max_len: usize) -> Self {
+        self.max_label_value_len = Some(max_len);
+        self
+    }
 
-            recorder
-        } else {
-            let thread_name =
-                format!("metrics-exporter-prometheus-{}", self.exporter_config.as_type_str());
+    /// Adds label keys to drop from all metrics when rendering.
+    ///
+    /// This is a safety valve for high-cardinality labels, such as a user ID, that can be used
+    /// without changing instrumentation.  Label keys are matched exactly, before any sanitization,
+    /// and apply to global labels as well.  If this method is called multiple times, the keys are
+    /// added to those already configured.
+    ///
+    /// Dropping labels can cause distinct series to collapse into a single series.  When that
+    /// happens, counters are summed, gauges take the value of the series whose original key sorts
+    /// last, and histogram samples are merged into a single distribution.
+    #[must_use]
+    pub fn drop_labels(mut self, keys: &[&str]) -> Self {
+        self.dropped_labels.extend(keys.iter().map(|key| (*key).to_owned()));
+        self
+    }
 
-            let runtime = runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .map_err(|e| BuildError::FailedToCreateRuntime(e.to_string()))?;
+    /// Sets whether or not counters are rendered with a `_total` suffix.
+    ///
+    /// Prometheus [recommends][naming] that counter names end with `_total`.  When enabled, the
+    /// suffix is appended to the name of every counter when rendering, unless the name already ends
+    /// with `_total`.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [naming]: https://prometheus.io/docs/practices/naming/#metric-names
+    #[must_use]
+    pub fn counter_total_suffix(mut self, enabled: bool) -> Self {
+        self.counter_total_suffix = enabled;
+        self
+    }
 
-            let (recorder, exporter) = {
-                let _g = runtime.enter();
-                self.build()?
-            };
+    /// Sets whether or not metric names and label keys are strictly sanitized.
+    ///
+    /// Metric names are always sanitized to match `[a-zA-Z_][a-zA-Z0-9_]*`: invalid characters,
+    /// including colons, are replaced with underscores, and a leading digit is preserved by prefixing
+    /// an underscore, such that `1foo` becomes `_1foo`.  By default, label keys are sanitized the
+    /// same way, except that a leading digit is replaced instead.  When strict sanitization is
+    /// enabled, label keys are sanitized exactly like metric names.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn strict_names(mut self, enabled: bool) -> Self {
+        self.strict_names = enabled;
+        self
+    }
 
-            thread::Builder::new()
-                .name(thread_name)
-                .spawn(move || runtime.block_on(exporter))
-                .map_err(|e| BuildError::FailedToCreateRuntime(e.to_string()))?;
+    /// Sets whether or not metric names are lowercased.
+    ///
+    /// When enabled, metric names are lowercased once sanitized and rewritten, which enforces
+    /// consistency when instrumentation mixes naming styles, such that `HttpRequests` becomes
+    /// `httprequests`.  Names which only differed by case then collide, and their series are merged,
+    /// just as for names which are the same once sanitized.  The namespace, if any, is left as is.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn lowercase_names(mut self, enabled: bool) -> Self {
+        self.lowercase_names = enabled;
+        self
+    }
 
-            recorder
-        };
+    /// Sets whether or not label keys are lowercased.
+    ///
+    /// When enabled, label keys are lowercased once sanitized, such that `Method` becomes `method`.
+    /// Labels whose keys only differed by case then collide, in which case only one of them is
+    /// rendered.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn lowercase_label_keys(mut self, enabled: bool) -> Self {
+        self.lowercase_label_keys = enabled;
+        self
+    }
 
-        metrics::set_global_recorder(recorder)?;
+    /// Sets a custom function for sanitizing metric names.
+    ///
+    /// The function is given the name of each metric, and must return a name which is valid under
+    /// the Prometheus [data model], as it is rendered as-is.  It replaces the default sanitization of
+    /// metric names, as well as [`strict_names`][Self::strict_names], but label keys are still
+    /// sanitized as usual.
+    ///
+    /// [data model]: https://prometheus.io/docs/concepts/data_model/#metric-names-and-labels
+    #[must_use]
+    pub fn name_sanitizer<F>(mut self, sanitizer: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.name_sanitizer = Some(NameSanitizer::new(sanitizer));
+        self
+    }
 
-        Ok(())
+    /// Sets a custom function for rewriting metric names, such as to collapse versioned names like
+    /// `api_v1_requests` and `api_v2_requests` into `api_requests`.
+    ///
+    /// The function is given the name of each metric after it has been sanitized, but before the
+    /// [namespace][Self::with_namespace] is added, and the name it returns is used as-is.  Names
+    /// which are rewritten to the same name are merged, in the same way as names which are the same
+    /// once sanitized: counters are summed, histograms share their samples, and the last gauge
+    /// wins.  Rewrites must therefore only collapse metrics that are safe to merge, and which are
+    /// of the same kind.  Descriptions, as well as bucket and quantile matchers, apply to the
+    /// rewritten names.
+    #[must_use]
+    pub fn name_rewriter<F>(mut self, rewriter: F) -> Self
+    where
+        F: Fn(&str) -> Cow<'_, str> + Send + Sync + 'static,
+    {
+        self.name_rewriter = Some(NameRewriter::new(rewriter));
+        self
     }
 
-    /// Builds the recorder and installs it globally, returning a handle to it.
+    /// Sets the character that invalid characters in metric names and label keys are replaced with.
     ///
-    /// The handle can be used to generate valid Prometheus scrape endpoint payloads directly.
+    /// As the replacement may stand in for the first character of a name, it must be an ASCII letter
+    /// or an underscore.  This does not apply to metric names sanitized by a custom
+    /// [`name_sanitizer`][Self::name_sanitizer].
+    ///
+    /// Defaults to `_`.
     ///
     /// ## Errors
     ///
-    /// If there is an error while building the recorder, or installing the recorder, an error
-    /// variant will be returned describing the error.
-    pub fn install_recorder(self) -> Result<PrometheusHandle, BuildError> {
-        let recorder = self.build_recorder();
-        let handle = recorder.handle();
+    /// If `replacement` is not an ASCII letter or an underscore, an error variant will be thrown.
+    pub fn sanitize_replacement(mut self, replacement: char) -> Result<Self, BuildError> {
+        if !(replacement.is_ascii_alphabetic() || replacement == '_') {
+            return Err(BuildError::InvalidSanitizeReplacement(replacement));
+        }
 
-        metrics::set_global_recorder(recorder)?;
+        self.sanitize_replacement = replacement;
+        Ok(self)
+    }
 
-        Ok(handle)
+    /// Sets a namespace to prefix every metric name with.
+    ///
+    /// The namespace is joined to each metric name with an underscore, after the name has been
+    /// sanitized, such that `requests` becomes `myapp_requests` for a namespace of `myapp`.  Names
+    /// which already start with the namespace are left as-is.  This applies to counters, gauges, and
+    /// distributions alike, as well as to the names given to matchers when overriding buckets or
+    /// quantiles, other than [`Matcher::Suffix`].
+    ///
+    /// Defaults to no namespace.
+    #[must_use]
+    pub fn with_namespace<N>(mut self, namespace: N) -> Self
+    where
+        N: Into<String>,
+    {
+        self.namespace = Some(namespace.into());
+        self
     }
 
-    /// Builds the recorder and exporter and returns them both.
+    /// Sets whether or not describing a metric replaces any existing description.
     ///
-    /// In most cases, users should prefer to use [`install`][PrometheusBuilder::install] to create
-    /// and install the recorder and exporter automatically for them.  If a caller is combining
-    /// recorders, or needs to schedule the exporter to run in a particular way, this method, or
-    /// [`build_recorder`][PrometheusBuilder::build_recorder], provide the flexibility to do so.
+    /// By default, only the first description (and unit) given for a metric is kept, and later
+    /// descriptions are ignored.  When enabled, the most recent description and unit given for a
+    /// metric are used instead, which allows registering metrics early with a placeholder
+    /// description and refining it later.
     ///
-    /// ## Panics
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn overwrite_descriptions(mut self, enabled: bool) -> Self {
+        self.overwrite_descriptions = enabled;
+        self
+    }
+
+    /// Sets the policy for handling differing descriptions given for the same metric name, such as
+    /// for different metrics whose names are the same once sanitized.
     ///
-    /// This method must be called from within an existing Tokio runtime or it will panic.
+    /// See [`DescriptionCollisionPolicy`] for the available policies.  This has no effect if
+    /// [`overwrite_descriptions`][Self::overwrite_descriptions] is enabled, in which case the most
+    /// recent description is always used.
     ///
-    /// ## Errors
+    /// Defaults to [`DescriptionCollisionPolicy::First`].
+    #[must_use]
+    pub fn description_collision_policy(mut self, policy: DescriptionCollisionPolicy) -> Self {
+        self.description_collision_policy = policy;
+        self
+    }
+
+    /// Sets whether or not each sample is rendered with a timestamp.
     ///
-    /// If there is an error while building the recorder and exporter, an error variant will be
-    /// returned describing the error.
-    #[warn(clippy::too_many_lines)]
-    #[cfg(any(feature = "http-listener", feature = "push-gateway"))]
-    #[cfg_attr(docsrs, doc(cfg(any(feature = "http-listener", feature = "push-gateway"))))]
-    #[cfg_attr(not(feature = "http-listener"), allow(unused_mut))]
-    pub fn build(mut self) -> Result<(PrometheusRecorder, ExporterFuture), BuildError> {
-        #[cfg(feature = "http-listener")]
-        let allowed_addresses = self.allowed_addresses.take();
-        let exporter_config = self.exporter_config.clone();
-        let upkeep_timeout = self.upkeep_timeout;
+    /// When enabled, every sample line is followed by the time at which the output was rendered:
+    /// milliseconds since the Unix epoch for the Prometheus exposition format, or seconds since the
+    /// Unix epoch for `OpenMetrics`.  Most scrapers prefer to assign timestamps themselves, so this is
+    /// primarily useful for debugging.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn with_timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
 
-        let recorder = self.build_recorder();
-        let handle = recorder.handle();
+    /// Sets whether or not the creation time of counters, histograms, and summaries is rendered.
+    ///
+    /// When enabled, the time at which each series was first registered is tracked, and rendered in
+    /// the `OpenMetrics` text format as a `<name>_created` sample, in seconds since the Unix epoch.
+    /// This lets consumers tell a reset series from one which simply stopped increasing.  The
+    /// Prometheus exposition format has no such notion, so nothing is rendered there.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn with_created_timestamps(mut self, enabled: bool) -> Self {
+        self.created_timestamps = enabled;
+        self
+    }
 
-        let recorder_handle = handle.clone();
-        tokio::spawn(async move {
-            loop {
+    /// Sets whether or not the metadata of each metric family is rendered.
+    ///
+    /// When disabled, the `# HELP`, `# TYPE`, and `# UNIT` lines are omitted, leaving only the
+    /// samples themselves, which saves bandwidth for high-frequency scrapes.  Metrics are then
+    /// treated as untyped by Prometheus, which is acceptable for the legacy text format with some
+    /// consumers, but the output is no longer valid `OpenMetrics`, which requires the metadata.
+    ///
+    /// Defaults to `true`.
+    #[must_use]
+    pub fn emit_metadata(mut self, enabled: bool) -> Self {
+        self.emit_metadata = enabled;
+        self
+    }
+
+    /// Sets whether or not the unit of a metric is appended to its description in the `# HELP` line.
+    ///
+    /// When enabled, metrics described with both a unit and a description are rendered with a help
+    /// text such as `Request latency (unit: seconds)`.  The Prometheus exposition format has no
+    /// `# UNIT` line, and `OpenMetrics` only renders it when the unit is a suffix of the metric name,
+    /// so this lets dashboards show units regardless.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn append_unit_to_help(mut self, enabled: bool) -> Self {
+        self.append_unit_to_help = enabled;
+        self
+    }
+
+    /// Sets whether or not the blank line between metric families is omitted.
+    ///
+    /// The Prometheus exposition format separates each metric family, starting with its `# HELP` or
+    /// `# TYPE` line, with a blank line.  When enabled, these blank lines are omitted, which some
+    /// strict parsers prefer.  `OpenMetrics` output never contains blank lines.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn compact_output(mut self, enabled: bool) -> Self {
+        self.compact_output = enabled;
+        self
+    }
+
+    /// Sets the policy for handling a metric name which is registered as more than one kind of
+    /// metric, such as both a counter and a gauge.
+    ///
+    /// See [`KindCollisionPolicy`] for the available policies.
+    ///
+    /// Defaults to [`KindCollisionPolicy::Warn`].
+    #[must_use]
+    pub fn kind_collision_policy(mut self, policy: KindCollisionPolicy) -> Self {
+        self.kind_collision_policy = policy;
+        self
+    }
+
+    /// Sets whether or not counters are prevented from decreasing between renders.
+    ///
+    /// A rendered counter can decrease if it starts over, such as when it is removed for being idle
+    /// and then registered again, which Prometheus interprets as a counter reset.  When enabled, the
+    /// highest value rendered so far is remembered for each counter, and rendered in place of any
+    /// lower value.
+    ///
+    /// Once a counter is removed, its highest value is remembered for as long as the idle timeout
+    /// for counters, if any, so that it stays monotonic if registered again within that time, and
+    /// is otherwise forgotten, such that it starts over.  With a
+    /// [cardinality limit][Self::with_cardinality_limit], the values of removed counters are also
+    /// forgotten, the longest removed first, once more values are remembered than the limit.  All
+    /// values are forgotten when [`PrometheusHandle::clear`][crate::PrometheusHandle::clear] is
+    /// called.  The same values are used to detect counters going backwards when
+    /// [logging anomalies][Self::log_on_anomaly].
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn monotonic_counters(mut self, enabled: bool) -> Self {
+        self.monotonic_counters = enabled;
+        self
+    }
+
+    /// Sets whether or not counters are rendered as the increase since the previous render.
+    ///
+    /// When enabled, each render behaves as though counters were reset to zero afterwards: the value
+    /// rendered for a counter is how much it increased since it was last rendered, or its full value
+    /// the first time.  The counters themselves keep counting up, so no increments are lost between
+    /// renders.  A counter which is lower than when it was last rendered, such as after starting
+    /// over, is rendered with its full value.
+    ///
+    /// Only renders of the full payload count as renders here: that is
+    /// [`PrometheusHandle::render`][crate::PrometheusHandle::render] and its variants in other
+    /// formats or encodings, as used by the HTTP listener and push gateway.  Snapshots and partial
+    /// renders, such as [`PrometheusHandle::snapshot`][crate::PrometheusHandle::snapshot] or
+    /// [`PrometheusHandle::render_family`][crate::PrometheusHandle::render_family], report
+    /// cumulative values, and leave the increase to be reported by the next full render.
+    ///
+    /// **This breaks the semantics of Prometheus counters**, which are expected to be cumulative, so
+    /// functions such as `rate` will give meaningless results.  It is only intended for pipelines
+    /// which consume each scrape exactly once, such as batch jobs.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn delta_counters(mut self, enabled: bool) -> Self {
+        self.delta_counters = enabled;
+        self
+    }
+
+    /// Sets whether or not anomalous metric values are logged when rendering.
+    ///
+    /// When enabled, a warning is logged whenever a counter is lower than the value it was
+    /// previously rendered with, or a gauge has a value of NaN, both of which usually point to bugs
+    /// in instrumentation.  Warnings are only logged when the `tracing` feature is enabled, which it
+    /// is by default as part of the `http-listener` and `push-gateway` features.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn log_on_anomaly(mut self, enabled: bool) -> Self {
+        self.log_on_anomaly = enabled;
+        self
+    }
+
+    /// Sets whether or not the recorder reports metrics about its own rendering.
+    ///
+    /// When enabled, every call to [`PrometheusHandle::render`][crate::PrometheusHandle::render]
+    /// or [`PrometheusHandle::render_openmetrics`][crate::PrometheusHandle::render_openmetrics],
+    /// including those made by the HTTP listener and push gateway, updates the
+    /// `prometheus_render_duration_seconds` and `prometheus_render_size_bytes` gauges with the time
+    /// taken and the size of the payload.  As the gauges are updated once the render completes, each
+    /// render reports the values of the previous one.
+    ///
+    /// Defaults to `false`.
+    #[must_use]
+    pub fn with_self_metrics(mut self, enabled: bool) -> Self {
+        self.self_metrics = enabled;
+        self
+    }
+
+    /// Sets whether or not the recorder reports standard metrics about the current process.
+    ///
+    /// When enabled, the `process_cpu_seconds_total`, `process_resident_memory_bytes`, and
+    /// `process_open_fds` gauges are updated whenever metrics are rendered.  CPU time is reported as
+    /// a gauge, rather than as a counter, as counters hold integer values, and it would otherwise
+    /// lose its sub-second precision.  These metrics are only collected on Linux, and are not
+    /// reported at all on other platforms.
+    ///
+    /// Defaults to `false`.
+    #[cfg(feature = "process-metrics")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "process-metrics")))]
+    #[must_use]
+    pub fn with_process_metrics(mut self, enabled: bool) -> Self {
+        self.process_metrics = enabled;
+        self
+    }
+
+    /// Builds the recorder and exporter and installs them globally.
+    ///
+    /// When called from within a Tokio runtime, the exporter future is spawned directly
+    /// into the runtime.  Otherwise, a new single-threaded Tokio runtime is created
+    /// on a background thread, and the exporter is spawned there.
+    ///
+    /// ## Errors
+    ///
+    /// If there is an error while either building the recorder and exporter, or installing the
+    /// recorder and exporter, an error variant will be returned describing the error.
+    #[cfg(any(feature = "http-listener", feature = "push-gateway"))]
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "http-listener", feature = "push-gateway"))))]
+    pub fn install(self) -> Result<(), BuildError> {
+        use tokio::runtime;
+
+        let recorder = if let Ok(handle) = runtime::Handle::try_current() {
+            let (recorder, exporter) = {
+                let _g = handle.enter();
+                self.build()?
+            };
+
+            handle.spawn(exporter);
+
+            recorder
+        } else {
+            let thread_name =
+                format!("metrics-exporter-prometheus-{}", self.exporter_config.as_type_str());
+
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| BuildError::FailedToCreateRuntime(e.to_string()))?;
+
+            let (recorder, exporter) = {
+                let _g = runtime.enter();
+                self.build()?
+            };
+
+            thread::Builder::new()
+                .name(thread_name)
+                .spawn(move || runtime.block_on(exporter))
+                .map_err(|e| BuildError::FailedToCreateRuntime(e.to_string()))?;
+
+            recorder
+        };
+
+        metrics::set_global_recorder(recorder)?;
+
+        Ok(())
+    }
+
+    /// Builds the recorder and installs it globally, returning a handle to it.
+    ///
+    /// The handle can be used to generate valid Prometheus scrape endpoint payloads directly.
+    ///
+    /// ## Errors
+    ///
+    /// If there is an error while building the recorder, or installing the recorder, an error
+    /// variant will be returned describing the error.
+    pub fn install_recorder(self) -> Result<PrometheusHandle, BuildError> {
+        let recorder = self.build_recorder();
+        let handle = recorder.handle();
+
+        metrics::set_global_recorder(recorder)?;
+
+        Ok(handle)
+    }
+
+    /// Builds the recorder and exporter and returns them both.
+    ///
+    /// In most cases, users should prefer to use [`install`][PrometheusBuilder::install] to create
+    /// and install the recorder and exporter automatically for them.  If a caller is combining
+    /// recorders, or needs to schedule the exporter to run in a particular way, this method, or
+    /// [`build_recorder`][PrometheusBuilder::build_recorder], provide the flexibility to do so.
+    ///
+    /// ## Panics
+    ///
+    /// This method must be called from within an existing Tokio runtime or it will panic.
+    ///
+    /// ## Errors
+    ///
+    /// If there is an error while building the recorder and exporter, an error variant will be
+    /// returned describing the error.  This includes having set both buckets and quantiles for the
+    /// same metric matcher.
+    #[warn(clippy::too_many_lines)]
+    #[cfg(any(feature = "http-listener", feature = "push-gateway"))]
+    #[cfg_attr(docsrs, doc(cfg(any(feature = "http-listener", feature = "push-gateway"))))]
+    #[cfg_attr(not(feature = "http-listener"), allow(unused_mut))]
+    pub fn build(mut self) -> Result<(PrometheusRecorder, ExporterFuture), BuildError> {
+        self.check_distribution_overrides()?;
+
+        #[cfg(feature = "http-listener")]
+        let allowed_addresses = self.allowed_addresses.take();
+        #[cfg(feature = "http-listener")]
+        let basic_auth = self
+            .basic_auth
+            .take()
+            .map(|(username, password)| super::basic_auth(&username, Some(&password)));
+        let exporter_config = self.exporter_config.clone();
+        let upkeep_timeout = self.upkeep_timeout;
+
+        let recorder = self.build_recorder();
+        let handle = recorder.handle();
+
+        // Only hold a weak reference, so that the task stops once the recorder is dropped.
+        let recorder_handle = handle.downgrade();
+        tokio::spawn(async move {
+            loop {
                 tokio::time::sleep(upkeep_timeout).await;
-                recorder_handle.run_upkeep();
+                let Some(handle) = recorder_handle.upgrade() else { break };
+                handle.run_upkeep();
             }
         });
 
@@ -476,11 +1202,16 @@ impl PrometheusBuilder {
                             handle,
                             listen_address,
                             allowed_addresses,
+                            basic_auth,
                         )?
                     }
                     #[cfg(feature = "uds-listener")]
                     super::ListenDestination::Uds(listen_path) => {
-                        super::http_listener::new_http_uds_listener(handle, listen_path)?
+                        super::http_listener::new_http_uds_listener(
+                            handle,
+                            listen_path,
+                            basic_auth,
+                        )?
                     }
                 },
 
@@ -494,25 +1225,157 @@ impl PrometheusBuilder {
         ))
     }
 
+    /// Checks that no metric matcher has both buckets and quantiles set for it.
+    #[cfg(any(feature = "http-listener", feature = "push-gateway"))]
+    fn check_distribution_overrides(&self) -> Result<(), BuildError> {
+        let (Some(buckets), Some(quantiles)) = (&self.bucket_overrides, &self.quantile_overrides)
+        else {
+            return Ok(());
+        };
+
+        // Report the same matcher every time if there are several conflicts.
+        match buckets.keys().filter(|matcher| quantiles.contains_key(*matcher)).min() {
+            Some(matcher) => {
+                Err(BuildError::ConflictingDistributionConfig { matcher: matcher.clone() })
+            }
+            None => Ok(()),
+        }
+    }
+
     /// Builds the recorder and returns it.
-    pub fn build_recorder(self) -> PrometheusRecorder {
-        self.build_with_clock(Clock::new())
+    ///
+    /// The recorder is not installed globally, which is useful in tests, or when combining several
+    /// recorders.  A handle for rendering its metrics can be obtained with
+    /// [`PrometheusRecorder::handle`].
+    ///
+    /// ```
+    /// # use metrics::{Key, Metadata, Level, Recorder};
+    /// # use metrics_exporter_prometheus::PrometheusBuilder;
+    /// let recorder = PrometheusBuilder::new().build_recorder();
+    /// let handle = recorder.handle();
+    ///
+    /// let metadata = Metadata::new(module_path!(), Level::INFO, None);
+    /// recorder.register_counter(&Key::from_name("requests"), &metadata).increment(1);
+    /// assert_eq!(handle.render(), "# TYPE requests counter\nrequests 1\n\n");
+    /// ```
+    pub fn build_recorder(mut self) -> PrometheusRecorder {
+        let clock = self.clock.take().unwrap_or_default();
+        self.build_with_clock(clock)
     }
 
     pub(crate) fn build_with_clock(self, clock: Clock) -> PrometheusRecorder {
+        let (name_sanitizer, strict_names) = (&self.name_sanitizer, self.strict_names);
+        let (replacement, lowercase_names) = (self.sanitize_replacement, self.lowercase_names);
+        let sanitize_name = |name: &str| {
+            let mut name = match name_sanitizer {
+                Some(sanitizer) => sanitizer.sanitize(name),
+                None if strict_names => sanitize_strict_with(name, replacement),
+                None => sanitize_metric_name_with(name, replacement),
+            };
+            if lowercase_names {
+                name.make_ascii_lowercase();
+            }
+            name
+        };
+        let namespace = self.namespace.as_deref().map(|namespace| {
+            let mut prefix = sanitize_name(namespace);
+            prefix.push('_');
+            prefix
+        });
+        let prefix = namespace.as_deref();
+        let bucket_overrides = self.bucket_overrides.map(|overrides| {
+            overrides
+                .into_iter()
+                .map(|(matcher, buckets)| {
+                    (matcher.sanitized(sanitize_name).namespaced(prefix), buckets)
+                })
+                .collect()
+        });
+        let denied_metrics = self
+            .denied_metrics
+            .into_iter()
+            .map(|matcher| matcher.sanitized(sanitize_name).namespaced(prefix))
+            .collect();
+        let quantile_overrides = self.quantile_overrides.map(|overrides| {
+            overrides
+                .into_iter()
+                .map(|(matcher, quantiles)| {
+                    (matcher.sanitized(sanitize_name).namespaced(prefix), quantiles)
+                })
+                .collect()
+        });
+
+        let mut distribution_builder = DistributionBuilder::new(
+            self.quantiles,
+            self.bucket_duration,
+            self.buckets,
+            self.bucket_count,
+            bucket_overrides,
+            quantile_overrides,
+        );
+        if let Some(accuracy) = self.summary_relative_accuracy {
+            distribution_builder = distribution_builder.with_summary_relative_accuracy(accuracy);
+        }
+        if let Some(max_age) = self.summary_max_age {
+            distribution_builder = distribution_builder.with_summary_max_age(max_age);
+        }
+
+        // Removed counters are remembered for as long as counters are kept while idle.
+        let counters_expire = self.recency_mask.matches(MetricKind::Counter);
+        let exported_counter_retention = self.idle_timeout.filter(|_| counters_expire);
+
         let inner = Inner {
-            registry: Registry::new(GenerationalStorage::new(AtomicStorage)),
-            recency: Recency::new(clock, self.recency_mask, self.idle_timeout),
+            registry: Registry::new(GenerationalStorage::new(AtomicStorage::new(clock.clone()))),
+            recency: Recency::new(clock.clone(), self.recency_mask, self.idle_timeout),
             distributions: RwLock::new(HashMap::new()),
-            distribution_builder: DistributionBuilder::new(
-                self.quantiles,
-                self.bucket_duration,
-                self.buckets,
-                self.bucket_count,
-                self.bucket_overrides,
-            ),
+            drain_lock: Mutex::new(()),
+            distribution_builder,
             descriptions: RwLock::new(HashMap::new()),
+            units: RwLock::new(HashMap::new()),
+            exemplars: RwLock::new(HashMap::new()),
+            kinds: RwLock::new(HashMap::new()),
+            logged_kind_collisions: Mutex::new(HashSet::new()),
+            kind_collision_policy: self.kind_collision_policy,
+            monotonic_counters: self.monotonic_counters,
+            delta_counters: self.delta_counters,
+            counter_baselines: RwLock::new(BTreeMap::new()),
+            log_on_anomaly: self.log_on_anomaly,
+            self_metrics: self.self_metrics,
+            keep_registered_families: self.keep_registered_families,
+            #[cfg(feature = "process-metrics")]
+            process_metrics: self.process_metrics,
+            exported_counters: RwLock::new(BTreeMap::new()),
+            exported_counter_retention,
             global_labels: self.global_labels.unwrap_or_default(),
+            dropped_labels: self.dropped_labels,
+            sort_labels: self.sort_labels,
+            drop_empty_labels: self.drop_empty_labels,
+            max_label_value_len: self.max_label_value_len,
+            counter_total_suffix: self.counter_total_suffix,
+            strict_names: self.strict_names,
+            lowercase_names: self.lowercase_names,
+            lowercase_label_keys: self.lowercase_label_keys,
+            name_sanitizer: self.name_sanitizer,
+            name_rewriter: self.name_rewriter,
+            sanitize_replacement: self.sanitize_replacement,
+            namespace,
+            overwrite_descriptions: self.overwrite_descriptions,
+            description_collision_policy: self.description_collision_policy,
+            created: RwLock::new(HashMap::new()),
+            created_timestamps: self.created_timestamps,
+            timestamps: self.timestamps,
+            compact_output: self.compact_output,
+            emit_metadata: self.emit_metadata,
+            append_unit_to_help: self.append_unit_to_help,
+            aggregate_histograms: self.aggregate_histograms,
+            count_out_of_bounds: self.count_out_of_bounds,
+            cardinality_limit: self.cardinality_limit,
+            series_count: AtomicUsize::new(0),
+            denied_metrics,
+            scrape_count: AtomicU64::new(0),
+            render_size_estimate: AtomicUsize::new(0),
+            last_scrape: RwLock::new(None),
+            clock,
         };
 
         PrometheusRecorder::from(inner)
@@ -525,17 +1388,47 @@ impl Default for PrometheusBuilder {
     }
 }
 
+/// Checks that the given bucket bounds are non-empty and not NaN, returning them sorted and
+/// deduplicated.
+fn validate_buckets(values: &[f64]) -> Result<Vec<f64>, BuildError> {
+    if values.is_empty() {
+        return Err(BuildError::EmptyBucketsOrQuantiles);
+    }
+
+    if values.iter().any(|v| v.is_nan()) {
+        return Err(BuildError::InvalidBuckets(values.to_vec()));
+    }
+
+    Ok(sorted_buckets(values.to_vec()))
+}
+
+/// Checks that the given quantiles are non-empty and all within the range of 0.0 to 1.0.
+fn validate_quantiles(quantiles: &[f64]) -> Result<(), BuildError> {
+    if quantiles.is_empty() {
+        return Err(BuildError::EmptyBucketsOrQuantiles);
+    }
+
+    match quantiles.iter().find(|q| !(0.0..=1.0).contains(*q)) {
+        Some(q) => Err(BuildError::InvalidQuantile(*q)),
+        None => Ok(()),
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::approx_constant)]
 mod tests {
+    use std::borrow::Cow;
     use std::time::Duration;
 
     use quanta::Clock;
 
-    use metrics::{Key, KeyName, Label, Recorder};
-    use metrics_util::MetricKindMask;
+    use metrics::{Key, KeyName, Label, Recorder, Unit};
+    use metrics_util::{MetricKind, MetricKindMask};
 
-    use super::{Matcher, PrometheusBuilder};
+    use super::{
+        DescriptionCollisionPolicy, DistributionKind, KindCollisionPolicy, Matcher,
+        PrometheusBuilder,
+    };
 
     static METADATA: metrics::Metadata =
         metrics::Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
@@ -584,6 +1477,111 @@ mod tests {
         assert_eq!(rendered, expected_histogram);
     }
 
+    #[test]
+    fn test_render_sorted() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+
+        for (name, labels) in [
+            ("zeta_counter", vec![Label::new("shard", "b")]),
+            ("alpha_counter", vec![]),
+            ("zeta_counter", vec![Label::new("shard", "a")]),
+            ("mid_counter", vec![Label::new("shard", "c")]),
+        ] {
+            let key = Key::from_parts(name, labels);
+            recorder.register_counter(&key, &METADATA).increment(1);
+        }
+
+        recorder.register_gauge(&Key::from_name("zeta_gauge"), &METADATA).set(2.0);
+        recorder.register_gauge(&Key::from_name("alpha_gauge"), &METADATA).set(1.0);
+
+        let handle = recorder.handle();
+        let expected = concat!(
+            "# TYPE alpha_counter counter\n",
+            "alpha_counter 1\n\n",
+            "# TYPE mid_counter counter\n",
+            "mid_counter{shard=\"c\"} 1\n\n",
+            "# TYPE zeta_counter counter\n",
+            "zeta_counter{shard=\"a\"} 1\n",
+            "zeta_counter{shard=\"b\"} 1\n\n",
+            "# TYPE alpha_gauge gauge\n",
+            "alpha_gauge 1\n\n",
+            "# TYPE zeta_gauge gauge\n",
+            "zeta_gauge 2\n\n",
+        );
+
+        // Rendering repeatedly should always produce the exact same output.
+        for _ in 0..5 {
+            assert_eq!(handle.render(), expected);
+        }
+    }
+
+    #[test]
+    fn test_render_to_writer() {
+        let recorder =
+            PrometheusBuilder::new().set_quantiles(&[0.0, 1.0]).unwrap().build_recorder();
+
+        recorder.register_counter(&Key::from_name("basic_counter"), &METADATA).increment(42);
+        let key = Key::from_parts("basic_gauge", vec![Label::new("wutang", "forever")]);
+        recorder.register_gauge(&key, &METADATA).set(-3.14);
+        recorder.register_histogram(&Key::from_name("basic_histogram"), &METADATA).record(12.0);
+
+        let handle = recorder.handle();
+        let mut output = Vec::new();
+        handle.render_to_writer(&mut output).expect("writing to a Vec should not fail");
+
+        assert_eq!(output, handle.render().into_bytes());
+    }
+
+    #[test]
+    fn test_render_to_writer_error() {
+        struct FailingWriter;
+
+        impl std::io::Write for FailingWriter {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed"))
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        recorder.register_counter(&Key::from_name("basic_counter"), &METADATA).increment(42);
+
+        let handle = recorder.handle();
+        let result = handle.render_to_writer(&mut FailingWriter);
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_clear() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+
+        let key_name = KeyName::from("basic_counter");
+        recorder.describe_counter(key_name.clone(), None, "A basic counter.".into());
+        let key = Key::from_name(key_name);
+        recorder.register_counter(&key, &METADATA).increment(42);
+        recorder.register_histogram(&Key::from_name("basic_histogram"), &METADATA).record(1.0);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        assert!(rendered.contains("basic_counter 42\n"));
+        assert!(rendered.contains("basic_histogram_count 1\n"));
+
+        handle.clear();
+        assert_eq!(handle.render(), "");
+
+        // Descriptions survive clearing, so re-registered metrics pick them back up.
+        recorder.register_counter(&key, &METADATA).increment(1);
+        let expected_counter = concat!(
+            "# HELP basic_counter A basic counter.\n",
+            "# TYPE basic_counter counter\n",
+            "basic_counter 1\n\n",
+        );
+        assert_eq!(handle.render(), expected_counter);
+    }
+
     #[test]
     fn test_buckets() {
         const DEFAULT_VALUES: [f64; 3] = [10.0, 100.0, 1000.0];
@@ -673,6 +1671,53 @@ mod tests {
         assert!(rendered.contains(default_data));
     }
 
+    #[test]
+    fn test_buckets_for_metric_fallback() {
+        let recorder = PrometheusBuilder::new()
+            .set_quantiles(&[0.0, 1.0])
+            .unwrap()
+            .set_buckets_for_metric(
+                Matcher::Prefix("http_request_duration".to_owned()),
+                &[0.1, 1.0],
+            )
+            .unwrap()
+            .set_buckets_for_metric(Matcher::Prefix("response_size".to_owned()), &[512.0, 4096.0])
+            .unwrap()
+            .build_recorder();
+
+        let key = Key::from_name("http_request_duration_seconds");
+        recorder.register_histogram(&key, &METADATA).record(0.5);
+        let key = Key::from_name("response_size_bytes");
+        recorder.register_histogram(&key, &METADATA).record(1024.0);
+        let key = Key::from_name("queue_wait_seconds");
+        recorder.register_histogram(&key, &METADATA).record(2.0);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+
+        let duration_data = concat!(
+            "# TYPE http_request_duration_seconds histogram\n",
+            "http_request_duration_seconds_bucket{le=\"0.1\"} 0\n",
+            "http_request_duration_seconds_bucket{le=\"1\"} 1\n",
+            "http_request_duration_seconds_bucket{le=\"+Inf\"} 1\n",
+        );
+        let size_data = concat!(
+            "# TYPE response_size_bytes histogram\n",
+            "response_size_bytes_bucket{le=\"512\"} 0\n",
+            "response_size_bytes_bucket{le=\"4096\"} 1\n",
+            "response_size_bytes_bucket{le=\"+Inf\"} 1\n",
+        );
+        let fallback_data = concat!(
+            "# TYPE queue_wait_seconds summary\n",
+            "queue_wait_seconds{quantile=\"0\"} 2\n",
+            "queue_wait_seconds{quantile=\"1\"} 2\n",
+        );
+
+        assert!(rendered.contains(duration_data));
+        assert!(rendered.contains(size_data));
+        assert!(rendered.contains(fallback_data));
+    }
+
     #[test]
     fn test_idle_timeout_all() {
         let (clock, mock) = Clock::mock();
@@ -850,7 +1895,42 @@ mod tests {
     }
 
     #[test]
-    fn test_idle_timeout_doesnt_remove_recents() {
+    fn test_idle_timeout_frees_distributions() {
+        let (clock, mock) = Clock::mock();
+
+        let recorder = PrometheusBuilder::new()
+            .idle_timeout(MetricKindMask::ALL, Some(Duration::from_secs(10)))
+            .set_quantiles(&[0.0, 1.0])
+            .unwrap()
+            .build_with_clock(clock);
+
+        let key = Key::from_parts("basic_histogram", vec![Label::new("user", "alice")]);
+        recorder.register_histogram(&key, &METADATA).record(5.0);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        assert!(rendered.contains("basic_histogram_sum{user=\"alice\"} 5\n"));
+
+        mock.increment(Duration::from_secs(11));
+        let rendered = handle.render();
+        assert_eq!(rendered, "");
+
+        // If the aggregated distribution had been kept around, the new sample would be added to
+        // the old one instead of starting from scratch.
+        recorder.register_histogram(&key, &METADATA).record(2.0);
+        let expected = concat!(
+            "# TYPE basic_histogram summary\n",
+            "basic_histogram{user=\"alice\",quantile=\"0\"} 2\n",
+            "basic_histogram{user=\"alice\",quantile=\"1\"} 2\n",
+            "basic_histogram_sum{user=\"alice\"} 2\n",
+            "basic_histogram_count{user=\"alice\"} 1\n\n",
+        );
+        let rendered = handle.render();
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_idle_timeout_doesnt_remove_recents() {
         let (clock, mock) = Clock::mock();
 
         let recorder = PrometheusBuilder::new()
@@ -939,6 +2019,132 @@ mod tests {
         assert_eq!(rendered, "");
     }
 
+    #[test]
+    fn test_counter_total_suffix() {
+        let recorder = PrometheusBuilder::new().counter_total_suffix(true).build_recorder();
+
+        let key = Key::from_name("foo");
+        let counter1 = recorder.register_counter(&key, &METADATA);
+        counter1.increment(1);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        let expected_counter = "# TYPE foo_total counter\nfoo_total 1\n\n";
+        assert_eq!(rendered, expected_counter);
+
+        let recorder = PrometheusBuilder::new().counter_total_suffix(true).build_recorder();
+
+        let key = Key::from_name("foo_total");
+        let counter1 = recorder.register_counter(&key, &METADATA);
+        counter1.increment(1);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        let expected_counter = "# TYPE foo_total counter\nfoo_total 1\n\n";
+        assert_eq!(rendered, expected_counter);
+    }
+
+    #[test]
+    fn test_render_openmetrics() {
+        let recorder =
+            PrometheusBuilder::new().set_quantiles(&[0.0, 1.0]).unwrap().build_recorder();
+
+        let key_name = KeyName::from("basic_counter");
+        recorder.describe_counter(key_name.clone(), None, "A \"basic\" counter.".into());
+        let counter1 = recorder.register_counter(&Key::from_name(key_name), &METADATA);
+        counter1.increment(42);
+
+        let key = Key::from_name("requests_total");
+        let counter2 = recorder.register_counter(&key, &METADATA);
+        counter2.increment(1);
+
+        let key = Key::from_name("basic_histogram");
+        let histogram1 = recorder.register_histogram(&key, &METADATA);
+        histogram1.record(12.0);
+
+        let handle = recorder.handle();
+        let rendered = handle.render_openmetrics();
+
+        let counter_data = concat!(
+            "# HELP basic_counter A \\\"basic\\\" counter.\n",
+            "# TYPE basic_counter counter\n",
+            "basic_counter_total 42\n",
+        );
+        let suffixed_counter_data = "# TYPE requests counter\nrequests_total 1\n";
+        let histogram_data = concat!(
+            "# TYPE basic_histogram summary\n",
+            "basic_histogram{quantile=\"0\"} 12\n",
+            "basic_histogram{quantile=\"1\"} 12\n",
+            "basic_histogram_sum 12\n",
+            "basic_histogram_count 1\n",
+        );
+
+        assert!(rendered.contains(counter_data));
+        assert!(rendered.contains(suffixed_counter_data));
+        assert!(rendered.ends_with(&format!("{histogram_data}# EOF\n")));
+        assert!(!rendered.contains("\n\n"));
+    }
+
+    #[test]
+    fn test_render_openmetrics_single_inf_bucket() {
+        let recorder =
+            PrometheusBuilder::new().set_buckets(&[1.0, f64::INFINITY]).unwrap().build_recorder();
+
+        let key = Key::from_name("basic_histogram");
+        let histogram1 = recorder.register_histogram(&key, &METADATA);
+        histogram1.record(12.0);
+
+        let handle = recorder.handle();
+        let rendered = handle.render_openmetrics();
+        let expected = concat!(
+            "# TYPE basic_histogram histogram\n",
+            "basic_histogram_bucket{le=\"1\"} 0\n",
+            "basic_histogram_bucket{le=\"+Inf\"} 1\n",
+            "basic_histogram_sum 12\n",
+            "basic_histogram_count 1\n",
+            "# EOF\n",
+        );
+
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_units() {
+        let recorder =
+            PrometheusBuilder::new().set_quantiles(&[0.0, 1.0]).unwrap().build_recorder();
+
+        let key_name = KeyName::from("request_latency_milliseconds");
+        recorder.describe_histogram(
+            key_name.clone(),
+            Some(Unit::Milliseconds),
+            "Request latency.".into(),
+        );
+        let histogram1 = recorder.register_histogram(&Key::from_name(key_name), &METADATA);
+        histogram1.record(12.0);
+
+        let key_name = KeyName::from("queue_depth");
+        recorder.describe_gauge(key_name.clone(), Some(Unit::Count), "Queue depth.".into());
+        let gauge1 = recorder.register_gauge(&Key::from_name(key_name), &METADATA);
+        gauge1.set(3.0);
+
+        let handle = recorder.handle();
+        assert_eq!(handle.unit("request_latency_milliseconds"), Some(Unit::Milliseconds));
+        assert_eq!(handle.unit("queue_depth"), Some(Unit::Count));
+        assert_eq!(handle.unit("unknown"), None);
+
+        let rendered = handle.render_openmetrics();
+        let expected_histogram = concat!(
+            "# HELP request_latency_milliseconds Request latency.\n",
+            "# TYPE request_latency_milliseconds summary\n",
+            "# UNIT request_latency_milliseconds milliseconds\n",
+        );
+        assert!(rendered.contains(expected_histogram));
+        assert!(!rendered.contains("# UNIT queue_depth"));
+
+        let rendered = handle.render();
+        assert!(!rendered.contains("# UNIT"));
+    }
+
     #[test]
     pub fn test_global_labels() {
         let recorder = PrometheusBuilder::new()
@@ -956,6 +2162,27 @@ mod tests {
         assert_eq!(rendered, expected_counter);
     }
 
+    #[test]
+    pub fn test_global_labels_with_metric_labels() {
+        let recorder = PrometheusBuilder::new()
+            .add_global_label("service", "api")
+            .add_global_label("region", "us-east")
+            .build_recorder();
+
+        let key = Key::from_parts("requests", vec![Label::new("method", "GET")]);
+        let counter1 = recorder.register_counter(&key, &METADATA);
+        counter1.increment(1);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        let expected_counter = concat!(
+            "# TYPE requests counter\n",
+            "requests{method=\"GET\",region=\"us-east\",service=\"api\"} 1\n\n",
+        );
+
+        assert_eq!(rendered, expected_counter);
+    }
+
     #[test]
     pub fn test_global_labels_overrides() {
         let recorder = PrometheusBuilder::new().add_global_label("foo", "foo").build_recorder();
@@ -972,6 +2199,58 @@ mod tests {
         assert_eq!(rendered, expected_counter);
     }
 
+    #[test]
+    pub fn test_escaped_help_render() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+
+        let key_name = KeyName::from("basic_counter");
+        recorder.describe_counter(
+            key_name.clone(),
+            None,
+            "Counts things.\nStored under C:\\metrics.".into(),
+        );
+        let counter1 = recorder.register_counter(&Key::from_name(key_name), &METADATA);
+        counter1.increment(1);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        let expected_counter = concat!(
+            "# HELP basic_counter Counts things.\\nStored under C:\\\\metrics.\n",
+            "# TYPE basic_counter counter\n",
+            "basic_counter 1\n\n",
+        );
+
+        assert_eq!(rendered, expected_counter);
+    }
+
+    #[test]
+    pub fn test_strict_names_render() {
+        let recorder = PrometheusBuilder::new()
+            .strict_names(true)
+            .set_buckets_for_metric(Matcher::Full("1foo:bar".to_owned()), &[1.0])
+            .unwrap()
+            .build_recorder();
+
+        let key = Key::from_name("1foo:bar").with_extra_labels(vec![Label::new("a b", "c d")]);
+        recorder.register_histogram(&key, &METADATA).record(1.0);
+        let key = Key::from_name("yee_haw:lets go");
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        let expected = concat!(
+            "# TYPE yee_haw_lets_go counter\n",
+            "yee_haw_lets_go 1\n\n",
+            "# TYPE _1foo_bar histogram\n",
+            "_1foo_bar_bucket{a_b=\"c d\",le=\"1\"} 1\n",
+            "_1foo_bar_bucket{a_b=\"c d\",le=\"+Inf\"} 1\n",
+            "_1foo_bar_sum{a_b=\"c d\"} 1\n",
+            "_1foo_bar_count{a_b=\"c d\"} 1\n\n",
+        );
+
+        assert_eq!(rendered, expected);
+    }
+
     #[test]
     pub fn test_sanitized_render() {
         let recorder = PrometheusBuilder::new().add_global_label("foo:", "foo").build_recorder();
@@ -985,8 +2264,2106 @@ mod tests {
 
         let handle = recorder.handle();
         let rendered = handle.render();
-        let expected_counter = "# HELP yee_haw:lets_go \"Simplë stuff.\\nRëally.\"\n# TYPE yee_haw:lets_go counter\nyee_haw:lets_go{foo_=\"foo\",_hno=\"\\\"yeet\\nies\\\"\"} 1\n\n";
+        let expected_counter = "# HELP yee_haw_lets_go \"Simplë stuff.\\nRëally.\"\n# TYPE yee_haw_lets_go counter\nyee_haw_lets_go{foo_=\"foo\",_hno=\"\\\"yeet\\nies\\\"\"} 1\n\n";
+
+        assert_eq!(rendered, expected_counter);
+    }
+
+    #[test]
+    pub fn test_sanitized_label_key_render() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+
+        let key = Key::from_name("requests")
+            .with_extra_labels(vec![Label::new("http.method", "GET.v1.x")]);
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        let expected_counter = "# TYPE requests counter\nrequests{http_method=\"GET.v1.x\"} 1\n\n";
 
         assert_eq!(rendered, expected_counter);
     }
+
+    #[test]
+    pub fn test_quantiles_for_metric() {
+        let recorder = PrometheusBuilder::new()
+            .set_quantiles(&[0.5])
+            .unwrap()
+            .set_quantiles_for_metric(Matcher::Full("critical".to_owned()), &[0.999])
+            .unwrap()
+            .build_recorder();
+
+        recorder.register_histogram(&Key::from_name("critical"), &METADATA).record(1.0);
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(1.0);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+
+        assert!(rendered.contains("critical{quantile=\"0.999\"} "));
+        assert!(!rendered.contains("critical{quantile=\"0.5\"} "));
+        assert!(rendered.contains("latency{quantile=\"0.5\"} "));
+        assert!(!rendered.contains("latency{quantile=\"0.999\"} "));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    pub fn test_render_gzip() {
+        use std::io::Read;
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let key = Key::from_parts("basic_counter", vec![Label::new("wutang", "forever")]);
+        recorder.register_counter(&key, &METADATA).increment(42);
+        recorder.register_gauge(&Key::from_name("basic_gauge"), &METADATA).set(-3.14);
+
+        let handle = recorder.handle();
+        let compressed = handle.render_gzip();
+
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed).unwrap();
+
+        assert_eq!(decompressed, handle.render());
+    }
+
+    #[test]
+    pub fn test_render_filtered() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        recorder.describe_counter("db_queries".into(), None, "Database queries.".into());
+        recorder.register_counter(&Key::from_name("db_queries"), &METADATA).increment(3);
+        recorder.register_gauge(&Key::from_name("db_connections"), &METADATA).set(5.0);
+        recorder.register_counter(&Key::from_name("http_requests"), &METADATA).increment(7);
+
+        let handle = recorder.handle();
+        let rendered = handle.render_filtered("db_");
+        let expected = concat!(
+            "# HELP db_queries Database queries.\n",
+            "# TYPE db_queries counter\n",
+            "db_queries 3\n\n",
+            "# TYPE db_connections gauge\n",
+            "db_connections 5\n\n",
+        );
+
+        assert_eq!(rendered, expected);
+        assert!(handle.render().contains("http_requests 7\n"));
+    }
+
+    #[test]
+    pub fn test_summary_fractional_sum() {
+        let recorder =
+            PrometheusBuilder::new().set_quantiles(&[0.0, 1.0]).unwrap().build_recorder();
+
+        let histogram = recorder.register_histogram(&Key::from_name("integral"), &METADATA);
+        histogram.record(1.5);
+        histogram.record(2.5);
+        let histogram = recorder.register_histogram(&Key::from_name("fractional"), &METADATA);
+        histogram.record(1.25);
+        histogram.record(2.5);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+
+        assert!(rendered.contains("integral_sum 4\n"));
+        assert!(rendered.contains("integral_count 2\n"));
+        assert!(rendered.contains("fractional_sum 3.75\n"));
+        assert!(rendered.contains("fractional_count 2\n"));
+    }
+
+    #[test]
+    pub fn test_special_float_values() {
+        let recorder =
+            PrometheusBuilder::new().set_quantiles(&[0.0, 1.0]).unwrap().build_recorder();
+
+        recorder.register_gauge(&Key::from_name("nan"), &METADATA).set(f64::NAN);
+        recorder.register_gauge(&Key::from_name("pos_inf"), &METADATA).set(f64::INFINITY);
+        recorder.register_gauge(&Key::from_name("neg_inf"), &METADATA).set(f64::NEG_INFINITY);
+        recorder.register_histogram(&Key::from_name("summary"), &METADATA).record(f64::INFINITY);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+
+        assert!(rendered.contains("\nnan NaN\n"));
+        assert!(rendered.contains("\npos_inf +Inf\n"));
+        assert!(rendered.contains("\nneg_inf -Inf\n"));
+        assert!(rendered.contains("\nsummary_sum +Inf\n"));
+    }
+
+    #[test]
+    pub fn test_histogram_buckets_cumulative() {
+        let recorder =
+            PrometheusBuilder::new().set_buckets(&[1.0, 5.0, 10.0, 50.0]).unwrap().build_recorder();
+
+        let histogram = recorder.register_histogram(&Key::from_name("latency"), &METADATA);
+        for sample in [0.5, 3.0, 4.0, 7.0, 20.0, 30.0, 40.0, 100.0] {
+            histogram.record(sample);
+        }
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        let counts = rendered
+            .lines()
+            .filter(|line| line.starts_with("latency_bucket"))
+            .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(counts, vec![1, 3, 4, 7, 8]);
+        assert!(counts.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(rendered.contains("latency_bucket{le=\"+Inf\"} 8\n"));
+        assert!(rendered.contains("latency_count 8\n"));
+    }
+
+    #[test]
+    pub fn test_overwrite_descriptions() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        recorder.describe_counter("requests".into(), None, "Placeholder.".into());
+        recorder.describe_counter("requests".into(), None, "Total requests served.".into());
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.starts_with("# HELP requests Placeholder.\n"));
+
+        let recorder = PrometheusBuilder::new().overwrite_descriptions(true).build_recorder();
+        recorder.describe_counter("requests".into(), None, "Placeholder.".into());
+        recorder.describe_counter("requests".into(), None, "Total requests served.".into());
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.starts_with("# HELP requests Total requests served.\n"));
+    }
+
+    #[test]
+    pub fn test_set_description() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        recorder.describe_counter("requests".into(), None, "Placeholder.".into());
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+
+        let region = String::from("us-east-1");
+        let handle = recorder.handle();
+        handle.set_description("requests", format!("Requests served in {region}."));
+
+        let rendered = handle.render();
+        assert!(rendered.starts_with("# HELP requests Requests served in us-east-1.\n"));
+    }
+
+    #[test]
+    pub fn test_with_timestamps() {
+        let recorder = PrometheusBuilder::new()
+            .with_timestamps(true)
+            .set_buckets(&[1.0])
+            .unwrap()
+            .build_recorder();
+        recorder.register_counter(&Key::from_name("basic_counter"), &METADATA).increment(42);
+        recorder.register_gauge(&Key::from_name("basic_gauge"), &METADATA).set(-3.14);
+        recorder.register_histogram(&Key::from_name("basic_histogram"), &METADATA).record(0.5);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        let samples = rendered.lines().filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        let mut count = 0;
+        for line in samples {
+            let timestamp = line.rsplit(' ').next().unwrap();
+            assert_eq!(timestamp.len(), 13, "unexpected timestamp in line: {line}");
+            assert!(timestamp.bytes().all(|b| b.is_ascii_digit()));
+            count += 1;
+        }
+        assert_eq!(count, 6);
+        assert!(rendered.contains("basic_counter 42 "));
+        assert!(rendered.contains("basic_gauge -3.14 "));
+    }
+
+    #[test]
+    pub fn test_drop_labels() {
+        let recorder = PrometheusBuilder::new().drop_labels(&["user_id"]).build_recorder();
+
+        let key = Key::from_parts(
+            "requests",
+            vec![Label::new("method", "GET"), Label::new("user_id", "1")],
+        );
+        recorder.register_counter(&key, &METADATA).increment(3);
+        let key = Key::from_parts(
+            "requests",
+            vec![Label::new("method", "GET"), Label::new("user_id", "2")],
+        );
+        recorder.register_counter(&key, &METADATA).increment(4);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        let expected = "# TYPE requests counter\nrequests{method=\"GET\"} 7\n\n";
+
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    pub fn test_metric_count() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        assert_eq!(handle.metric_count(), 0);
+
+        for method in ["GET", "POST", "PUT"] {
+            let key = Key::from_parts("requests", vec![Label::new("method", method)]);
+            recorder.register_counter(&key, &METADATA).increment(1);
+        }
+        recorder.register_gauge(&Key::from_name("connections"), &METADATA).set(1.0);
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(1.0);
+        // Registering an existing series again does not add a new one.
+        let key = Key::from_parts("requests", vec![Label::new("method", "GET")]);
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        assert_eq!(handle.metric_count(), 5);
+    }
+
+    #[cfg(feature = "http-listener")]
+    #[test]
+    pub fn test_upkeep_removes_idle_metrics() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let (recorder, _exporter) = PrometheusBuilder::new()
+                .with_http_listener(([127, 0, 0, 1], 0))
+                .idle_timeout(MetricKindMask::ALL, Some(Duration::from_millis(50)))
+                .upkeep_timeout(Duration::from_millis(10))
+                .build()
+                .unwrap();
+
+            recorder.register_counter(&Key::from_name("basic_counter"), &METADATA).increment(1);
+            recorder.register_gauge(&Key::from_name("basic_gauge"), &METADATA).set(1.0);
+            recorder.register_histogram(&Key::from_name("basic_histogram"), &METADATA).record(1.0);
+
+            let handle = recorder.handle();
+            assert_eq!(handle.metric_count(), 3);
+
+            // No rendering happens here, so only the upkeep task can remove the idle metrics.
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert_eq!(handle.metric_count(), 0);
+        });
+    }
+
+    #[test]
+    pub fn test_default_distribution() {
+        let recorder = PrometheusBuilder::new()
+            .set_default_distribution(DistributionKind::Histogram(vec![1.0, 2.0]))
+            .unwrap()
+            .set_buckets_for_metric(Matcher::Full("overridden".to_owned()), &[5.0])
+            .unwrap()
+            .build_recorder();
+
+        recorder.register_histogram(&Key::from_name("unmatched"), &METADATA).record(1.5);
+        recorder.register_histogram(&Key::from_name("overridden"), &METADATA).record(1.5);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("# TYPE unmatched histogram\n"));
+        assert!(rendered.contains("unmatched_bucket{le=\"1\"} 0\n"));
+        assert!(rendered.contains("unmatched_bucket{le=\"2\"} 1\n"));
+        assert!(rendered.contains("overridden_bucket{le=\"5\"} 1\n"));
+        assert!(!rendered.contains("overridden_bucket{le=\"2\"}"));
+
+        let recorder = PrometheusBuilder::new()
+            .set_buckets(&[1.0])
+            .unwrap()
+            .set_default_distribution(DistributionKind::Summary(vec![0.5]))
+            .unwrap()
+            .set_buckets_for_metric(Matcher::Full("overridden".to_owned()), &[5.0])
+            .unwrap()
+            .build_recorder();
+
+        recorder.register_histogram(&Key::from_name("unmatched"), &METADATA).record(1.5);
+        recorder.register_histogram(&Key::from_name("overridden"), &METADATA).record(1.5);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("# TYPE unmatched summary\n"));
+        assert!(rendered.contains("unmatched{quantile=\"0.5\"} "));
+        assert!(rendered.contains("# TYPE overridden histogram\n"));
+        assert!(rendered.contains("overridden_bucket{le=\"5\"} 1\n"));
+
+        let result = PrometheusBuilder::new()
+            .set_default_distribution(DistributionKind::Histogram(Vec::new()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    pub fn test_concurrent_record_and_render() {
+        const THREADS: usize = 4;
+        const SAMPLES: usize = 10_000;
+
+        let recorder = std::sync::Arc::new(
+            PrometheusBuilder::new().set_buckets(&[0.5, 1.0]).unwrap().build_recorder(),
+        );
+        let handle = recorder.handle();
+
+        let writers = (0..THREADS)
+            .map(|i| {
+                let recorder = recorder.clone();
+                std::thread::spawn(move || {
+                    let key = Key::from_parts("latency", vec![Label::new("thread", i.to_string())]);
+                    let histogram = recorder.register_histogram(&key, &METADATA);
+                    for _ in 0..SAMPLES {
+                        histogram.record(1.0);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Scrape continuously while the writers are recording, which should never deadlock.
+        let mut scrapes = 0;
+        while !writers.iter().all(std::thread::JoinHandle::is_finished) {
+            let _ = handle.render();
+            scrapes += 1;
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert!(scrapes > 0);
+
+        let snapshot = handle.snapshot();
+        let by_labels = &snapshot.distributions["latency"];
+        assert_eq!(by_labels.len(), THREADS);
+        for distribution in by_labels.values() {
+            let crate::Distribution::Histogram(histogram) = distribution else {
+                panic!("expected histogram distribution");
+            };
+            assert!(histogram.count() > 0);
+            assert!(histogram.count() <= SAMPLES as u64);
+        }
+    }
+
+    #[test]
+    fn test_render_with_custom_encoder() {
+        struct CounterNames;
+
+        impl crate::Encoder for CounterNames {
+            fn encode(
+                &self,
+                snapshot: &crate::Snapshot,
+                out: &mut dyn std::fmt::Write,
+            ) -> std::fmt::Result {
+                for name in snapshot.counters.keys() {
+                    writeln!(out, "{name}")?;
+                }
+                Ok(())
+            }
+        }
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+        recorder.register_counter(&Key::from_name("errors"), &METADATA).increment(1);
+        recorder.register_gauge(&Key::from_name("connections"), &METADATA).set(1.0);
+
+        assert_eq!(handle.render_with(&CounterNames), "errors\nrequests\n");
+
+        // The built-in encoder produces the same output as regular rendering.
+        let encoder = crate::PrometheusTextEncoder::new();
+        assert_eq!(handle.render_with(&encoder), handle.render());
+    }
+
+    #[test]
+    fn test_render_summary_edge_quantiles_and_nan() {
+        let recorder =
+            PrometheusBuilder::new().set_quantiles(&[0.0, 1.0]).unwrap().build_recorder();
+
+        let key = Key::from_name("latency");
+        let histogram = recorder.register_histogram(&key, &METADATA);
+        histogram.record(2.0);
+        histogram.record(4.0);
+
+        let key = Key::from_name("broken");
+        let histogram = recorder.register_histogram(&key, &METADATA);
+        histogram.record(f64::NAN);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("latency{quantile=\"0\"} 2\n"), "{}", rendered);
+        assert!(rendered.contains("latency{quantile=\"1\"} 4\n"), "{}", rendered);
+        assert!(rendered.contains("broken_sum NaN\n"), "{}", rendered);
+        assert!(rendered.contains("broken_count 1\n"), "{}", rendered);
+        assert!(!rendered.contains("quantile=\"1.0\""), "{}", rendered);
+        // A sketch holding only NaN has no usable minimum or maximum.
+        assert!(rendered.contains("broken{quantile=\"0\"} +Inf\n"), "{}", rendered);
+        assert!(rendered.contains("broken{quantile=\"1\"} -Inf\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_label_order_is_canonical() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+
+        let key = Key::from_parts("requests", vec![Label::new("a", "1"), Label::new("b", "2")]);
+        recorder.register_counter(&key, &METADATA).increment(1);
+        let key = Key::from_parts("requests", vec![Label::new("b", "2"), Label::new("a", "1")]);
+        recorder.register_counter(&key, &METADATA).increment(2);
+
+        let rendered = recorder.handle().render();
+        let expected = "# TYPE requests counter\nrequests{a=\"1\",b=\"2\"} 3\n\n";
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_render_negotiated() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+
+        let (body, content_type) = handle.render_negotiated("*/*");
+        assert_eq!(content_type, crate::PROMETHEUS_CONTENT_TYPE);
+        assert_eq!(body, "# TYPE requests counter\nrequests 1\n\n");
+
+        let accept = "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5";
+        let (body, content_type) = handle.render_negotiated(accept);
+        assert_eq!(content_type, crate::OPENMETRICS_CONTENT_TYPE);
+        assert_eq!(body, "# TYPE requests counter\nrequests_total 1\n# EOF\n");
+
+        let (_, content_type) = handle.render_negotiated("application/openmetrics-text;q=0");
+        assert_eq!(content_type, crate::PROMETHEUS_CONTENT_TYPE);
+    }
+
+    #[test]
+    fn test_compact_output() {
+        fn render(compact: bool) -> String {
+            let recorder = PrometheusBuilder::new().compact_output(compact).build_recorder();
+            recorder.describe_counter(KeyName::from("requests"), None, "Number of requests".into());
+            recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+            recorder.register_gauge(&Key::from_name("connections"), &METADATA).set(2.0);
+            recorder.handle().render()
+        }
+
+        let expected = concat!(
+            "# HELP requests Number of requests\n",
+            "# TYPE requests counter\n",
+            "requests 1\n",
+            "\n",
+            "# TYPE connections gauge\n",
+            "connections 2\n",
+            "\n",
+        );
+        assert_eq!(render(false), expected);
+
+        let expected = concat!(
+            "# HELP requests Number of requests\n",
+            "# TYPE requests counter\n",
+            "requests 1\n",
+            "# TYPE connections gauge\n",
+            "connections 2\n",
+        );
+        assert_eq!(render(true), expected);
+    }
+
+    #[test]
+    fn test_kind_collision_policy() {
+        fn render(policy: KindCollisionPolicy) -> String {
+            let recorder = PrometheusBuilder::new().kind_collision_policy(policy).build_recorder();
+            recorder.register_counter(&Key::from_name("foo"), &METADATA).increment(1);
+            recorder.register_gauge(&Key::from_name("foo"), &METADATA).set(2.0);
+            recorder.handle().render()
+        }
+
+        let expected = "# TYPE foo counter\nfoo 1\n\n# TYPE foo gauge\nfoo 2\n\n";
+        assert_eq!(render(KindCollisionPolicy::Warn), expected);
+
+        let expected = "# TYPE foo counter\nfoo 1\n\n";
+        assert_eq!(render(KindCollisionPolicy::Drop), expected);
+
+        let expected = "# TYPE foo counter\nfoo 1\n\n# TYPE foo_gauge gauge\nfoo_gauge 2\n\n";
+        assert_eq!(render(KindCollisionPolicy::Rename), expected);
+    }
+
+    #[test]
+    fn test_monotonic_counters() {
+        fn render_after_reset(monotonic: bool) -> Vec<String> {
+            let (clock, mock) = Clock::mock();
+            let recorder = PrometheusBuilder::new()
+                .idle_timeout(MetricKindMask::COUNTER, Some(Duration::from_secs(10)))
+                .monotonic_counters(monotonic)
+                .build_with_clock(clock);
+            let handle = recorder.handle();
+            let key = Key::from_name("requests");
+
+            let mut rendered = Vec::new();
+            recorder.register_counter(&key, &METADATA).increment(10);
+            rendered.push(handle.render());
+
+            // Once idle, the counter is removed, and starts over from zero when registered again.
+            mock.increment(Duration::from_secs(11));
+            rendered.push(handle.render());
+            recorder.register_counter(&key, &METADATA).increment(3);
+            rendered.push(handle.render());
+            recorder.register_counter(&key, &METADATA).increment(9);
+            rendered.push(handle.render());
+            rendered
+        }
+
+        let expected = [
+            "# TYPE requests counter\nrequests 10\n\n",
+            "",
+            "# TYPE requests counter\nrequests 10\n\n",
+            "# TYPE requests counter\nrequests 12\n\n",
+        ];
+        assert_eq!(render_after_reset(true), expected);
+
+        let expected = [
+            "# TYPE requests counter\nrequests 10\n\n",
+            "",
+            "# TYPE requests counter\nrequests 3\n\n",
+            "# TYPE requests counter\nrequests 12\n\n",
+        ];
+        assert_eq!(render_after_reset(false), expected);
+    }
+
+    #[test]
+    fn test_monotonic_counters_retention() {
+        let (clock, mock) = Clock::mock();
+        let recorder = PrometheusBuilder::new()
+            .idle_timeout(MetricKindMask::COUNTER, Some(Duration::from_secs(10)))
+            .monotonic_counters(true)
+            .build_with_clock(clock);
+        let handle = recorder.handle();
+        let key = Key::from_name("requests");
+
+        recorder.register_counter(&key, &METADATA).increment(10);
+        assert!(handle.render().contains("requests 10\n"));
+
+        // Once removed for longer than the idle timeout, the counter's previous value is forgotten.
+        mock.increment(Duration::from_secs(11));
+        assert_eq!(handle.render(), "");
+        mock.increment(Duration::from_secs(11));
+        assert_eq!(handle.render(), "");
+        recorder.register_counter(&key, &METADATA).increment(3);
+        assert!(handle.render().contains("requests 3\n"));
+
+        // Beyond the cardinality limit, the values of the counters removed the longest are forgotten.
+        let (clock, mock) = Clock::mock();
+        let recorder = PrometheusBuilder::new()
+            .idle_timeout(MetricKindMask::COUNTER, Some(Duration::from_secs(10)))
+            .monotonic_counters(true)
+            .with_cardinality_limit(2)
+            .build_with_clock(clock);
+        let handle = recorder.handle();
+        recorder.register_counter(&Key::from_name("a"), &METADATA).increment(10);
+        handle.render();
+        mock.increment(Duration::from_secs(5));
+        recorder.register_counter(&Key::from_name("b"), &METADATA).increment(10);
+        handle.render();
+        mock.increment(Duration::from_secs(6));
+        assert!(!handle.render().contains("a 10\n"));
+        mock.increment(Duration::from_secs(5));
+        assert_eq!(handle.render(), "");
+        recorder.register_counter(&Key::from_name("c"), &METADATA).increment(1);
+        handle.render();
+        recorder.register_counter(&Key::from_name("a"), &METADATA).increment(3);
+        let rendered = handle.render();
+        assert!(rendered.contains("a 3\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_render_empty_histogram() {
+        let recorder = PrometheusBuilder::new().set_buckets(&[0.5, 1.0]).unwrap().build_recorder();
+        let _histogram = recorder.register_histogram(&Key::from_name("latency"), &METADATA);
+
+        let expected = concat!(
+            "# TYPE latency histogram\n",
+            "latency_bucket{le=\"0.5\"} 0\n",
+            "latency_bucket{le=\"1\"} 0\n",
+            "latency_bucket{le=\"+Inf\"} 0\n",
+            "latency_sum 0\n",
+            "latency_count 0\n",
+            "\n",
+        );
+        assert_eq!(recorder.handle().render(), expected);
+    }
+
+    #[test]
+    fn test_name_sanitizer() {
+        let recorder = PrometheusBuilder::new()
+            .name_sanitizer(|name| name.replace(':', ""))
+            .set_buckets_for_metric(Matcher::Full("http:latency".into()), &[1.0])
+            .unwrap()
+            .build_recorder();
+
+        let key = Key::from_parts("http:requests", vec![Label::new("status:code", "200")]);
+        recorder.register_counter(&key, &METADATA).increment(1);
+        recorder.register_histogram(&Key::from_name("http:latency"), &METADATA).record(0.5);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("httprequests{status_code=\"200\"} 1\n"), "{}", rendered);
+        assert!(rendered.contains("httplatency_bucket{le=\"1\"} 1\n"), "{}", rendered);
+        assert!(!rendered.contains(':'), "{}", rendered);
+    }
+
+    #[test]
+    fn test_render_bucket_boundaries() {
+        let recorder =
+            PrometheusBuilder::new().set_buckets(&[0.005, 1.0, 1e6]).unwrap().build_recorder();
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(2.0);
+
+        let expected = concat!(
+            "# TYPE latency histogram\n",
+            "latency_bucket{le=\"0.005\"} 0\n",
+            "latency_bucket{le=\"1\"} 0\n",
+            "latency_bucket{le=\"1000000\"} 1\n",
+            "latency_bucket{le=\"+Inf\"} 1\n",
+            "latency_sum 2\n",
+            "latency_count 1\n",
+            "\n",
+        );
+        assert_eq!(recorder.handle().render(), expected);
+    }
+
+    #[test]
+    fn test_render_fractional_samples() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Full("latency_histogram".into()), &[0.001, 0.005])
+            .unwrap()
+            .build_recorder();
+        let handle = recorder.handle();
+
+        let summary = recorder.register_histogram(&Key::from_name("latency_summary"), &METADATA);
+        let histogram =
+            recorder.register_histogram(&Key::from_name("latency_histogram"), &METADATA);
+        for value in [0.0023, 0.0025] {
+            summary.record(value);
+            histogram.record(value);
+        }
+
+        let rendered = handle.render();
+        assert!(rendered.contains("latency_histogram_bucket{le=\"0.001\"} 0\n"), "{}", rendered);
+        assert!(rendered.contains("latency_histogram_bucket{le=\"0.005\"} 2\n"), "{}", rendered);
+        assert!(rendered.contains("latency_histogram_count 2\n"), "{}", rendered);
+
+        let snapshot = handle.snapshot();
+        let crate::Distribution::Summary(summary, _, sum) =
+            &snapshot.distributions["latency_summary"][&Vec::<String>::new()]
+        else {
+            panic!("expected summary distribution");
+        };
+        let quantile = summary.snapshot(quanta::Instant::now()).quantile(0.5).unwrap();
+        assert!((0.0022..0.0026).contains(&quantile), "{}", quantile);
+        assert!((sum - 0.0048).abs() < 1e-12, "{}", sum);
+    }
+
+    #[test]
+    fn test_invalid_buckets() {
+        let err = PrometheusBuilder::new().set_buckets(&[]).unwrap_err();
+        assert_eq!(err.to_string(), "bucket bounds/quantiles cannot be empty");
+
+        let err = PrometheusBuilder::new().set_buckets(&[1.0, f64::NAN, 2.0]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bucket bounds must not be NaN, nor given more than once: [1.0, NaN, 2.0]"
+        );
+
+        let err = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Full("latency".into()), &[f64::NAN])
+            .unwrap_err();
+        assert!(matches!(err, crate::BuildError::InvalidBuckets(_)), "{}", err);
+
+        // Unordered and duplicate buckets are sorted and deduplicated instead.
+        let builder = PrometheusBuilder::new()
+            .set_buckets(&[1.0, 0.5, 2.0, 1.0])
+            .unwrap()
+            .set_buckets_for_metric(Matcher::Full("latency".into()), &[2.0, 1.0, 2.0])
+            .unwrap();
+        assert_eq!(builder.buckets, Some(vec![0.5, 1.0, 2.0]));
+        assert_eq!(
+            builder.bucket_overrides.unwrap()[&Matcher::Full("latency".into())],
+            vec![1.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn test_unordered_buckets_rendered_ascending() {
+        let recorder =
+            PrometheusBuilder::new().set_buckets(&[1.0, 0.5, 2.0]).unwrap().build_recorder();
+        let histogram = recorder.register_histogram(&Key::from_name("latency"), &METADATA);
+        histogram.record(0.7);
+
+        let rendered = recorder.handle().render();
+        let buckets =
+            rendered.lines().filter(|line| line.starts_with("latency_bucket")).collect::<Vec<_>>();
+        assert_eq!(
+            buckets,
+            [
+                "latency_bucket{le=\"0.5\"} 0",
+                "latency_bucket{le=\"1\"} 1",
+                "latency_bucket{le=\"2\"} 1",
+                "latency_bucket{le=\"+Inf\"} 1",
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn test_log_on_anomaly() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Output(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Output {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber =
+            tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let recorder = PrometheusBuilder::new().log_on_anomaly(true).build_recorder();
+            recorder.register_gauge(&Key::from_name("temperature"), &METADATA).set(f64::NAN);
+            recorder.register_gauge(&Key::from_name("humidity"), &METADATA).set(0.5);
+            let _ = recorder.handle().render();
+        });
+
+        let logged = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert!(logged.contains("Gauge has a value of NaN."), "{}", logged);
+        assert!(logged.contains("name=\"temperature\""), "{}", logged);
+        assert!(!logged.contains("humidity"), "{}", logged);
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn test_kind_collision_logged_once() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Output(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Output {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber =
+            tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let recorder = PrometheusBuilder::new().build_recorder();
+            recorder.register_counter(&Key::from_name("foo"), &METADATA).increment(1);
+            for id in ["1", "1", "2"] {
+                let key = Key::from_parts("foo", vec![Label::new("id", id)]);
+                recorder.register_gauge(&key, &METADATA).set(2.0);
+            }
+        });
+
+        let logged = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(logged.matches("more than one kind of metric").count(), 1, "{logged}");
+    }
+
+    #[test]
+    fn test_render_family() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        recorder.describe_gauge(KeyName::from("api.connections"), None, "Open connections".into());
+        recorder.register_counter(&Key::from_name("api.requests"), &METADATA).increment(1);
+        let key = Key::from_parts("api.connections", vec![Label::new("pool", "main")]);
+        recorder.register_gauge(&key, &METADATA).set(3.0);
+        recorder.register_gauge(&Key::from_name("api.connections_max"), &METADATA).set(10.0);
+
+        let expected = concat!(
+            "# HELP api_connections Open connections\n",
+            "# TYPE api_connections gauge\n",
+            "api_connections{pool=\"main\"} 3\n",
+            "\n",
+        );
+        assert_eq!(handle.render_family("api.connections").as_deref(), Some(expected));
+        assert_eq!(handle.render_family("api_connections").as_deref(), Some(expected));
+        assert_eq!(handle.render_family("api.unknown"), None);
+    }
+
+    #[test]
+    fn test_render_with_labels() {
+        let recorder = PrometheusBuilder::new()
+            .add_global_label("zone", "a")
+            .set_buckets(&[1.0])
+            .unwrap()
+            .build_recorder();
+        let handle = recorder.handle();
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+        let key = Key::from_parts("connections", vec![Label::new("role", "primary")]);
+        recorder.register_gauge(&key, &METADATA).set(2.0);
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(0.5);
+
+        let expected = concat!(
+            "# TYPE requests counter\n",
+            "requests{role=\"leader\",zone=\"a\"} 1\n",
+            "\n",
+            "# TYPE connections gauge\n",
+            "connections{role=\"primary\",zone=\"a\"} 2\n",
+            "\n",
+            "# TYPE latency histogram\n",
+            "latency_bucket{role=\"leader\",zone=\"a\",le=\"1\"} 1\n",
+            "latency_bucket{role=\"leader\",zone=\"a\",le=\"+Inf\"} 1\n",
+            "latency_sum{role=\"leader\",zone=\"a\"} 0.5\n",
+            "latency_count{role=\"leader\",zone=\"a\"} 1\n",
+            "\n",
+        );
+        assert_eq!(handle.render_with_labels(&[("role", "leader"), ("zone", "b")]), expected);
+
+        // The labels aren't persisted past the render they were given to.
+        assert!(!handle.render().contains("leader"));
+    }
+
+    #[test]
+    fn test_label_key_injection() {
+        let recorder =
+            PrometheusBuilder::new().add_global_label("global\"}{", "a").build_recorder();
+        let handle = recorder.handle();
+        let labels = vec![Label::new("evil\"}{", "x\"} 1\nfake 2")];
+        recorder.register_counter(&Key::from_parts("requests", labels), &METADATA).increment(1);
+
+        let expected = concat!(
+            "# TYPE requests counter\n",
+            "requests{evil___=\"x\\\"} 1\\nfake 2\",global___=\"a\"} 1\n",
+            "\n",
+        );
+        assert_eq!(handle.render(), expected);
+        assert_eq!(handle.render_with_labels(&[("extra\"}", "b")]).lines().count(), 3);
+    }
+
+    #[test]
+    fn test_self_metrics() {
+        let recorder = PrometheusBuilder::new().with_self_metrics(true).build_recorder();
+        let handle = recorder.handle();
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+
+        let first = handle.render();
+        assert!(!first.contains("prometheus_render"), "{}", first);
+
+        let snapshot = handle.snapshot();
+        let duration = snapshot.gauges["prometheus_render_duration_seconds"][&Vec::<String>::new()];
+        let size = snapshot.gauges["prometheus_render_size_bytes"][&Vec::<String>::new()];
+        assert!(duration > 0.0 && duration < 10.0, "{}", duration);
+        #[allow(clippy::cast_precision_loss)]
+        let expected_size = first.len() as f64;
+        assert!((size - expected_size).abs() < f64::EPSILON, "{}", size);
+
+        let second = handle.render();
+        assert!(second.contains("# TYPE prometheus_render_size_bytes gauge\n"), "{}", second);
+        assert!(second.contains("# TYPE prometheus_render_duration_seconds gauge\n"), "{}", second);
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _ = recorder.handle().render();
+        assert!(!recorder.handle().render().contains("prometheus_render"));
+    }
+
+    #[test]
+    fn test_quantile_validation() {
+        let recorder =
+            PrometheusBuilder::new().set_quantiles(&[0.5, 0.9, 0.99]).unwrap().build_recorder();
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(1.0);
+        let rendered = recorder.handle().render();
+        for quantile in ["0.5", "0.9", "0.99"] {
+            let line = format!("latency{{quantile=\"{quantile}\"}} ");
+            assert!(rendered.contains(&line), "{}", rendered);
+        }
+
+        let err = PrometheusBuilder::new().set_quantiles(&[1.5]).unwrap_err();
+        assert_eq!(err.to_string(), "quantiles must be between 0.0 and 1.0, inclusive: 1.5");
+
+        let err = PrometheusBuilder::new()
+            .set_quantiles_for_metric(Matcher::Full("latency".into()), &[0.5, -0.1])
+            .unwrap_err();
+        assert!(
+            matches!(err, crate::BuildError::InvalidQuantile(q) if q.is_sign_negative()),
+            "{}",
+            err
+        );
+
+        let err = PrometheusBuilder::new().set_quantiles(&[f64::NAN]).unwrap_err();
+        assert!(matches!(err, crate::BuildError::InvalidQuantile(q) if q.is_nan()), "{}", err);
+    }
+
+    #[test]
+    fn test_namespace() {
+        let recorder = PrometheusBuilder::new()
+            .with_namespace("myapp")
+            .counter_total_suffix(true)
+            .set_buckets_for_metric(Matcher::Full("latency".into()), &[1.0])
+            .unwrap()
+            .build_recorder();
+
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+        recorder.register_counter(&Key::from_name("myapp_errors"), &METADATA).increment(2);
+        recorder.register_gauge(&Key::from_name("connections"), &METADATA).set(3.0);
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(0.5);
+
+        let rendered = recorder.handle().render();
+        let expected = concat!(
+            "# TYPE myapp_errors_total counter\n",
+            "myapp_errors_total 2\n\n",
+            "# TYPE myapp_requests_total counter\n",
+            "myapp_requests_total 1\n\n",
+            "# TYPE myapp_connections gauge\n",
+            "myapp_connections 3\n\n",
+            "# TYPE myapp_latency histogram\n",
+            "myapp_latency_bucket{le=\"1\"} 1\n",
+            "myapp_latency_bucket{le=\"+Inf\"} 1\n",
+            "myapp_latency_sum 0.5\n",
+            "myapp_latency_count 1\n\n",
+        );
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_sort_labels() {
+        let labels = vec![Label::new("zone", "a"), Label::new("method", "GET")];
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let key = Key::from_parts("requests", labels.clone());
+        recorder.register_counter(&key, &METADATA).increment(1);
+        let rendered = recorder.handle().render();
+        let expected =
+            concat!("# TYPE requests counter\n", "requests{method=\"GET\",zone=\"a\"} 1\n\n",);
+        assert_eq!(rendered, expected);
+
+        let recorder = PrometheusBuilder::new().sort_labels(false).build_recorder();
+        let key = Key::from_parts("requests", labels);
+        recorder.register_counter(&key, &METADATA).increment(1);
+        let rendered = recorder.handle().render();
+        let expected =
+            concat!("# TYPE requests counter\n", "requests{zone=\"a\",method=\"GET\"} 1\n\n",);
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_time_histogram() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        {
+            let _guard = handle
+                .time_histogram("sleep_milliseconds", &[("kind", "short")])
+                .with_unit(Unit::Milliseconds);
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        let rendered = handle.render();
+        assert!(rendered.contains("sleep_milliseconds_count{kind=\"short\"} 1\n"), "{}", rendered);
+        let sum = rendered
+            .lines()
+            .find_map(|line| line.strip_prefix("sleep_milliseconds_sum{kind=\"short\"} "))
+            .and_then(|sum| sum.parse::<f64>().ok())
+            .unwrap();
+        assert!(sum >= 5.0, "{}", rendered);
+
+        // The elapsed time is read from the clock given to the builder.
+        let (clock, mock) = Clock::mock();
+        let recorder = PrometheusBuilder::new().with_clock(clock).build_recorder();
+        let handle = recorder.handle();
+        {
+            let _guard = handle.time_histogram("job_seconds", &[]);
+            mock.increment(Duration::from_secs(90));
+        }
+        let rendered = handle.render();
+        assert!(rendered.contains("job_seconds_sum 90\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_histogram_exemplars() {
+        let recorder = PrometheusBuilder::new().set_buckets(&[0.1, 1.0]).unwrap().build_recorder();
+        let handle = recorder.handle();
+
+        let key = Key::from_name("latency");
+        handle.record_histogram_with_exemplar(&key, 0.5, &[("trace_id", "abc")]);
+        handle.record_histogram_with_exemplar(&key, 0.05, &[("trace_id", "def")]);
+        handle.record_histogram_with_exemplar(&key, 0.7, &[("trace_id", "ghi")]);
+
+        let (rendered, _) = handle.render_negotiated("application/openmetrics-text");
+        let lines = rendered.lines().collect::<Vec<_>>();
+        assert!(lines[1].starts_with("latency_bucket{le=\"0.1\"} 1 # {trace_id=\"def\"} 0.05 "));
+        assert!(lines[2].starts_with("latency_bucket{le=\"1\"} 3 # {trace_id=\"ghi\"} 0.7 "));
+        assert_eq!(lines[3], "latency_bucket{le=\"+Inf\"} 3");
+
+        // Exemplars are only rendered in the OpenMetrics format.
+        let rendered = handle.render();
+        assert!(rendered.contains("latency_bucket{le=\"1\"} 3\n"), "{}", rendered);
+        assert!(!rendered.contains("trace_id"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_aggregate_histograms() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets(&[1.0, 5.0])
+            .unwrap()
+            .aggregate_histograms(true)
+            .build_recorder();
+
+        let key = Key::from_parts("latency", vec![Label::new("route", "a")]);
+        recorder.register_histogram(&key, &METADATA).record(0.5);
+        let key = Key::from_parts("latency", vec![Label::new("route", "b")]);
+        let histogram = recorder.register_histogram(&key, &METADATA);
+        histogram.record(3.0);
+        histogram.record(10.0);
+
+        let rendered = recorder.handle().render();
+        let expected = concat!(
+            "# TYPE latency histogram\n",
+            "latency_bucket{le=\"1\"} 1\n",
+            "latency_bucket{le=\"5\"} 2\n",
+            "latency_bucket{le=\"+Inf\"} 3\n",
+            "latency_sum 13.5\n",
+            "latency_count 3\n",
+            "latency_bucket{route=\"a\",le=\"1\"} 1\n",
+            "latency_bucket{route=\"a\",le=\"5\"} 1\n",
+            "latency_bucket{route=\"a\",le=\"+Inf\"} 1\n",
+            "latency_sum{route=\"a\"} 0.5\n",
+            "latency_count{route=\"a\"} 1\n",
+            "latency_bucket{route=\"b\",le=\"1\"} 0\n",
+            "latency_bucket{route=\"b\",le=\"5\"} 1\n",
+            "latency_bucket{route=\"b\",le=\"+Inf\"} 2\n",
+            "latency_sum{route=\"b\"} 13\n",
+            "latency_count{route=\"b\"} 2\n\n",
+        );
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_cardinality_limit() {
+        let recorder = PrometheusBuilder::new().with_cardinality_limit(2).build_recorder();
+        let handle = recorder.handle();
+
+        let first = Key::from_parts("requests", vec![Label::new("id", "1")]);
+        let second = Key::from_parts("requests", vec![Label::new("id", "2")]);
+        recorder.register_counter(&first, &METADATA).increment(1);
+        recorder.register_gauge(&second, &METADATA).set(1.0);
+
+        let third = Key::from_parts("requests", vec![Label::new("id", "3")]);
+        recorder.register_counter(&third, &METADATA).increment(1);
+        recorder.register_histogram(&third, &METADATA).record(1.0);
+
+        // Existing series still update once the limit is reached.
+        recorder.register_counter(&first, &METADATA).increment(1);
+
+        let rendered = handle.render();
+        assert!(rendered.contains("requests{id=\"1\"} 2\n"), "{}", rendered);
+        assert!(!rendered.contains("id=\"3\""), "{}", rendered);
+        assert!(rendered.contains("prometheus_dropped_series_total 2\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_cardinality_limit_concurrent() {
+        let recorder = PrometheusBuilder::new().with_cardinality_limit(10).build_recorder();
+        let handle = recorder.handle();
+
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let recorder = &recorder;
+                scope.spawn(move || {
+                    for id in 0..50 {
+                        let id = format!("{thread}-{id}");
+                        let key = Key::from_parts("requests", vec![Label::new("id", id)]);
+                        recorder.register_counter(&key, &METADATA).increment(1);
+                    }
+                });
+            }
+        });
+
+        // Only the dropped series counter itself goes beyond the limit.
+        assert_eq!(handle.metric_count(), 11);
+        let rendered = handle.render();
+        assert_eq!(rendered.matches("requests{").count(), 10, "{rendered}");
+        assert!(rendered.contains("prometheus_dropped_series_total 390\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_cardinality_limit_released() {
+        let (clock, mock) = Clock::mock();
+        let recorder = PrometheusBuilder::new()
+            .idle_timeout(MetricKindMask::GAUGE, Some(Duration::from_secs(10)))
+            .with_cardinality_limit(2)
+            .build_with_clock(clock);
+        let handle = recorder.handle();
+
+        let first = Key::from_parts("connections", vec![Label::new("id", "1")]);
+        let second = Key::from_parts("connections", vec![Label::new("id", "2")]);
+        recorder.register_gauge(&first, &METADATA).set(1.0);
+        recorder.register_gauge(&second, &METADATA).set(1.0);
+        assert_eq!(handle.metric_count(), 2);
+
+        // Series removed by the idle timeout, or explicitly, make room for new ones.
+        let _ = handle.render();
+        mock.increment(Duration::from_secs(11));
+        let _ = handle.render();
+        assert_eq!(handle.metric_count(), 0);
+
+        recorder.register_gauge(&first, &METADATA).set(1.0);
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+        assert_eq!(handle.metric_count(), 2);
+        handle.remove_metric("requests");
+        assert_eq!(handle.metric_count(), 1);
+        recorder.register_gauge(&second, &METADATA).set(2.0);
+        assert!(handle.render().contains("connections{id=\"2\"} 2\n"));
+
+        handle.clear();
+        assert_eq!(handle.metric_count(), 0);
+    }
+
+    #[test]
+    fn test_render_trimmed() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        assert_eq!(handle.render_trimmed(true), "");
+
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+        recorder.register_gauge(&Key::from_name("connections"), &METADATA).set(2.0);
+        assert!(handle.render().ends_with("connections 2\n\n"));
+
+        let expected = concat!(
+            "# TYPE requests counter\n",
+            "requests 1\n\n",
+            "# TYPE connections gauge\n",
+            "connections 2",
+        );
+        assert_eq!(handle.render_trimmed(false), expected);
+        assert_eq!(handle.render_trimmed(true), format!("{expected}\n"));
+    }
+
+    #[test]
+    fn test_scrape_tracking() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        assert_eq!(handle.scrape_count(), 0);
+        assert_eq!(handle.last_scrape(), None);
+
+        handle.render();
+        let first = handle.last_scrape().unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        handle.render();
+        let second = handle.last_scrape().unwrap();
+
+        assert_eq!(handle.scrape_count(), 2);
+        assert!(second > first);
+    }
+
+    #[test]
+    fn test_sanitize_replacement() {
+        let recorder = PrometheusBuilder::new().sanitize_replacement('X').unwrap().build_recorder();
+        let key = Key::from_parts("http.requests", vec![Label::new("user.id", "1")]);
+        recorder.register_counter(&key, &METADATA).increment(1);
+
+        let rendered = recorder.handle().render();
+        let expected =
+            concat!("# TYPE httpXrequests counter\n", "httpXrequests{userXid=\"1\"} 1\n\n",);
+        assert_eq!(rendered, expected);
+
+        let err = PrometheusBuilder::new().sanitize_replacement('-').unwrap_err();
+        assert!(matches!(err, crate::BuildError::InvalidSanitizeReplacement('-')), "{}", err);
+        assert!(PrometheusBuilder::new().sanitize_replacement('1').is_err());
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_render_bytes() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let key = Key::from_parts("basic_counter", vec![Label::new("wutang", "forever")]);
+        recorder.register_counter(&key, &METADATA).increment(42);
+        recorder.register_gauge(&Key::from_name("basic_gauge"), &METADATA).set(-3.14);
+
+        let handle = recorder.handle();
+        assert_eq!(handle.render_bytes(), handle.render().as_bytes());
+    }
+
+    #[test]
+    fn test_emit_metadata() {
+        let recorder = PrometheusBuilder::new().emit_metadata(false).build_recorder();
+        recorder.describe_counter("requests".into(), None, "Total requests.".into());
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+        recorder.register_gauge(&Key::from_name("connections"), &METADATA).set(2.0);
+
+        let rendered = recorder.handle().render();
+        assert_eq!(rendered, "requests 1\n\nconnections 2\n\n");
+    }
+
+    #[test]
+    fn test_drop_empty_labels() {
+        let recorder = PrometheusBuilder::new().drop_empty_labels(true).build_recorder();
+
+        let key =
+            Key::from_parts("requests", vec![Label::new("env", ""), Label::new("method", "GET")]);
+        recorder.register_counter(&key, &METADATA).increment(1);
+        let key = Key::from_parts("requests", vec![Label::new("method", "GET")]);
+        recorder.register_counter(&key, &METADATA).increment(2);
+
+        let rendered = recorder.handle().render();
+        let expected = concat!("# TYPE requests counter\n", "requests{method=\"GET\"} 3\n\n");
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_every_metric_kind_is_rendered() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let kinds = [MetricKind::Counter, MetricKind::Gauge, MetricKind::Histogram];
+        for kind in kinds {
+            // This match is exhaustive on purpose: a new kind of metric must be registered, and
+            // rendered, here too, rather than silently vanishing from the output.
+            let (name, metric_type) = match kind {
+                MetricKind::Counter => {
+                    recorder.register_counter(&Key::from_name("a_counter"), &METADATA).increment(1);
+                    ("a_counter", "counter")
+                }
+                MetricKind::Gauge => {
+                    recorder.register_gauge(&Key::from_name("a_gauge"), &METADATA).set(1.0);
+                    ("a_gauge", "gauge")
+                }
+                MetricKind::Histogram => {
+                    recorder
+                        .register_histogram(&Key::from_name("a_histogram"), &METADATA)
+                        .record(1.0);
+                    ("a_histogram", "summary")
+                }
+            };
+
+            let rendered = recorder.handle().render();
+            let line = format!("# TYPE {name} {metric_type}\n");
+            assert!(rendered.contains(&line), "{}", rendered);
+        }
+    }
+
+    #[test]
+    fn test_with_clock() {
+        let (clock, mock) = Clock::mock();
+        let recorder = PrometheusBuilder::new()
+            .idle_timeout(MetricKindMask::COUNTER, Some(Duration::from_secs(10)))
+            .with_clock(clock)
+            .build_recorder();
+        let handle = recorder.handle();
+
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+        assert!(handle.render().contains("requests 1\n"));
+        let first = handle.last_scrape().unwrap();
+
+        mock.increment(Duration::from_secs(11));
+        assert!(!handle.render().contains("requests"));
+        assert_eq!(handle.last_scrape().unwrap().duration_since(first), Duration::from_secs(11));
+    }
+
+    #[test]
+    fn test_sanitization_collisions_are_merged() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        recorder.register_counter(&Key::from_name("foo.bar"), &METADATA).increment(1);
+        recorder.register_counter(&Key::from_name("foo-bar"), &METADATA).increment(2);
+        recorder.register_gauge(&Key::from_name("temp.c"), &METADATA).set(1.0);
+        recorder.register_gauge(&Key::from_name("temp-c"), &METADATA).set(2.0);
+
+        // `temp.c` sorts after `temp-c`, so its value wins.
+        let rendered = recorder.handle().render();
+        let expected = concat!(
+            "# TYPE foo_bar counter\n",
+            "foo_bar 3\n\n",
+            "# TYPE temp_c gauge\n",
+            "temp_c 1\n\n",
+        );
+        assert_eq!(rendered, expected);
+    }
+
+    #[test]
+    fn test_summary_relative_accuracy() {
+        let median = |accuracy: f64| {
+            let recorder = PrometheusBuilder::new()
+                .set_quantiles(&[0.5])
+                .unwrap()
+                .set_summary_relative_accuracy(accuracy)
+                .unwrap()
+                .build_recorder();
+            let histogram = recorder.register_histogram(&Key::from_name("latency"), &METADATA);
+            // Stay within a single block of the histogram's sample bucket, so that samples are
+            // drained in the order they were recorded.
+            for value in 1..=31 {
+                histogram.record(f64::from(value));
+            }
+
+            let rendered = recorder.handle().render();
+            rendered
+                .lines()
+                .find_map(|line| line.strip_prefix("latency{quantile=\"0.5\"} "))
+                .and_then(|value| value.parse::<f64>().ok())
+                .unwrap()
+        };
+
+        let (tight, loose) = (median(0.0001), median(0.25));
+        assert!((tight - 16.0).abs() < (loose - 16.0).abs(), "{} vs {}", tight, loose);
+        assert!((tight - 16.0).abs() <= 16.0 * 0.0001, "{}", tight);
+
+        for accuracy in [0.0, 1.0, -0.5, f64::NAN] {
+            let err = PrometheusBuilder::new().set_summary_relative_accuracy(accuracy).unwrap_err();
+            assert!(matches!(err, crate::BuildError::InvalidRelativeAccuracy(_)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_descriptions_and_units_catalog() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        recorder.describe_counter(
+            KeyName::from("requests"),
+            Some(Unit::Count),
+            "Number of requests".into(),
+        );
+        recorder.describe_histogram(
+            KeyName::from("latency.seconds"),
+            Some(Unit::Seconds),
+            "Request latency".into(),
+        );
+        recorder.describe_gauge(KeyName::from("in_flight"), None, "Requests in flight".into());
+
+        let handle = recorder.handle();
+        assert_eq!(
+            handle.descriptions(),
+            vec![
+                ("in_flight".to_owned(), "Requests in flight".to_owned()),
+                ("latency_seconds".to_owned(), "Request latency".to_owned()),
+                ("requests".to_owned(), "Number of requests".to_owned()),
+            ]
+        );
+        assert_eq!(
+            handle.units(),
+            vec![
+                ("latency_seconds".to_owned(), Unit::Seconds),
+                ("requests".to_owned(), Unit::Count),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_histogram_buckets() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        handle
+            .set_histogram_buckets(
+                "upstream_latency",
+                &[("region", "eu")],
+                &[(0.1, 3), (0.5, 7), (1.0, 9)],
+                4.25,
+                10,
+            )
+            .unwrap();
+
+        let expected = concat!(
+            "# TYPE upstream_latency histogram\n",
+            "upstream_latency_bucket{region=\"eu\",le=\"0.1\"} 3\n",
+            "upstream_latency_bucket{region=\"eu\",le=\"0.5\"} 7\n",
+            "upstream_latency_bucket{region=\"eu\",le=\"1\"} 9\n",
+            "upstream_latency_bucket{region=\"eu\",le=\"+Inf\"} 10\n",
+            "upstream_latency_sum{region=\"eu\"} 4.25\n",
+            "upstream_latency_count{region=\"eu\"} 10\n",
+            "\n",
+        );
+        assert_eq!(handle.render(), expected);
+
+        // Setting the buckets again replaces the series, and an explicit `+Inf` bucket isn't
+        // rendered twice.
+        handle
+            .set_histogram_buckets(
+                "upstream_latency",
+                &[("region", "eu")],
+                &[(1.0, 1), (f64::INFINITY, 2)],
+                3.0,
+                2,
+            )
+            .unwrap();
+        let expected = concat!(
+            "# TYPE upstream_latency histogram\n",
+            "upstream_latency_bucket{region=\"eu\",le=\"1\"} 1\n",
+            "upstream_latency_bucket{region=\"eu\",le=\"+Inf\"} 2\n",
+            "upstream_latency_sum{region=\"eu\"} 3\n",
+            "upstream_latency_count{region=\"eu\"} 2\n",
+            "\n",
+        );
+        assert_eq!(handle.render(), expected);
+
+        // Invalid buckets are rejected, leaving the series as-is.
+        let set = |buckets: &[(f64, u64)], count| {
+            handle.set_histogram_buckets(
+                "upstream_latency",
+                &[("region", "eu")],
+                buckets,
+                1.0,
+                count,
+            )
+        };
+        for buckets in [&[(f64::NAN, 1)][..], &[(1.0, 1), (1.0, 2)]] {
+            let err = set(buckets, 2).unwrap_err();
+            assert!(matches!(err, crate::BuildError::InvalidBuckets(_)), "{}", err);
+        }
+        for (buckets, count) in [(&[(0.5, 2), (1.0, 1)][..], 2), (&[(1.0, 3)], 2)] {
+            let err = set(buckets, count).unwrap_err();
+            assert!(matches!(err, crate::BuildError::NonCumulativeBuckets(_)), "{}", err);
+        }
+        assert_eq!(handle.render(), expected);
+    }
+
+    #[test]
+    fn test_set_histogram_buckets_cardinality_limit() {
+        let recorder = PrometheusBuilder::new().with_cardinality_limit(1).build_recorder();
+        let handle = recorder.handle();
+        for region in ["eu", "us", "eu"] {
+            handle
+                .set_histogram_buckets("upstream_latency", &[("region", region)], &[], 1.0, 1)
+                .unwrap();
+        }
+
+        let rendered = handle.render();
+        assert!(rendered.contains("upstream_latency_count{region=\"eu\"} 1\n"), "{}", rendered);
+        assert!(!rendered.contains("region=\"us\""), "{}", rendered);
+        assert!(rendered.contains("prometheus_dropped_series_total 1\n"), "{}", rendered);
+        assert_eq!(handle.metric_count(), 2);
+    }
+
+    #[test]
+    fn test_max_label_value_len() {
+        let recorder = PrometheusBuilder::new().max_label_value_len(8).build_recorder();
+        let exact = Key::from_parts("requests", vec![Label::new("path", "/api/v1/")]);
+        recorder.register_counter(&exact, &METADATA).increment(1);
+        let long = Key::from_parts("requests", vec![Label::new("path", "/api/v1/users?page=2")]);
+        recorder.register_counter(&long, &METADATA).increment(2);
+        // The escaped quote would straddle the limit, so it's cut off entirely.
+        let quoted = Key::from_parts("quoted", vec![Label::new("q", "abcdefg\"hij")]);
+        recorder.register_counter(&quoted, &METADATA).increment(3);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("requests{path=\"/api/v1/\"} 1\n"), "{}", rendered);
+        assert!(rendered.contains("requests{path=\"/api/v1/…\"} 2\n"), "{}", rendered);
+        assert!(rendered.contains("quoted{q=\"abcdefg…\"} 3\n"), "{}", rendered);
+
+        let recorder = PrometheusBuilder::new().max_label_value_len(9).build_recorder();
+        recorder.register_counter(&quoted, &METADATA).increment(3);
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("quoted{q=\"abcdefg\\\"…\"} 3\n"), "{}", rendered);
+    }
+
+    #[test]
+    #[cfg(all(feature = "process-metrics", target_os = "linux"))]
+    fn test_process_metrics() {
+        let recorder = PrometheusBuilder::new().with_process_metrics(true).build_recorder();
+        let rendered = recorder.handle().render();
+
+        let value_of = |name: &str| {
+            rendered
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+                .and_then(|value| value.parse::<f64>().ok())
+                .unwrap_or_else(|| panic!("{} missing from {}", name, rendered))
+        };
+        assert!(rendered.contains("# TYPE process_cpu_seconds_total gauge\n"), "{}", rendered);
+        assert!(value_of("process_cpu_seconds_total") >= 0.0);
+        assert!(value_of("process_resident_memory_bytes") > 0.0);
+        assert!(value_of("process_open_fds") > 0.0);
+
+        let recorder = PrometheusBuilder::new().build_recorder();
+        assert!(!recorder.handle().render().contains("process_"));
+    }
+
+    #[test]
+    fn test_keep_registered_families() {
+        let (clock, mock) = Clock::mock();
+        let recorder = PrometheusBuilder::new()
+            .idle_timeout(MetricKindMask::ALL, Some(Duration::from_secs(10)))
+            .keep_registered_families(true)
+            .set_buckets_for_metric(Matcher::Full("latency".into()), &[1.0])
+            .unwrap()
+            .build_with_clock(clock);
+        recorder.describe_counter(KeyName::from("requests"), None, "Number of requests".into());
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+        recorder.register_gauge(&Key::from_name("in_flight"), &METADATA).set(2.0);
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(0.5);
+        recorder.register_histogram(&Key::from_name("size"), &METADATA).record(42.0);
+
+        let handle = recorder.handle();
+        assert!(handle.render().contains("requests 1\n"));
+
+        mock.increment(Duration::from_secs(11));
+        let expected = concat!(
+            "# HELP requests Number of requests\n",
+            "# TYPE requests counter\n\n",
+            "# TYPE in_flight gauge\n\n",
+            "# TYPE latency histogram\n\n",
+            "# TYPE size summary\n\n",
+        );
+        assert_eq!(handle.render(), expected);
+        assert_eq!(handle.render(), expected);
+    }
+
+    #[test]
+    fn test_name_rewriter() {
+        let recorder = PrometheusBuilder::new()
+            .with_namespace("app")
+            .name_rewriter(|name| match name.strip_prefix("api_v") {
+                Some(rest) => match rest.split_once('_') {
+                    Some((_, suffix)) => Cow::Owned(format!("api_{suffix}")),
+                    None => Cow::Borrowed(name),
+                },
+                None => Cow::Borrowed(name),
+            })
+            .build_recorder();
+        recorder.describe_counter(KeyName::from("api_requests"), None, "API requests".into());
+        recorder.register_counter(&Key::from_name("api.v1.requests"), &METADATA).increment(3);
+        recorder.register_counter(&Key::from_name("api_v2_requests"), &METADATA).increment(4);
+        recorder.register_counter(&Key::from_name("other"), &METADATA).increment(1);
+
+        let expected = concat!(
+            "# HELP app_api_requests API requests\n",
+            "# TYPE app_api_requests counter\n",
+            "app_api_requests 7\n\n",
+            "# TYPE app_other counter\n",
+            "app_other 1\n\n",
+        );
+        assert_eq!(recorder.handle().render(), expected);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_render_json() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Full("latency".into()), &[0.1, 1.0])
+            .unwrap()
+            .build_recorder();
+        let key = Key::from_parts("requests", vec![Label::new("path", "/\"quoted\"")]);
+        recorder.register_counter(&key, &METADATA).increment(42);
+        recorder.register_gauge(&Key::from_name("in_flight"), &METADATA).set(f64::NAN);
+        let histogram = recorder.register_histogram(&Key::from_name("latency"), &METADATA);
+        histogram.record(0.05);
+        histogram.record(0.5);
+        histogram.record(5.0);
+
+        let rendered = recorder.handle().render_json();
+        let json = serde_json::from_str::<serde_json::Value>(&rendered).unwrap();
+        assert_eq!(
+            json["counters"]["requests"],
+            serde_json::json!([{ "labels": { "path": "/\"quoted\"" }, "value": 42 }])
+        );
+        assert_eq!(json["gauges"]["in_flight"][0]["value"], serde_json::Value::Null);
+        assert_eq!(
+            json["distributions"]["latency"],
+            serde_json::json!([{
+                "labels": {},
+                "type": "histogram",
+                "buckets": [{ "le": 0.1, "count": 1 }, { "le": 1.0, "count": 2 }],
+                "sum": 5.55,
+                "count": 3,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_registered_gauge_renders_zero() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let _gauge = recorder.register_gauge(&Key::from_name("connections"), &METADATA);
+
+        assert_eq!(recorder.handle().render(), "# TYPE connections gauge\nconnections 0\n\n");
+    }
+
+    #[test]
+    fn test_render_chunks() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Full("latency".into()), &[0.1, 1.0])
+            .unwrap()
+            .build_recorder();
+        recorder.describe_counter(KeyName::from("requests"), None, "Number of requests".into());
+        for path in ["/a", "/b"] {
+            let key = Key::from_parts("requests", vec![Label::new("path", path)]);
+            recorder.register_counter(&key, &METADATA).increment(1);
+        }
+        recorder.register_counter(&Key::from_name("errors"), &METADATA).increment(2);
+        recorder.register_gauge(&Key::from_name("in_flight"), &METADATA).set(3.0);
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(0.5);
+        recorder.register_histogram(&Key::from_name("size"), &METADATA).record(42.0);
+
+        let handle = recorder.handle();
+        let chunks = handle.render_chunks().collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 5);
+        assert_eq!(
+            chunks[1],
+            concat!(
+                "# HELP requests Number of requests\n",
+                "# TYPE requests counter\n",
+                "requests{path=\"/a\"} 1\n",
+                "requests{path=\"/b\"} 1\n\n",
+            )
+        );
+        assert!(chunks.iter().all(|chunk| chunk.starts_with("# ")), "{:?}", chunks);
+        assert_eq!(chunks.concat(), handle.render());
+
+        // Chunked renders report, and consume, the same counter deltas as full renders.
+        let recorder = PrometheusBuilder::new().delta_counters(true).build_recorder();
+        let handle = recorder.handle();
+        let counter = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        counter.increment(5);
+        assert_eq!(
+            handle.render_chunks().collect::<String>(),
+            "# TYPE requests counter\nrequests 5\n\n"
+        );
+        counter.increment(3);
+        assert_eq!(handle.render(), "# TYPE requests counter\nrequests 3\n\n");
+        counter.increment(2);
+        assert_eq!(
+            handle.render_chunks().collect::<String>(),
+            "# TYPE requests counter\nrequests 2\n\n"
+        );
+    }
+
+    #[test]
+    fn test_created_timestamps() {
+        let recorder = PrometheusBuilder::new()
+            .with_created_timestamps(true)
+            .set_quantiles(&[0.5])
+            .unwrap()
+            .build_recorder();
+        let before = std::time::SystemTime::now();
+        let key = Key::from_parts("requests", vec![Label::new("path", "/")]);
+        recorder.register_counter(&key, &METADATA).increment(1);
+        recorder.register_gauge(&Key::from_name("in_flight"), &METADATA).set(1.0);
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(0.5);
+        let after = std::time::SystemTime::now();
+
+        let handle = recorder.handle();
+        let rendered = handle.render_openmetrics();
+        let created = rendered
+            .lines()
+            .filter_map(|line| line.split_once("_created"))
+            .map(|(name, rest)| (name.to_owned(), rest.rsplit(' ').next().unwrap().to_owned()))
+            .collect::<Vec<_>>();
+        let names = created.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["requests", "latency"], "{rendered}");
+        assert!(rendered.contains("requests_created{path=\"/\"} "), "{}", rendered);
+
+        let epoch_seconds =
+            |time: std::time::SystemTime| time.duration_since(std::time::UNIX_EPOCH).unwrap();
+        let (earliest, latest) = (epoch_seconds(before).as_secs(), epoch_seconds(after).as_secs());
+        for (name, timestamp) in &created {
+            let (seconds, millis) = timestamp.split_once('.').unwrap();
+            assert_eq!(millis.len(), 3, "unexpected timestamp for {name}: {timestamp}");
+            let seconds = seconds.parse::<u64>().unwrap();
+            assert!((earliest..=latest).contains(&seconds), "{} created at {}", name, timestamp);
+        }
+
+        // Registering the series again keeps its original creation time.
+        recorder.register_counter(&key, &METADATA).increment(1);
+        assert_eq!(handle.render_openmetrics().matches("requests_created").count(), 1);
+        assert!(handle
+            .render_openmetrics()
+            .contains(&format!("requests_created{{path=\"/\"}} {}", created[0].1)));
+
+        // The Prometheus exposition format has no notion of creation times.
+        assert!(!handle.render().contains("_created"));
+    }
+
+    #[cfg(feature = "http-listener")]
+    #[test]
+    fn test_conflicting_distribution_config() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let _guard = runtime.enter();
+
+        let result = PrometheusBuilder::new()
+            .with_http_listener(([127, 0, 0, 1], 0))
+            .set_buckets_for_metric(Matcher::Prefix("http_".into()), &[0.1, 1.0])
+            .unwrap()
+            .set_quantiles_for_metric(Matcher::Prefix("http_".into()), &[0.5, 0.99])
+            .unwrap()
+            .build();
+        match result {
+            Err(crate::BuildError::ConflictingDistributionConfig { matcher }) => {
+                assert_eq!(matcher, Matcher::Prefix("http_".into()));
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("conflicting distribution config should not build"),
+        }
+    }
+
+    #[cfg(feature = "http-listener")]
+    #[test]
+    fn test_distribution_config_for_distinct_matchers() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let _guard = runtime.enter();
+
+        let (recorder, _exporter) = PrometheusBuilder::new()
+            .with_http_listener(([127, 0, 0, 1], 0))
+            .set_buckets_for_metric(Matcher::Prefix("http_".into()), &[0.1, 1.0])
+            .unwrap()
+            .set_quantiles_for_metric(Matcher::Prefix("db_".into()), &[0.5])
+            .unwrap()
+            .build()
+            .unwrap();
+        recorder.register_histogram(&Key::from_name("http_latency"), &METADATA).record(0.5);
+        recorder.register_histogram(&Key::from_name("db_latency"), &METADATA).record(0.5);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("# TYPE http_latency histogram\n"), "{}", rendered);
+        assert!(rendered.contains("# TYPE db_latency summary\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_remove_metric() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        recorder.describe_counter(KeyName::from("requests"), None, "Number of requests".into());
+        for path in ["/a", "/b"] {
+            let key = Key::from_parts("requests", vec![Label::new("path", path)]);
+            recorder.register_counter(&key, &METADATA).increment(1);
+        }
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(0.5);
+        recorder.register_gauge(&Key::from_name("in_flight"), &METADATA).set(3.0);
+
+        let handle = recorder.handle();
+        handle.remove_metric("requests");
+        handle.remove_metric("latency");
+        assert_eq!(handle.render(), "# TYPE in_flight gauge\nin_flight 3\n\n");
+        assert!(handle.descriptions().is_empty());
+
+        // The metric is exported again once registered again.
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(2);
+        assert!(handle.render().contains("requests 2\n"));
+    }
+
+    #[test]
+    fn test_delta_counters() {
+        let recorder = PrometheusBuilder::new().delta_counters(true).build_recorder();
+        let requests = recorder.register_counter(&Key::from_name("requests"), &METADATA);
+        let errors = recorder.register_counter(&Key::from_name("errors"), &METADATA);
+        requests.increment(5);
+        errors.increment(1);
+
+        let handle = recorder.handle();
+        let rendered = handle.render();
+        assert!(rendered.contains("requests 5\n"), "{}", rendered);
+        assert!(rendered.contains("errors 1\n"), "{}", rendered);
+
+        requests.increment(3);
+        let rendered = handle.render();
+        assert!(rendered.contains("requests 3\n"), "{}", rendered);
+        assert!(rendered.contains("errors 0\n"), "{}", rendered);
+
+        // Snapshots and partial renders report cumulative values, without consuming the increase.
+        requests.increment(4);
+        assert_eq!(handle.snapshot().counters["requests"][&Vec::<String>::new()], 12);
+        assert!(handle.render_family("requests").unwrap().contains("requests 12\n"));
+        let rendered = handle.render();
+        assert!(rendered.contains("requests 4\n"), "{}", rendered);
+
+        // A counter which starts over is rendered in full.
+        handle.clear();
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(2);
+        assert!(handle.render().contains("requests 2\n"));
+    }
+
+    #[test]
+    fn test_large_integer_values_are_exact() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        recorder.register_counter(&Key::from_name("bytes_sent"), &METADATA).absolute(u64::MAX);
+        // Series which collapse into one once sanitized are summed without overflowing.
+        recorder.register_counter(&Key::from_name("bytes-received"), &METADATA).absolute(u64::MAX);
+        recorder.register_counter(&Key::from_name("bytes_received"), &METADATA).absolute(1);
+
+        let handle = recorder.handle();
+        handle.set_histogram_buckets("size", &[], &[(1.0, u64::MAX - 1)], 0.0, u64::MAX).unwrap();
+
+        let rendered = handle.render();
+        assert!(rendered.contains("bytes_sent 18446744073709551615\n"), "{}", rendered);
+        assert!(rendered.contains("bytes_received 18446744073709551615\n"), "{}", rendered);
+        assert!(rendered.contains("size_bucket{le=\"1\"} 18446744073709551614\n"), "{}", rendered);
+        assert!(
+            rendered.contains("size_bucket{le=\"+Inf\"} 18446744073709551615\n"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("size_count 18446744073709551615\n"), "{}", rendered);
+
+        let rendered = handle.render_openmetrics();
+        assert!(rendered.contains("bytes_sent_total 18446744073709551615\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_append_unit_to_help() {
+        let recorder = PrometheusBuilder::new()
+            .append_unit_to_help(true)
+            .set_buckets(&[1.0])
+            .unwrap()
+            .build_recorder();
+        recorder.describe_histogram(
+            KeyName::from("request_duration"),
+            Some(Unit::Seconds),
+            "Request latency".into(),
+        );
+        recorder.describe_counter(KeyName::from("requests"), None, "Number of requests".into());
+        recorder.register_histogram(&Key::from_name("request_duration"), &METADATA).record(0.5);
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+
+        let rendered = recorder.handle().render();
+        assert!(
+            rendered.contains("# HELP request_duration Request latency (unit: seconds)\n"),
+            "{}",
+            rendered
+        );
+        assert!(rendered.contains("# HELP requests Number of requests\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_histogram_bucket_counts() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full("latency".into()),
+                &crate::linear_buckets(0.1, 0.1, 10).unwrap(),
+            )
+            .unwrap()
+            .build_recorder();
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(0.5);
+        recorder.register_histogram(&Key::from_name("size"), &METADATA).record(1.0);
+
+        let handle = recorder.handle();
+        assert_eq!(handle.histogram_bucket_counts(), [("latency".to_owned(), 10)]);
+
+        // The `+Inf` bucket is rendered in addition to the configured buckets.
+        let rendered = handle.render();
+        assert_eq!(rendered.matches("latency_bucket{").count(), 11, "{rendered}");
+    }
+
+    #[test]
+    fn test_lowercase_names() {
+        let recorder = PrometheusBuilder::new()
+            .lowercase_names(true)
+            .lowercase_label_keys(true)
+            .set_buckets_for_metric(Matcher::Full("RequestDuration".into()), &[1.0])
+            .unwrap()
+            .build_recorder();
+        let key = Key::from_parts("HttpRequests", vec![Label::new("Method", "GET")]);
+        recorder.register_counter(&key, &METADATA).increment(1);
+        // Names which only differ by case end up in the same family.
+        recorder.register_counter(&Key::from_name("httpRequests"), &METADATA).increment(2);
+        recorder.register_histogram(&Key::from_name("RequestDuration"), &METADATA).record(0.5);
+
+        let rendered = recorder.handle().render();
+        assert!(rendered.contains("httprequests{method=\"GET\"} 1\n"), "{}", rendered);
+        assert!(rendered.contains("httprequests 2\n"), "{}", rendered);
+        assert!(rendered.contains("# TYPE requestduration histogram\n"), "{}", rendered);
+        assert!(!rendered.contains("Http"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_flush_reflects_recorded_values() {
+        let recorder = std::sync::Arc::new(
+            PrometheusBuilder::new().set_buckets(&[1.0]).unwrap().build_recorder(),
+        );
+        let handle = recorder.handle();
+
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(0.5);
+        handle.flush();
+        let rendered = handle.render();
+        assert!(rendered.contains("requests 1\n"), "{}", rendered);
+        assert!(rendered.contains("latency_count 1\n"), "{}", rendered);
+
+        // Values recorded on another thread are reflected once it has been joined.
+        let remote = std::sync::Arc::clone(&recorder);
+        std::thread::spawn(move || {
+            remote.register_counter(&Key::from_name("requests"), &METADATA).increment(1);
+            remote.register_histogram(&Key::from_name("latency"), &METADATA).record(2.0);
+        })
+        .join()
+        .unwrap();
+        handle.flush();
+        let rendered = handle.render();
+        assert!(rendered.contains("requests 2\n"), "{}", rendered);
+        assert!(rendered.contains("latency_bucket{le=\"1\"} 1\n"), "{}", rendered);
+        assert!(rendered.contains("latency_count 2\n"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_summary_max_age() {
+        let (clock, mock) = Clock::mock();
+        mock.increment(Duration::from_secs(1));
+        let recorder = PrometheusBuilder::new()
+            .set_summary_max_age(Duration::from_secs(600))
+            .unwrap()
+            .set_summary_age_buckets(5)
+            .unwrap()
+            .set_quantiles(&[1.0])
+            .unwrap()
+            .build_with_clock(clock);
+        let histogram = recorder.register_histogram(&Key::from_name("latency"), &METADATA);
+        let handle = recorder.handle();
+        let max = || {
+            let rendered = handle.render();
+            let line = rendered
+                .lines()
+                .find(|line| line.starts_with("latency{quantile=\"1\"}"))
+                .unwrap()
+                .to_owned();
+            line.rsplit(' ').next().unwrap().parse::<f64>().unwrap()
+        };
+
+        histogram.record(100.0);
+        mock.increment(Duration::from_secs(300));
+        histogram.record(1.0);
+        assert!((max() - 100.0).abs() < 0.1);
+
+        // Once the first sample is older than the maximum age, it no longer influences the
+        // quantiles, but is still counted.
+        mock.increment(Duration::from_secs(360));
+        assert!((max() - 1.0).abs() < 0.001);
+        assert!(handle.render().contains("latency_count 2\n"));
+
+        for count in [0, usize::MAX] {
+            let err = PrometheusBuilder::new().set_summary_age_buckets(count).unwrap_err();
+            assert!(matches!(err, crate::BuildError::InvalidSummaryAgeBuckets(_)), "{}", err);
+        }
+        let err = PrometheusBuilder::new().set_summary_max_age(Duration::ZERO).unwrap_err();
+        assert!(matches!(err, crate::BuildError::ZeroBucketDuration), "{}", err);
+    }
+
+    #[test]
+    fn test_unordered_histogram_buckets_render_ascending() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        handle.set_histogram_buckets("size", &[], &[(1.0, 2), (0.5, 1), (2.0, 3)], 3.5, 3).unwrap();
+
+        let rendered = handle.render();
+        let bounds = rendered
+            .lines()
+            .filter_map(|line| line.strip_prefix("size_bucket{le=\""))
+            .map(|line| line.split('"').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(bounds, ["0.5", "1", "2", "+Inf"], "{rendered}");
+    }
+
+    #[test]
+    fn test_render_to_string_capacity() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        for i in 0..50 {
+            let key = Key::from_parts("requests", vec![Label::new("id", i.to_string())]);
+            recorder.register_counter(&key, &METADATA).increment(i);
+        }
+
+        let expected = handle.render();
+        assert!(!expected.is_empty());
+        for capacity in [0, 16, expected.len(), expected.len() * 2] {
+            let rendered = handle.render_to_string_capacity(capacity);
+            assert_eq!(rendered, expected);
+            assert!(rendered.capacity() >= capacity);
+        }
+
+        // Subsequent renders are sized from previous ones, without needing to grow the buffer.
+        let rendered = handle.render();
+        assert_eq!(rendered, expected);
+        assert_eq!(rendered.capacity(), expected.len());
+    }
+
+    #[test]
+    fn test_deny_metric() {
+        let recorder =
+            PrometheusBuilder::new().deny_metric(Matcher::Prefix("debug_".into())).build_recorder();
+        let handle = recorder.handle();
+
+        recorder.describe_counter(
+            KeyName::from("debug_requests"),
+            None,
+            "internal requests".into(),
+        );
+        recorder.register_counter(&Key::from_name("debug_requests"), &METADATA).increment(1);
+        recorder.register_gauge(&Key::from_name("debug.queue"), &METADATA).set(2.0);
+        recorder.register_histogram(&Key::from_name("debug_latency"), &METADATA).record(3.0);
+        handle.set_histogram_buckets("debug_sizes", &[], &[(1.0, 1)], 0.5, 1).unwrap();
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(4);
+
+        let rendered = handle.render();
+        assert_eq!(rendered, "# TYPE requests counter\nrequests 4\n\n");
+        assert!(handle.descriptions().is_empty());
+
+        let snapshot = handle.snapshot();
+        assert_eq!(snapshot.counters.keys().collect::<Vec<_>>(), ["requests"]);
+        assert!(snapshot.gauges.is_empty());
+        assert!(snapshot.distributions.is_empty());
+    }
+
+    #[test]
+    fn test_relative_gauge_updates() {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let key = Key::from_name("queue_depth");
+
+        recorder.register_gauge(&key, &METADATA).set(5.0);
+        handle.increment_gauge(&key, 3.0);
+        handle.decrement_gauge(&key, 2.0);
+        assert_eq!(handle.render(), "# TYPE queue_depth gauge\nqueue_depth 6\n\n");
+
+        // Relative updates through the facade land in the same series.
+        let gauge = recorder.register_gauge(&key, &METADATA);
+        gauge.increment(1.5);
+        gauge.decrement(0.5);
+        assert_eq!(handle.render(), "# TYPE queue_depth gauge\nqueue_depth 7\n\n");
+    }
+
+    #[test]
+    fn test_description_collision_policy() {
+        for (policy, expected) in [
+            (DescriptionCollisionPolicy::First, "# HELP foo_bar dotted\n"),
+            (DescriptionCollisionPolicy::Concatenate, "# HELP foo_bar dotted; dashed\n"),
+        ] {
+            let recorder =
+                PrometheusBuilder::new().description_collision_policy(policy).build_recorder();
+            let handle = recorder.handle();
+
+            for _ in 0..2 {
+                recorder.describe_counter(KeyName::from("foo.bar"), None, "dotted".into());
+                recorder.describe_counter(KeyName::from("foo-bar"), None, "dashed".into());
+            }
+            recorder.register_counter(&Key::from_name("foo.bar"), &METADATA).increment(1);
+            recorder.register_counter(&Key::from_name("foo-bar"), &METADATA).increment(1);
+
+            let rendered = handle.render();
+            let expected = format!("{expected}# TYPE foo_bar counter\nfoo_bar 2\n\n");
+            assert_eq!(rendered, expected, "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn test_count_out_of_bounds() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets(&[1.0, 5.0])
+            .expect("buckets should be valid")
+            .count_out_of_bounds(true)
+            .build_recorder();
+        let handle = recorder.handle();
+
+        let key = Key::from_parts("latency", vec![Label::new("endpoint", "a")]);
+        let histogram = recorder.register_histogram(&key, &METADATA);
+        for value in [0.5, 5.0, 7.0, 10.0] {
+            histogram.record(value);
+        }
+
+        let rendered = handle.render();
+        assert!(
+            rendered.contains("latency_bucket{endpoint=\"a\",le=\"+Inf\"} 4\n"),
+            "{}",
+            rendered
+        );
+        assert!(
+            rendered.contains("latency_out_of_bounds_total{endpoint=\"a\"} 2\n"),
+            "{}",
+            rendered
+        );
+
+        // Registering the histogram again shares the same counter with held handles.
+        recorder.register_histogram(&key, &METADATA).record(8.0);
+        histogram.record(9.0);
+        let rendered = handle.render();
+        assert!(
+            rendered.contains("latency_out_of_bounds_total{endpoint=\"a\"} 4\n"),
+            "{}",
+            rendered
+        );
+
+        // Summaries have no buckets to exceed.
+        let recorder = PrometheusBuilder::new().count_out_of_bounds(true).build_recorder();
+        recorder.register_histogram(&key, &METADATA).record(1e9);
+        assert!(!recorder.handle().render().contains("out_of_bounds"));
+    }
 }
@@ -1,12 +1,12 @@
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use http_body_util::Full;
 use hyper::{
     body::{self, Bytes, Incoming},
-    header::{HeaderValue, CONTENT_TYPE},
+    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
     server::conn::http1::Builder as HyperHttpBuilder,
     service::service_fn,
-    Request, Response, StatusCode,
+    Method, Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
 use ipnet::IpNet;
@@ -22,6 +22,7 @@ use crate::{common::BuildError, ExporterFuture, PrometheusHandle};
 struct HttpListeningExporter {
     handle: PrometheusHandle,
     allowed_addresses: Option<Vec<IpNet>>,
+    basic_auth: Option<HeaderValue>,
     listener_type: ListenerType,
 }
 
@@ -67,9 +68,12 @@ impl HttpListeningExporter {
     fn process_tcp_stream(&self, stream: TcpStream) {
         let is_allowed = self.check_tcp_allowed(&stream);
         let handle = self.handle.clone();
+        let basic_auth = self.basic_auth.clone();
         let service = service_fn(move |req: Request<body::Incoming>| {
             let handle = handle.clone();
-            async move { Ok::<_, hyper::Error>(Self::handle_http_request(is_allowed, &handle, &req)) }
+            let response =
+                Self::handle_http_request(is_allowed, basic_auth.as_ref(), &handle, &req);
+            async move { Ok::<_, hyper::Error>(response) }
         });
 
         tokio::spawn(async move {
@@ -82,19 +86,16 @@ impl HttpListeningExporter {
     }
 
     fn check_tcp_allowed(&self, stream: &TcpStream) -> bool {
-        let Some(addrs) = &self.allowed_addresses else {
+        if self.allowed_addresses.is_none() {
             // No allowed addresses specified, so everything is allowed
             return true;
-        };
+        }
         stream.peer_addr().map_or_else(
             |e| {
                 warn!(error = ?e, "Error obtaining remote address.");
                 false
             },
-            |peer_addr| {
-                let remote_ip = peer_addr.ip();
-                addrs.iter().any(|addr| addr.contains(&remote_ip))
-            },
+            |peer_addr| is_allowed(self.allowed_addresses.as_deref(), peer_addr.ip()),
         )
     }
 
@@ -115,9 +116,11 @@ impl HttpListeningExporter {
     #[cfg(feature = "uds-listener")]
     fn process_uds_stream(&self, stream: UnixStream) {
         let handle = self.handle.clone();
+        let basic_auth = self.basic_auth.clone();
         let service = service_fn(move |req: Request<body::Incoming>| {
             let handle = handle.clone();
-            async move { Ok::<_, hyper::Error>(Self::handle_http_request(true, &handle, &req)) }
+            let response = Self::handle_http_request(true, basic_auth.as_ref(), &handle, &req);
+            async move { Ok::<_, hyper::Error>(response) }
         });
 
         tokio::spawn(async move {
@@ -131,28 +134,71 @@ impl HttpListeningExporter {
 
     fn handle_http_request(
         is_allowed: bool,
+        basic_auth: Option<&HeaderValue>,
         handle: &PrometheusHandle,
         req: &Request<Incoming>,
     ) -> Response<Full<Bytes>> {
-        if is_allowed {
-            let mut response = Response::new(match req.uri().path() {
-                "/health" => "OK".into(),
-                _ => handle.render().into(),
-            });
-            response.headers_mut().append(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
-            response
-        } else {
+        if !is_allowed {
             // This unwrap should not fail because we don't use any function that
             // can assign an Err to it's inner such as `Builder::header``. A unit test
             // will have to suffice to detect if this fails to hold true.
-            Response::builder()
+            return Response::builder()
                 .status(StatusCode::FORBIDDEN)
                 .body(Full::<Bytes>::default())
-                .unwrap()
+                .unwrap();
         }
+
+        if let Some(expected) = basic_auth {
+            let authorized = req
+                .headers()
+                .get(AUTHORIZATION)
+                .map_or(false, |actual| constant_time_eq(actual.as_bytes(), expected.as_bytes()));
+            if !authorized {
+                // As above, this unwrap should not fail.
+                return Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header(WWW_AUTHENTICATE, HeaderValue::from_static("Basic"))
+                    .body(Full::<Bytes>::default())
+                    .unwrap();
+            }
+        }
+
+        let body = match (req.method(), req.uri().path()) {
+            (&Method::GET, "/metrics") => handle.render(),
+            (&Method::GET, "/health") => "OK".to_owned(),
+            _ => {
+                // As above, this unwrap should not fail.
+                return Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Full::<Bytes>::default())
+                    .unwrap();
+            }
+        };
+
+        let mut response = Response::new(body.into());
+        response
+            .headers_mut()
+            .append(CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+        response
     }
 }
 
+/// Checks whether the given address is in the allowlist, either directly or within any of the
+/// allowed subnets.
+///
+/// Everything is allowed when there is no allowlist.
+fn is_allowed(allowed_addresses: Option<&[IpNet]>, address: IpAddr) -> bool {
+    allowed_addresses.map_or(true, |allowed| allowed.iter().any(|net| net.contains(&address)))
+}
+
+/// Compares two byte strings in constant time with respect to their contents.
+///
+/// Only the lengths of the inputs can be inferred from timing, which avoids leaking how much of a
+/// credential matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /// Creates an `ExporterFuture` implementing a http listener that serves prometheus metrics.
 ///
 /// # Errors
@@ -161,6 +207,7 @@ pub(crate) fn new_http_listener(
     handle: PrometheusHandle,
     listen_address: SocketAddr,
     allowed_addresses: Option<Vec<IpNet>>,
+    basic_auth: Option<HeaderValue>,
 ) -> Result<ExporterFuture, BuildError> {
     let listener = std::net::TcpListener::bind(listen_address)
         .and_then(|listener| {
@@ -173,6 +220,7 @@ pub(crate) fn new_http_listener(
     let exporter = HttpListeningExporter {
         handle,
         allowed_addresses,
+        basic_auth,
         listener_type: ListenerType::Tcp(listener),
     };
 
@@ -188,18 +236,72 @@ pub(crate) fn new_http_listener(
 pub(crate) fn new_http_uds_listener(
     handle: PrometheusHandle,
     listen_path: PathBuf,
+    basic_auth: Option<HeaderValue>,
 ) -> Result<ExporterFuture, BuildError> {
     if listen_path.exists() {
         std::fs::remove_file(&listen_path)
             .map_err(|e| BuildError::FailedToCreateHTTPListener(e.to_string()))?;
     }
-    let listener = UnixListener::bind(listen_path)
+    let listener = UnixListener::bind(&listen_path)
         .map_err(|e| BuildError::FailedToCreateHTTPListener(e.to_string()))?;
+    let socket_file = SocketFile(listen_path);
     let exporter = HttpListeningExporter {
         handle,
         allowed_addresses: None,
+        basic_auth,
         listener_type: ListenerType::Uds(listener),
     };
 
-    Ok(Box::pin(async move { exporter.serve().await.map_err(super::ExporterError::HttpListener) }))
+    Ok(Box::pin(async move {
+        // Moved into the future so that the socket file is removed once the future is dropped.
+        let _socket_file = socket_file;
+        exporter.serve().await.map_err(super::ExporterError::HttpListener)
+    }))
+}
+
+/// Removes the socket file of a Unix domain socket listener when dropped.
+#[cfg(feature = "uds-listener")]
+struct SocketFile(PathBuf);
+
+#[cfg(feature = "uds-listener")]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use ipnet::IpNet;
+
+    use super::{constant_time_eq, is_allowed};
+
+    #[test]
+    fn test_is_allowed() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let allowed = ["10.0.0.0/8", "192.168.1.1/32", "::1/128"]
+            .iter()
+            .map(|s| s.parse::<IpNet>().unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(is_allowed(None, ip("203.0.113.1")));
+        assert!(!is_allowed(Some(&[]), ip("127.0.0.1")));
+
+        assert!(is_allowed(Some(&allowed), ip("10.1.2.3")));
+        assert!(is_allowed(Some(&allowed), ip("192.168.1.1")));
+        assert!(is_allowed(Some(&allowed), ip("::1")));
+        assert!(!is_allowed(Some(&allowed), ip("192.168.1.2")));
+        assert!(!is_allowed(Some(&allowed), ip("11.0.0.1")));
+        assert!(!is_allowed(Some(&allowed), ip("::2")));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"Basic abc=", b"Basic abc="));
+        assert!(!constant_time_eq(b"Basic abc=", b"Basic abd="));
+        assert!(!constant_time_eq(b"Basic abc=", b"Basic abc"));
+    }
 }
@@ -9,6 +9,8 @@ use std::pin::Pin;
 #[cfg(feature = "push-gateway")]
 use std::time::Duration;
 
+#[cfg(any(feature = "http-listener", feature = "push-gateway"))]
+use hyper::header::HeaderValue;
 #[cfg(feature = "push-gateway")]
 use hyper::Uri;
 
@@ -65,6 +67,26 @@ impl ExporterConfig {
     }
 }
 
+/// Creates the value of an `Authorization` header for HTTP Basic authentication.
+#[cfg(any(feature = "http-listener", feature = "push-gateway"))]
+fn basic_auth(username: &str, password: Option<&str>) -> HeaderValue {
+    use base64::prelude::BASE64_STANDARD;
+    use base64::write::EncoderWriter;
+    use std::io::Write;
+
+    let mut buf = b"Basic ".to_vec();
+    {
+        let mut encoder = EncoderWriter::new(&mut buf, &BASE64_STANDARD);
+        write!(encoder, "{username}:").expect("should not fail to encode username");
+        if let Some(password) = password {
+            write!(encoder, "{password}").expect("should not fail to encode password");
+        }
+    }
+    let mut header = HeaderValue::from_bytes(&buf).expect("base64 is always valid HeaderValue");
+    header.set_sensitive(true);
+    header
+}
+
 #[cfg(feature = "http-listener")]
 mod http_listener;
 
@@ -72,3 +94,40 @@ mod http_listener;
 mod push_gateway;
 
 pub(crate) mod builder;
+
+#[cfg(all(test, any(feature = "http-listener", feature = "push-gateway")))]
+mod tests {
+    use super::basic_auth;
+
+    #[test]
+    #[allow(clippy::similar_names)] // reader vs header, sheesh clippy
+    pub fn test_basic_auth() {
+        use base64::prelude::BASE64_STANDARD;
+        use base64::read::DecoderReader;
+        use std::io::Read;
+
+        const BASIC: &str = "Basic ";
+
+        // username only
+        let username = "metrics";
+        let header = basic_auth(username, None);
+
+        let reader = &header.as_ref()[BASIC.len()..];
+        let mut decoder = DecoderReader::new(reader, &BASE64_STANDARD);
+        let mut result = Vec::new();
+        decoder.read_to_end(&mut result).unwrap();
+        assert_eq!(b"metrics:", &result[..]);
+        assert!(header.is_sensitive());
+
+        // username/password
+        let password = "123!_@ABC";
+        let header = basic_auth(username, Some(password));
+
+        let reader = &header.as_ref()[BASIC.len()..];
+        let mut decoder = DecoderReader::new(reader, &BASE64_STANDARD);
+        let mut result = Vec::new();
+        decoder.read_to_end(&mut result).unwrap();
+        assert_eq!(b"metrics:123!_@ABC", &result[..]);
+        assert!(header.is_sensitive());
+    }
+}
@@ -1,5 +1,7 @@
 //! Helpers for rendering metrics in the Prometheus exposition format.
 
+use std::fmt;
+
 use indexmap::IndexMap;
 use metrics::Key;
 
@@ -9,18 +11,58 @@ use metrics::Key;
 ///
 /// Both the metric name, and labels, are sanitized. See [`sanitize_metric_name`], [`sanitize_label_key`],
 /// and [`sanitize_label_value`] for more information.
+///
+/// Labels are sorted by key, so that the same set of labels always produces the same parts regardless
+/// of the order they were given in.
 pub fn key_to_parts(
     key: &Key,
     default_labels: Option<&IndexMap<String, String>>,
 ) -> (String, Vec<String>) {
-    let name = sanitize_metric_name(key.name());
+    let retain = |_: &str, _: &str| true;
+    key_to_parts_with(
+        key,
+        default_labels,
+        retain,
+        true,
+        sanitize_metric_name,
+        sanitize_label_key,
+        sanitize_label_value,
+    )
+}
+
+/// Breaks a key into the name and label components, using the given sanitizers for the metric name,
+/// label keys, and label values.
+///
+/// Only labels for which `retain` returns `true`, given their key and value, are kept, which is
+/// checked before sanitization.  If `sort_labels` is `false`, labels are kept in insertion order,
+/// with default labels first.
+pub(crate) fn key_to_parts_with<R, N, L, V>(
+    key: &Key,
+    default_labels: Option<&IndexMap<String, String>>,
+    retain: R,
+    sort_labels: bool,
+    sanitize_name: N,
+    sanitize_key: L,
+    sanitize_value: V,
+) -> (String, Vec<String>)
+where
+    R: Fn(&str, &str) -> bool,
+    N: Fn(&str) -> String,
+    L: Fn(&str) -> String,
+    V: Fn(&str) -> String,
+{
+    let name = sanitize_name(key.name());
     let mut values = default_labels.cloned().unwrap_or_default();
     key.labels().for_each(|label| {
         values.insert(label.key().to_string(), label.value().to_string());
     });
+    values.retain(|k, v| retain(k, v));
+    if sort_labels {
+        values.sort_keys();
+    }
     let labels = values
         .iter()
-        .map(|(k, v)| format!("{}=\"{}\"", sanitize_label_key(k), sanitize_label_value(v)))
+        .map(|(k, v)| format!("{}=\"{}\"", sanitize_key(k), sanitize_value(v)))
         .collect();
 
     (name, labels)
@@ -28,25 +70,79 @@ pub fn key_to_parts(
 
 /// Writes a help (description) line in the Prometheus [exposition format].
 ///
+/// ## Errors
+///
+/// If writing to `buffer` fails, an error is returned.
+///
 /// [exposition format]: https://github.com/prometheus/docs/blob/main/content/docs/instrumenting/exposition_formats.md#text-format-details
-pub fn write_help_line(buffer: &mut String, name: &str, desc: &str) {
-    buffer.push_str("# HELP ");
-    buffer.push_str(name);
-    buffer.push(' ');
+pub fn write_help_line<W>(buffer: &mut W, name: &str, desc: &str) -> fmt::Result
+where
+    W: fmt::Write + ?Sized,
+{
+    buffer.write_str("# HELP ")?;
+    buffer.write_str(name)?;
+    buffer.write_char(' ')?;
     let desc = sanitize_description(desc);
-    buffer.push_str(&desc);
-    buffer.push('\n');
+    buffer.write_str(&desc)?;
+    buffer.write_char('\n')
+}
+
+/// Writes a help (description) line in the [OpenMetrics] text format.
+///
+/// Unlike [`write_help_line`], double quotes in the description are escaped as well, as required by
+/// the specification.
+///
+/// ## Errors
+///
+/// If writing to `buffer` fails, an error is returned.
+///
+/// [OpenMetrics]: https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md#text-format
+pub fn write_openmetrics_help_line<W>(buffer: &mut W, name: &str, desc: &str) -> fmt::Result
+where
+    W: fmt::Write + ?Sized,
+{
+    buffer.write_str("# HELP ")?;
+    buffer.write_str(name)?;
+    buffer.write_char(' ')?;
+    let desc = sanitize_label_value(desc);
+    buffer.write_str(&desc)?;
+    buffer.write_char('\n')
+}
+
+/// Writes a unit line in the [OpenMetrics] text format.
+///
+/// ## Errors
+///
+/// If writing to `buffer` fails, an error is returned.
+///
+/// [OpenMetrics]: https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md#text-format
+pub fn write_unit_line<W>(buffer: &mut W, name: &str, unit: &str) -> fmt::Result
+where
+    W: fmt::Write + ?Sized,
+{
+    buffer.write_str("# UNIT ")?;
+    buffer.write_str(name)?;
+    buffer.write_char(' ')?;
+    buffer.write_str(unit)?;
+    buffer.write_char('\n')
 }
 
 /// Writes a metric type line in the Prometheus [exposition format].
 ///
+/// ## Errors
+///
+/// If writing to `buffer` fails, an error is returned.
+///
 /// [exposition format]: https://github.com/prometheus/docs/blob/main/content/docs/instrumenting/exposition_formats.md#text-format-details
-pub fn write_type_line(buffer: &mut String, name: &str, metric_type: &str) {
-    buffer.push_str("# TYPE ");
-    buffer.push_str(name);
-    buffer.push(' ');
-    buffer.push_str(metric_type);
-    buffer.push('\n');
+pub fn write_type_line<W>(buffer: &mut W, name: &str, metric_type: &str) -> fmt::Result
+where
+    W: fmt::Write + ?Sized,
+{
+    buffer.write_str("# TYPE ")?;
+    buffer.write_str(name)?;
+    buffer.write_char(' ')?;
+    buffer.write_str(metric_type)?;
+    buffer.write_char('\n')
 }
 
 /// Writes a metric in the Prometheus [exposition format].
@@ -56,78 +152,180 @@ pub fn write_type_line(buffer: &mut String, name: &str, metric_type: &str) {
 /// `additional_label` would typically be used to specify a data type-specific label, such as `le` for
 /// for aggregated histograms, or `quantile` for aggregated summaries.
 ///
+/// ## Errors
+///
+/// If writing to `buffer` fails, an error is returned.
+///
 /// [exposition format]: https://github.com/prometheus/docs/blob/main/content/docs/instrumenting/exposition_formats.md#text-format-details
-pub fn write_metric_line<T, T2>(
-    buffer: &mut String,
+pub fn write_metric_line<W, T, T2>(
+    buffer: &mut W,
     name: &str,
     suffix: Option<&'static str>,
     labels: &[String],
     additional_label: Option<(&'static str, T)>,
     value: T2,
-) where
-    T: std::fmt::Display,
-    T2: std::fmt::Display,
+) -> fmt::Result
+where
+    W: fmt::Write + ?Sized,
+    T: fmt::Display,
+    T2: fmt::Display,
 {
-    buffer.push_str(name);
+    write_metric_line_with_timestamp(buffer, name, suffix, labels, additional_label, value, None)
+}
+
+/// Writes a metric in the Prometheus [exposition format], with an optional timestamp.
+///
+/// This behaves the same as [`write_metric_line`], except that when `timestamp` is specified, it is
+/// written verbatim after the value.  The timestamp should already be formatted as required by the
+/// exposition format in use, i.e. as milliseconds since the Unix epoch for the Prometheus text
+/// format.
+///
+/// When `suffix` is specified, it is appended to the `name`, which is useful for writing summary
+/// statistics, such as the sum or total of an aggregated histogram or aggregated summary.  Likewise,
+/// `additional_label` would typically be used to specify a data type-specific label, such as `le` for
+/// for aggregated histograms, or `quantile` for aggregated summaries.
+///
+/// ## Errors
+///
+/// If writing to `buffer` fails, an error is returned.
+///
+/// [exposition format]: https://github.com/prometheus/docs/blob/main/content/docs/instrumenting/exposition_formats.md#text-format-details
+pub fn write_metric_line_with_timestamp<W, T, T2>(
+    buffer: &mut W,
+    name: &str,
+    suffix: Option<&'static str>,
+    labels: &[String],
+    additional_label: Option<(&'static str, T)>,
+    value: T2,
+    timestamp: Option<&str>,
+) -> fmt::Result
+where
+    W: fmt::Write + ?Sized,
+    T: fmt::Display,
+    T2: fmt::Display,
+{
+    write_metric_sample(buffer, name, suffix, labels, additional_label, value, timestamp)?;
+    buffer.write_char('\n')
+}
+
+/// Writes a metric sample in the Prometheus [exposition format], without the terminating newline.
+///
+/// This allows anything that trails the sample, such as an `OpenMetrics` exemplar, to be written on
+/// the same line.
+///
+/// [exposition format]: https://github.com/prometheus/docs/blob/main/content/docs/instrumenting/exposition_formats.md#text-format-details
+pub(crate) fn write_metric_sample<W, T, T2>(
+    buffer: &mut W,
+    name: &str,
+    suffix: Option<&'static str>,
+    labels: &[String],
+    additional_label: Option<(&'static str, T)>,
+    value: T2,
+    timestamp: Option<&str>,
+) -> fmt::Result
+where
+    W: fmt::Write + ?Sized,
+    T: fmt::Display,
+    T2: fmt::Display,
+{
+    buffer.write_str(name)?;
     if let Some(suffix) = suffix {
-        buffer.push('_');
-        buffer.push_str(suffix);
+        buffer.write_char('_')?;
+        buffer.write_str(suffix)?;
     }
 
     if !labels.is_empty() || additional_label.is_some() {
-        buffer.push('{');
+        buffer.write_char('{')?;
 
         let mut first = true;
         for label in labels {
             if first {
                 first = false;
             } else {
-                buffer.push(',');
+                buffer.write_char(',')?;
             }
-            buffer.push_str(label);
+            buffer.write_str(label)?;
         }
 
         if let Some((name, value)) = additional_label {
             if !first {
-                buffer.push(',');
+                buffer.write_char(',')?;
             }
-            buffer.push_str(name);
-            buffer.push_str("=\"");
-            buffer.push_str(value.to_string().as_str());
-            buffer.push('"');
+            write!(buffer, "{name}=\"{value}\"")?;
         }
 
-        buffer.push('}');
+        buffer.write_char('}')?;
     }
 
-    buffer.push(' ');
-    buffer.push_str(value.to_string().as_str());
-    buffer.push('\n');
+    write!(buffer, " {value}")?;
+    if let Some(timestamp) = timestamp {
+        buffer.write_char(' ')?;
+        buffer.write_str(timestamp)?;
+    }
+
+    Ok(())
+}
+
+/// A floating-point value displayed as expected by the Prometheus exposition format.
+///
+/// Positive and negative infinity are displayed as `+Inf` and `-Inf`, and NaN as `NaN`, rather
+/// than the `inf`, `-inf`, and `NaN` produced by the standard `Display` implementation for `f64`.
+/// All other values are displayed as the shortest representation which parses back to the same
+/// value, so that e.g. `1.0` is displayed as `1`.
+///
+/// This is used for sample values, as well as for the `le` and `quantile` labels of histograms and
+/// summaries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FloatValue(pub f64);
+
+impl fmt::Display for FloatValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_nan() {
+            f.write_str("NaN")
+        } else if self.0.is_infinite() {
+            f.write_str(if self.0.is_sign_positive() { "+Inf" } else { "-Inf" })
+        } else {
+            fmt::Display::fmt(&self.0, f)
+        }
+    }
 }
 
 /// Sanitizes a metric name to be valid under the Prometheus [data model].
 ///
 /// [data model]: https://prometheus.io/docs/concepts/data_model/#metric-names-and-labels
 pub fn sanitize_metric_name(name: &str) -> String {
-    // The first character must be [a-zA-Z_:], and all subsequent characters must be [a-zA-Z0-9_:].
-    name.chars()
-        .enumerate()
-        .map(|(i, c)| {
-            if i == 0 && valid_metric_name_start_character(c)
-                || i != 0 && valid_metric_name_character(c)
-            {
-                c
-            } else {
-                '_'
-            }
-        })
-        .collect()
+    sanitize_metric_name_with(name, '_')
+}
+
+/// Sanitizes a metric name, replacing invalid characters with the given replacement.
+pub(crate) fn sanitize_metric_name_with(name: &str, replacement: char) -> String {
+    // The first character must be [a-zA-Z_], and all subsequent characters must be [a-zA-Z0-9_].
+    // A leading digit is kept by prefixing an underscore, rather than being replaced.
+    let mut sanitized = String::with_capacity(name.len() + 1);
+    if name.starts_with(|c| !valid_metric_name_start_character(c) && valid_metric_name_character(c))
+    {
+        sanitized.push('_');
+    }
+
+    sanitized.extend(name.chars().map(|c| {
+        if valid_metric_name_character(c) {
+            c
+        } else {
+            replacement
+        }
+    }));
+    sanitized
 }
 
 /// Sanitizes a label key to be valid under the Prometheus [data model].
 ///
 /// [data model]: https://prometheus.io/docs/concepts/data_model/#metric-names-and-labels
 pub fn sanitize_label_key(key: &str) -> String {
+    sanitize_label_key_with(key, '_')
+}
+
+/// Sanitizes a label key, replacing invalid characters with the given replacement.
+pub(crate) fn sanitize_label_key_with(key: &str, replacement: char) -> String {
     // The first character must be [a-zA-Z_], and all subsequent characters must be [a-zA-Z0-9_].
     key.chars()
         .enumerate()
@@ -137,12 +335,41 @@ pub fn sanitize_label_key(key: &str) -> String {
             {
                 c
             } else {
-                '_'
+                replacement
             }
         })
         .collect()
 }
 
+/// Prepends the given namespace prefix to a metric name, unless the name already starts with it.
+pub(crate) fn with_namespace(prefix: Option<&str>, name: String) -> String {
+    match prefix {
+        Some(prefix) if !name.starts_with(prefix) => format!("{prefix}{name}"),
+        _ => name,
+    }
+}
+
+/// Strictly sanitizes a metric name or label key to match `[a-zA-Z_][a-zA-Z0-9_]*`.
+///
+/// This is how [`sanitize_metric_name`] sanitizes metric names, but unlike [`sanitize_label_key`], a
+/// leading digit is preserved by prefixing an underscore rather than being replaced.
+pub fn sanitize_strict(name: &str) -> String {
+    sanitize_strict_with(name, '_')
+}
+
+/// Strictly sanitizes a metric name or label key, replacing invalid characters with the given
+/// replacement.
+pub(crate) fn sanitize_strict_with(name: &str, replacement: char) -> String {
+    let mut sanitized = String::with_capacity(name.len() + 1);
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.push('_');
+    }
+
+    sanitized
+        .extend(name.chars().map(|c| if valid_label_key_character(c) { c } else { replacement }));
+    sanitized
+}
+
 /// Sanitizes a label value to be valid under the Prometheus [data model].
 ///
 /// [data model]: https://prometheus.io/docs/concepts/data_model/#metric-names-and-labels
@@ -150,6 +377,55 @@ pub fn sanitize_label_value(value: &str) -> String {
     sanitize_label_value_or_description(value, false)
 }
 
+/// Truncates an already sanitized label value to at most `max_len` characters, appending an ellipsis
+/// if anything was cut off.
+///
+/// Escape sequences are never split, so the truncated value remains correctly escaped.
+pub(crate) fn truncate_label_value(value: String, max_len: usize) -> String {
+    let mut len = 0;
+    let mut chars = value.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        // Escape sequences are a backslash followed by the escaped character.
+        let width = if c == '\\' && chars.next().is_some() { 2 } else { 1 };
+        if len + width > max_len {
+            let mut truncated = value[..idx].to_owned();
+            truncated.push('…');
+            return truncated;
+        }
+        len += width;
+    }
+
+    value
+}
+
+/// Splits a label formatted as `key="value"` into its key and unescaped value.
+#[cfg(any(feature = "json", feature = "remote-write", feature = "testing"))]
+pub(crate) fn parse_label(label: &str) -> Option<(&str, String)> {
+    let (key, value) = label.split_once('=')?;
+    let value = value.strip_prefix('"')?.strip_suffix('"')?;
+    Some((key, unescape_label_value(value)))
+}
+
+/// Reverses the escaping of label values done when sanitizing them.
+#[cfg(any(feature = "json", feature = "remote-write", feature = "testing"))]
+fn unescape_label_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(escaped) => unescaped.push(escaped),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
 /// Sanitizes a metric description to be valid under the Prometheus [exposition format].
 ///
 /// [exposition format]: https://github.com/prometheus/docs/blob/main/content/docs/instrumenting/exposition_formats.md#text-format-details
@@ -212,14 +488,15 @@ fn sanitize_label_value_or_description(value: &str, is_desc: bool) -> String {
 
 #[inline]
 fn valid_metric_name_start_character(c: char) -> bool {
-    // Essentially, needs to match the regex pattern of [a-zA-Z_:].
-    c.is_ascii_alphabetic() || c == '_' || c == ':'
+    // Essentially, needs to match the regex pattern of [a-zA-Z_].  Colons are valid in the data
+    // model, but are reserved for recording rules, so they're never exported.
+    c.is_ascii_alphabetic() || c == '_'
 }
 
 #[inline]
 fn valid_metric_name_character(c: char) -> bool {
-    // Essentially, needs to match the regex pattern of [a-zA-Z0-9_:].
-    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+    // Essentially, needs to match the regex pattern of [a-zA-Z0-9_].
+    c.is_ascii_alphanumeric() || c == '_'
 }
 
 #[inline]
@@ -238,11 +515,33 @@ fn valid_label_key_character(c: char) -> bool {
 mod tests {
     use crate::formatting::{
         sanitize_description, sanitize_label_key, sanitize_label_value, sanitize_metric_name,
-        valid_label_key_character, valid_label_key_start_character, valid_metric_name_character,
-        valid_metric_name_start_character,
+        sanitize_strict, valid_label_key_character, valid_label_key_start_character,
+        valid_metric_name_character, valid_metric_name_start_character, FloatValue,
     };
     use proptest::prelude::*;
 
+    #[test]
+    fn test_float_value_known_cases() {
+        let cases = &[
+            (0.0, "0"),
+            (1.0, "1"),
+            (-1.5, "-1.5"),
+            (0.005, "0.005"),
+            (0.999, "0.999"),
+            (1e6, "1000000"),
+            (1e-9, "0.000000001"),
+            (-0.0, "-0"),
+            (f64::INFINITY, "+Inf"),
+            (f64::NEG_INFINITY, "-Inf"),
+            (f64::NAN, "NaN"),
+        ];
+
+        for (input, expected) in cases {
+            let result = FloatValue(*input).to_string();
+            assert_eq!(expected, &result);
+        }
+    }
+
     #[test]
     fn test_sanitize_metric_name_known_cases() {
         let cases = &[
@@ -250,9 +549,12 @@ mod tests {
             ("\"", "_"),
             ("foo_bar", "foo_bar"),
             ("foo1_bar", "foo1_bar"),
-            ("1foobar", "_foobar"),
-            ("foo1:bar2", "foo1:bar2"),
-            ("123", "_23"),
+            ("1foobar", "_1foobar"),
+            ("5xx_errors", "_5xx_errors"),
+            ("foo1:bar2", "foo1_bar2"),
+            ("a:b", "a_b"),
+            (":", "_"),
+            ("123", "_123"),
         ];
 
         for (input, expected) in cases {
@@ -280,6 +582,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sanitize_strict_known_cases() {
+        let cases = &[
+            ("*", "_"),
+            (":", "_"),
+            ("foo_bar", "foo_bar"),
+            ("1foo", "_1foo"),
+            ("a b", "a_b"),
+            ("a:b", "a_b"),
+            ("http.method", "http_method"),
+            ("123", "_123"),
+        ];
+
+        for (input, expected) in cases {
+            let result = sanitize_strict(input);
+            assert_eq!(expected, &result);
+        }
+    }
+
     #[test]
     fn test_sanitize_label_value_known_cases() {
         let cases = &[
@@ -318,7 +639,13 @@ mod tests {
 
     proptest! {
         #[test]
-        fn test_sanitize_metric_name(input in "[\n\"\\\\]?.*[\n\"\\\\]?") {
+        fn test_float_value_round_trips(input in proptest::num::f64::NORMAL | proptest::num::f64::SUBNORMAL | proptest::num::f64::ZERO) {
+            let result = FloatValue(input).to_string();
+            assert_eq!(result.parse::<f64>().ok(), Some(input));
+        }
+
+        #[test]
+        fn test_sanitize_metric_name(input in "[\n\"\\\\:0-9]?.*[\n\"\\\\:]?") {
             let result = sanitize_metric_name(&input);
             let as_chars = result.chars().collect::<Vec<_>>();
 
@@ -359,6 +686,20 @@ mod tests {
                 "invalid character in label key");
         }
 
+        #[test]
+        fn test_sanitize_strict(input in "[\n\"\\\\:0-9]?.*[\n\"\\\\:]?") {
+            let result = sanitize_strict(&input);
+            let as_chars = result.chars().collect::<Vec<_>>();
+
+            if let Some(c) = as_chars.first() {
+                assert!(valid_label_key_start_character(*c),
+                    "first character of strict name was not valid");
+            }
+
+            assert!(as_chars.iter().all(|c| valid_label_key_character(*c)),
+                "invalid character in strict name");
+        }
+
         #[test]
         fn test_sanitize_label_value(input in "[\n\"\\\\]?.*[\n\"\\\\]?") {
             let result = sanitize_label_value(&input);
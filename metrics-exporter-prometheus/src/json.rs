@@ -0,0 +1,89 @@
+//! Serialization of snapshots to JSON, for debugging.
+
+use std::collections::BTreeMap;
+
+use quanta::Instant;
+use serde_json::{json, Map, Value};
+
+use crate::common::Snapshot;
+use crate::distribution::Distribution;
+use crate::formatting::parse_label;
+
+/// Serializes the given snapshot to a JSON object.
+///
+/// Each metric family maps to an array of its series, and the labels of each series are given as
+/// an object of label keys and unescaped values.  Values which JSON cannot represent, such as
+/// infinities and NaN, are serialized as `null`.  The `+Inf` bucket of histograms is omitted, as
+/// its count is always equal to the count of the series.  Summaries are snapshotted as of `now`.
+pub(crate) fn snapshot_to_json(snapshot: &Snapshot, now: Instant) -> String {
+    let counters = families(&snapshot.counters, |value| json!({ "value": value }));
+    let gauges = families(&snapshot.gauges, |value| json!({ "value": value }));
+    let distributions = families(&snapshot.distributions, |distribution| match distribution {
+        Distribution::Histogram(histogram) => {
+            let buckets = histogram
+                .buckets()
+                .into_iter()
+                .filter(|(le, _)| *le != f64::INFINITY)
+                .map(|(le, count)| json!({ "le": le, "count": count }))
+                .collect::<Vec<_>>();
+            json!({
+                "type": "histogram",
+                "buckets": buckets,
+                "sum": histogram.sum(),
+                "count": histogram.count(),
+            })
+        }
+        Distribution::Summary(summary, quantiles, sum) => {
+            let snapshot = summary.snapshot(now);
+            let quantiles = quantiles
+                .iter()
+                .map(|quantile| {
+                    let value = snapshot.quantile(quantile.value()).unwrap_or(0.0);
+                    json!({ "quantile": quantile.value(), "value": value })
+                })
+                .collect::<Vec<_>>();
+            json!({
+                "type": "summary",
+                "quantiles": quantiles,
+                "sum": sum,
+                "count": summary.count(),
+            })
+        }
+    });
+
+    json!({ "counters": counters, "gauges": gauges, "distributions": distributions }).to_string()
+}
+
+/// Serializes metric families, using the given function to serialize the value of each series.
+fn families<V, F>(families: &BTreeMap<String, BTreeMap<Vec<String>, V>>, f: F) -> Value
+where
+    F: Fn(&V) -> Value,
+{
+    let families = families
+        .iter()
+        .map(|(name, by_labels)| {
+            let series = by_labels
+                .iter()
+                .map(|(labels, value)| {
+                    let mut series = f(value);
+                    series["labels"] = labels_to_json(labels);
+                    series
+                })
+                .collect();
+            (name.clone(), Value::Array(series))
+        })
+        .collect::<Map<_, _>>();
+    Value::Object(families)
+}
+
+/// Converts labels formatted as `key="value"` to an object of label keys and unescaped values.
+fn labels_to_json(labels: &[String]) -> Value {
+    let labels = labels
+        .iter()
+        .filter_map(|label| {
+            let (key, value) = parse_label(label)?;
+            Some((key.to_owned(), Value::String(value)))
+        })
+        .collect::<Map<_, _>>();
+    Value::Object(labels)
+}
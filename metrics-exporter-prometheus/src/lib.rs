@@ -95,6 +95,15 @@
 //! to create or build an exporter, at least one of these feature flags must be enabled.  Builder
 //! methods that require certain feature flags will be documented as such.
 //!
+//! Additionally, the **`process-metrics`** feature flag allows the recorder to report standard
+//! metrics about the current process, via
+//! [`PrometheusBuilder::with_process_metrics`][crate::PrometheusBuilder::with_process_metrics],
+//! and the **`remote-write`** feature flag allows serializing metrics as a Prometheus remote write
+//! request, via
+//! [`PrometheusHandle::remote_write_payload`][crate::PrometheusHandle::remote_write_payload].
+//! The **`testing`** feature flag enables the [`testing`][crate::testing] module, with helpers for
+//! parsing rendered output in tests.
+//!
 //! [metrics]: https://docs.rs/metrics/latest/metrics/
 //! [data model]: https://prometheus.io/docs/concepts/data_model/
 //! [exposition format]: https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
@@ -102,13 +111,25 @@
 #![deny(clippy::pedantic)]
 #![allow(clippy::must_use_candidate)]
 #![allow(clippy::module_name_repetitions)]
+// The builder, and the recorder and encoder it configures, hold many independent on/off settings.
+#![allow(clippy::struct_excessive_bools)]
 #![deny(missing_docs)]
 #![cfg_attr(docsrs, feature(doc_cfg), deny(rustdoc::broken_intra_doc_links))]
+mod buckets;
+pub use self::buckets::{exponential_buckets, linear_buckets};
+
 mod common;
-pub use self::common::{BuildError, Matcher};
+pub use self::common::{
+    BuildError, DescriptionCollisionPolicy, Exemplar, KindCollisionPolicy, Matcher, Snapshot,
+};
 
 mod distribution;
-pub use distribution::{Distribution, DistributionBuilder};
+pub use distribution::{Distribution, DistributionBuilder, DistributionKind, RollingSummary};
+
+mod encoder;
+pub use self::encoder::{
+    Encoder, PrometheusTextEncoder, OPENMETRICS_CONTENT_TYPE, PROMETHEUS_CONTENT_TYPE,
+};
 
 mod exporter;
 pub use self::exporter::builder::PrometheusBuilder;
@@ -119,6 +140,25 @@ pub use self::exporter::ExporterFuture;
 pub mod formatting;
 mod recorder;
 
+#[cfg(feature = "json")]
+mod json;
+
+mod multi;
+pub use self::multi::MultiHandle;
+
+#[cfg(feature = "process-metrics")]
+mod process;
+
+#[cfg(feature = "remote-write")]
+mod remote_write;
+
+#[cfg(feature = "testing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "testing")))]
+pub mod testing;
+
 mod registry;
 
+mod timer;
+pub use self::timer::TimerGuard;
+
 pub use self::recorder::{PrometheusHandle, PrometheusRecorder};
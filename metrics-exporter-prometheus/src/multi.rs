@@ -0,0 +1,86 @@
+//! Rendering the metrics of multiple recorders through a single handle.
+
+use crate::PrometheusHandle;
+
+/// Handle for rendering the metrics of multiple [`PrometheusRecorder`][crate::PrometheusRecorder]s
+/// as a single payload.
+///
+/// This allows isolated subsystems to each have their own recorder, while exposing their metrics
+/// through a single scrape endpoint.  The metrics of each recorder are merged, such that each
+/// metric family is only described once, even if several recorders hold metrics under the same
+/// name.  When a metric name is registered as a different kind of metric by different recorders,
+/// or the same series is present in several recorders, the recorder given first takes precedence,
+/// and the collision is logged.
+///
+/// The payload is rendered as configured for the first recorder, so settings such as
+/// [`PrometheusBuilder::counter_total_suffix`][crate::PrometheusBuilder::counter_total_suffix] of
+/// the other recorders are not applied.
+#[derive(Clone, Debug, Default)]
+pub struct MultiHandle {
+    handles: Vec<PrometheusHandle>,
+}
+
+impl MultiHandle {
+    /// Creates a new `MultiHandle` from the given handles.
+    pub fn new(handles: Vec<PrometheusHandle>) -> Self {
+        Self { handles }
+    }
+
+    /// Adds a handle, whose metrics are rendered after those of any existing handles.
+    pub fn push(&mut self, handle: PrometheusHandle) {
+        self.handles.push(handle);
+    }
+
+    /// Takes a snapshot of the metrics held by each recorder and generates a single payload
+    /// conforming to the Prometheus exposition format.
+    pub fn render(&self) -> String {
+        let mut handles = self.handles.iter();
+        let Some(first) = handles.next() else { return String::new() };
+
+        let mut snapshot = first.snapshot();
+        for handle in handles {
+            snapshot.merge(handle.snapshot());
+        }
+
+        first.render_snapshot(&snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, KeyName, Label, Recorder, SharedString};
+
+    use super::MultiHandle;
+    use crate::PrometheusBuilder;
+
+    static METADATA: metrics::Metadata =
+        metrics::Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
+
+    #[test]
+    fn test_render_merges_recorders() {
+        let first = PrometheusBuilder::new().build_recorder();
+        let second = PrometheusBuilder::new().build_recorder();
+
+        for (recorder, subsystem) in [(&first, "auth"), (&second, "billing")] {
+            let description = SharedString::const_str("Total requests.");
+            recorder.describe_counter(KeyName::from_const_str("requests"), None, description);
+            let key = Key::from_parts("requests", vec![Label::new("subsystem", subsystem)]);
+            recorder.register_counter(&key, &METADATA).increment(1);
+        }
+        first.register_counter(&Key::from_name("errors"), &METADATA).increment(1);
+        second.register_gauge(&Key::from_name("errors"), &METADATA).set(1.0);
+
+        let handle = MultiHandle::new(vec![first.handle(), second.handle()]);
+        let expected = concat!(
+            "# TYPE errors counter\n",
+            "errors 1\n\n",
+            "# HELP requests Total requests.\n",
+            "# TYPE requests counter\n",
+            "requests{subsystem=\"auth\"} 1\n",
+            "requests{subsystem=\"billing\"} 1\n\n",
+        );
+        assert_eq!(handle.render(), expected);
+
+        assert_eq!(MultiHandle::default().render(), "");
+    }
+}
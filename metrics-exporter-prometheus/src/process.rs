@@ -0,0 +1,82 @@
+//! Collection of standard metrics about the current process.
+
+/// Metrics about the current process, as read from the operating system.
+#[derive(Debug)]
+pub(crate) struct ProcessMetrics {
+    /// Resident memory size, in bytes.
+    pub resident_memory_bytes: u64,
+    /// Total user and system CPU time spent, in seconds.
+    pub cpu_seconds: f64,
+    /// Number of open file descriptors.
+    pub open_fds: u64,
+}
+
+/// Collects metrics about the current process.
+///
+/// Returns `None` if the metrics could not be read, or on platforms other than Linux, where
+/// collection is not supported.
+#[cfg(target_os = "linux")]
+pub(crate) fn collect() -> Option<ProcessMetrics> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let open_fds = std::fs::read_dir("/proc/self/fd").ok()?.count();
+
+    Some(ProcessMetrics {
+        resident_memory_bytes: parse_resident_memory_bytes(&status)?,
+        cpu_seconds: parse_cpu_seconds(&stat)?,
+        open_fds: open_fds as u64,
+    })
+}
+
+/// Collects metrics about the current process.
+///
+/// Returns `None` if the metrics could not be read, or on platforms other than Linux, where
+/// collection is not supported.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn collect() -> Option<ProcessMetrics> {
+    None
+}
+
+/// Parses the total CPU time from the contents of `/proc/[pid]/stat`.
+#[cfg(any(test, target_os = "linux"))]
+#[allow(clippy::cast_precision_loss)]
+fn parse_cpu_seconds(stat: &str) -> Option<f64> {
+    // CPU times are reported in clock ticks, of which there are a fixed 100 per second as far as
+    // userspace is concerned, which is also what the Go client library assumes.
+    const TICKS_PER_SECOND: f64 = 100.0;
+
+    // The command name is in parentheses and may itself contain spaces or parentheses, so the
+    // remaining fields start after the last closing parenthesis, with the state as field 3.
+    let (_, fields) = stat.rsplit_once(')')?;
+    let mut fields = fields.split_whitespace().skip(11);
+    let user_ticks = fields.next()?.parse::<u64>().ok()?;
+    let system_ticks = fields.next()?.parse::<u64>().ok()?;
+
+    Some((user_ticks + system_ticks) as f64 / TICKS_PER_SECOND)
+}
+
+/// Parses the resident memory size from the contents of `/proc/[pid]/status`.
+#[cfg(any(test, target_os = "linux"))]
+fn parse_resident_memory_bytes(status: &str) -> Option<u64> {
+    let line = status.lines().find_map(|line| line.strip_prefix("VmRSS:"))?;
+    let kilobytes = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+
+    Some(kilobytes * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_cpu_seconds, parse_resident_memory_bytes};
+
+    #[test]
+    fn test_parse_proc_files() {
+        let stat = "4242 (my (odd) app) S 1 4242 4242 0 -1 4194560 1234 0 0 0 1250 370 0 0 20 0 \
+                    8 0 123456 987654321 2048 18446744073709551615";
+        assert_eq!(parse_cpu_seconds(stat), Some(16.2));
+        assert_eq!(parse_cpu_seconds("4242 (truncated) S 1"), None);
+
+        let status = "Name:\tapp\nVmPeak:\t  20480 kB\nVmRSS:\t   8192 kB\nThreads:\t8\n";
+        assert_eq!(parse_resident_memory_bytes(status), Some(8 * 1024 * 1024));
+        assert_eq!(parse_resident_memory_bytes("Name:\tapp\n"), None);
+    }
+}
@@ -1,212 +1,1048 @@
-use std::collections::HashMap;
-use std::sync::atomic::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::{PoisonError, RwLock};
+#[cfg(any(feature = "http-listener", feature = "push-gateway"))]
+use std::sync::Weak;
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::time::{Duration, SystemTime};
 
 use indexmap::IndexMap;
-use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
-use metrics_util::registry::{Recency, Registry};
-use quanta::Instant;
+use metrics::{
+    Counter, Gauge, Histogram, Key, KeyName, Label, Metadata, Recorder, SharedString, Unit,
+};
+use metrics_util::registry::{Generational, Recency, Registry};
+use metrics_util::MetricKind;
+use quanta::{Clock, Instant};
 
-use crate::common::Snapshot;
-use crate::distribution::{Distribution, DistributionBuilder};
+use crate::common::{
+    BuildError, DescriptionCollisionPolicy, Exemplar, KindCollisionPolicy, Matcher, NameRewriter,
+    NameSanitizer, Snapshot,
+};
+use crate::distribution::{Distribution, DistributionBuilder, DistributionKind};
+use crate::encoder::{bucket_index, Encoder, Format, PrometheusTextEncoder};
 use crate::formatting::{
-    key_to_parts, sanitize_metric_name, write_help_line, write_metric_line, write_type_line,
+    key_to_parts_with, sanitize_label_key_with, sanitize_label_value, sanitize_metric_name_with,
+    sanitize_strict_with, truncate_label_value, with_namespace,
 };
-use crate::registry::GenerationalAtomicStorage;
+use crate::registry::{AtomicBucketInstant, GenerationalAtomicStorage};
+use crate::timer::TimerGuard;
+
+/// Exemplars of a histogram, by labels.
+type ExemplarsByLabels = HashMap<Vec<String>, Vec<Exemplar>>;
+
+/// The value a counter series was last rendered with, for checking that it doesn't go backwards.
+#[derive(Debug, Default)]
+pub(crate) struct ExportedCounter {
+    value: u64,
+    /// When the series was first missing from a render, if it is currently missing.
+    missing_since: Option<Instant>,
+}
 
 #[derive(Debug)]
 pub(crate) struct Inner {
     pub registry: Registry<Key, GenerationalAtomicStorage>,
     pub recency: Recency<Key>,
     pub distributions: RwLock<HashMap<String, IndexMap<Vec<String>, Distribution>>>,
+    pub drain_lock: Mutex<()>,
     pub distribution_builder: DistributionBuilder,
     pub descriptions: RwLock<HashMap<String, SharedString>>,
+    pub units: RwLock<HashMap<String, Unit>>,
+    pub exemplars: RwLock<HashMap<String, ExemplarsByLabels>>,
+    pub kinds: RwLock<HashMap<String, MetricKind>>,
+    pub logged_kind_collisions: Mutex<HashSet<String>>,
+    pub kind_collision_policy: KindCollisionPolicy,
+    pub monotonic_counters: bool,
+    pub delta_counters: bool,
+    pub counter_baselines: RwLock<BTreeMap<String, BTreeMap<Vec<String>, u64>>>,
+    pub log_on_anomaly: bool,
+    pub self_metrics: bool,
+    pub keep_registered_families: bool,
+    #[cfg(feature = "process-metrics")]
+    pub process_metrics: bool,
+    pub exported_counters: RwLock<BTreeMap<String, BTreeMap<Vec<String>, ExportedCounter>>>,
+    pub exported_counter_retention: Option<Duration>,
     pub global_labels: IndexMap<String, String>,
+    pub dropped_labels: Vec<String>,
+    pub sort_labels: bool,
+    pub drop_empty_labels: bool,
+    pub max_label_value_len: Option<usize>,
+    pub counter_total_suffix: bool,
+    pub strict_names: bool,
+    pub lowercase_names: bool,
+    pub lowercase_label_keys: bool,
+    pub name_sanitizer: Option<NameSanitizer>,
+    pub name_rewriter: Option<NameRewriter>,
+    pub sanitize_replacement: char,
+    pub namespace: Option<String>,
+    pub overwrite_descriptions: bool,
+    pub description_collision_policy: DescriptionCollisionPolicy,
+    pub created: RwLock<HashMap<(MetricKind, Key), SystemTime>>,
+    pub created_timestamps: bool,
+    pub timestamps: bool,
+    pub compact_output: bool,
+    pub emit_metadata: bool,
+    pub append_unit_to_help: bool,
+    pub aggregate_histograms: bool,
+    pub count_out_of_bounds: bool,
+    pub cardinality_limit: Option<usize>,
+    pub series_count: AtomicUsize,
+    pub denied_metrics: Vec<Matcher>,
+    pub scrape_count: AtomicU64,
+    pub render_size_estimate: AtomicUsize,
+    pub last_scrape: RwLock<Option<Instant>>,
+    pub clock: Clock,
 }
 
 impl Inner {
+    /// Sanitizes and rewrites the given metric name, prefixing it with the namespace, if any.
+    pub(crate) fn sanitize_metric_name(&self, name: &str) -> String {
+        let name = match &self.name_sanitizer {
+            Some(sanitizer) => sanitizer.sanitize(name),
+            None if self.strict_names => sanitize_strict_with(name, self.sanitize_replacement),
+            None => sanitize_metric_name_with(name, self.sanitize_replacement),
+        };
+        let mut name = match &self.name_rewriter {
+            Some(rewriter) => rewriter.rewrite(name),
+            None => name,
+        };
+        if self.lowercase_names {
+            name.make_ascii_lowercase();
+        }
+
+        with_namespace(self.namespace.as_deref(), name)
+    }
+
+    /// Sanitizes the given label key.
+    fn sanitize_label_key(&self, key: &str) -> String {
+        let mut key = if self.strict_names {
+            sanitize_strict_with(key, self.sanitize_replacement)
+        } else {
+            sanitize_label_key_with(key, self.sanitize_replacement)
+        };
+        if self.lowercase_label_keys {
+            key.make_ascii_lowercase();
+        }
+        key
+    }
+
+    /// Breaks a key into the name and label components, including any global labels.
+    fn key_to_parts(&self, key: &Key) -> (String, Vec<String>) {
+        let (dropped_labels, drop_empty_labels) = (&self.dropped_labels, self.drop_empty_labels);
+        let retain = |key: &str, value: &str| {
+            !(drop_empty_labels && value.is_empty() || dropped_labels.iter().any(|k| k == key))
+        };
+        let sanitize_name = |name: &str| self.sanitize_metric_name(name);
+        let sanitize_key = |key: &str| self.sanitize_label_key(key);
+        let sanitize_value = |value: &str| {
+            let value = sanitize_label_value(value);
+            match self.max_label_value_len {
+                Some(max_len) => truncate_label_value(value, max_len),
+                None => value,
+            }
+        };
+        key_to_parts_with(
+            key,
+            Some(&self.global_labels),
+            retain,
+            self.sort_labels,
+            sanitize_name,
+            sanitize_key,
+            sanitize_value,
+        )
+    }
+
+    /// Records the kind of metric the given key was registered as, if its name is not yet known.
+    ///
+    /// Collisions with another kind are logged once per name, if they should be.
+    fn register_kind(&self, key: &Key, kind: MetricKind) {
+        let name = self.sanitize_metric_name(key.name());
+        let existing =
+            self.kinds.read().unwrap_or_else(PoisonError::into_inner).get(&name).copied();
+        let existing = existing.unwrap_or_else(|| {
+            let mut kinds = self.kinds.write().unwrap_or_else(PoisonError::into_inner);
+            *kinds.entry(name.clone()).or_insert(kind)
+        });
+
+        if existing != kind && self.kind_collision_policy == KindCollisionPolicy::Warn {
+            let mut logged =
+                self.logged_kind_collisions.lock().unwrap_or_else(PoisonError::into_inner);
+            if logged.insert(name.clone()) {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(
+                    name,
+                    registered = ?existing,
+                    requested = ?kind,
+                    "Metric name registered as more than one kind of metric; output will be invalid."
+                );
+            }
+        }
+    }
+
+    /// Resolves the name that metrics of the given kind are rendered under, according to the kind
+    /// collision policy.
+    ///
+    /// Returns `None` if the metrics should not be rendered at all.
+    fn resolve_name(
+        &self,
+        kinds: &HashMap<String, MetricKind>,
+        name: String,
+        kind: MetricKind,
+    ) -> Option<String> {
+        match kinds.get(&name) {
+            Some(existing) if *existing != kind => match self.kind_collision_policy {
+                KindCollisionPolicy::Warn => Some(name),
+                KindCollisionPolicy::Drop => None,
+                KindCollisionPolicy::Rename => {
+                    let suffix = match kind {
+                        MetricKind::Counter => "counter",
+                        MetricKind::Gauge => "gauge",
+                        MetricKind::Histogram => "histogram",
+                    };
+                    Some(format!("{name}_{suffix}"))
+                }
+            },
+            _ => Some(name),
+        }
+    }
+
+    /// Takes a snapshot of the metrics for a scrape, that is a render of the full payload.
+    ///
+    /// Unlike [`get_recent_metrics`][Self::get_recent_metrics], this renders counters as deltas,
+    /// if configured to, which is why only scrapes may take it: any other snapshot would otherwise
+    /// consume the increase that the next scrape should report.
+    fn get_scrape_metrics(&self) -> Snapshot {
+        let mut snapshot = self.get_recent_metrics();
+        if self.delta_counters {
+            self.subtract_counter_baselines(&mut snapshot.counters);
+        }
+        snapshot
+    }
+
     fn get_recent_metrics(&self) -> Snapshot {
-        let mut counters = HashMap::new();
+        #[cfg(feature = "process-metrics")]
+        if self.process_metrics {
+            self.record_process_metrics();
+        }
+
+        let kinds = self.kinds.read().unwrap_or_else(PoisonError::into_inner).clone();
+
+        let mut created_times = HashMap::new();
+        let mut counters = BTreeMap::new();
         let counter_handles = self.registry.get_counter_handles();
         for (key, counter) in counter_handles {
             let gen = counter.get_generation();
             if !self.recency.should_store_counter(&key, gen, &self.registry) {
+                self.forget_created(MetricKind::Counter, &key);
+                self.release_series(1);
                 continue;
             }
 
-            let (name, labels) = key_to_parts(&key, Some(&self.global_labels));
+            let (name, labels) = self.key_to_parts(&key);
+            let Some(name) = self.resolve_name(&kinds, name, MetricKind::Counter) else {
+                continue;
+            };
+            if let Some(created) = self.created_time(MetricKind::Counter, &key) {
+                add_created(&mut created_times, &name, &labels, created);
+            }
             let value = counter.get_inner().load(Ordering::Acquire);
+            // Series can collapse into one if labels were dropped, or if their names or labels are
+            // the same once sanitized, in which case they're summed.
             let entry =
-                counters.entry(name).or_insert_with(HashMap::new).entry(labels).or_insert(0);
-            *entry = value;
+                counters.entry(name).or_insert_with(BTreeMap::new).entry(labels).or_insert(0u64);
+            *entry = entry.saturating_add(value);
         }
 
-        let mut gauges = HashMap::new();
-        let gauge_handles = self.registry.get_gauge_handles();
+        if self.monotonic_counters || self.log_on_anomaly {
+            self.check_counters(&mut counters);
+        }
+        let mut gauges = BTreeMap::new();
+        // Gauges which collapse into one series can't be summed, so the last one wins: visit them in
+        // order, such that which one that is doesn't depend on the iteration order of the registry.
+        let mut gauge_handles = self.registry.get_gauge_handles().into_iter().collect::<Vec<_>>();
+        gauge_handles.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
         for (key, gauge) in gauge_handles {
             let gen = gauge.get_generation();
             if !self.recency.should_store_gauge(&key, gen, &self.registry) {
+                self.release_series(1);
                 continue;
             }
 
-            let (name, labels) = key_to_parts(&key, Some(&self.global_labels));
+            let (name, labels) = self.key_to_parts(&key);
+            let Some(name) = self.resolve_name(&kinds, name, MetricKind::Gauge) else {
+                continue;
+            };
             let value = f64::from_bits(gauge.get_inner().load(Ordering::Acquire));
+            if self.log_on_anomaly && value.is_nan() {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(name, ?labels, "Gauge has a value of NaN.");
+            }
             let entry =
-                gauges.entry(name).or_insert_with(HashMap::new).entry(labels).or_insert(0.0);
+                gauges.entry(name).or_insert_with(BTreeMap::new).entry(labels).or_insert(0.0);
             *entry = value;
         }
 
         // Update distributions
         self.drain_histograms_to_distributions();
         // Remove expired histograms
-        let histogram_handles = self.registry.get_histogram_handles();
-        for (key, histogram) in histogram_handles {
-            let gen = histogram.get_generation();
-            if !self.recency.should_store_histogram(&key, gen, &self.registry) {
-                // Since we store aggregated distributions directly, when we're told that a metric
-                // is not recent enough and should be/was deleted from the registry, we also need to
-                // delete it on our side as well.
-                let (name, labels) = key_to_parts(&key, Some(&self.global_labels));
-                let mut wg = self.distributions.write().unwrap_or_else(PoisonError::into_inner);
-                let delete_by_name = if let Some(by_name) = wg.get_mut(&name) {
-                    by_name.swap_remove(&labels);
-                    by_name.is_empty()
-                } else {
-                    false
-                };
-
-                // If there's no more variants in the per-metric-name distribution map, then delete
-                // it entirely, otherwise we end up with weird empty output during render.
-                if delete_by_name {
-                    wg.remove(&name);
+        self.remove_idle_histograms();
+
+        let mut distributions = self
+            .distributions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter_map(|(name, by_labels)| {
+                let name = self.resolve_name(&kinds, name.clone(), MetricKind::Histogram)?;
+                let by_labels = by_labels
+                    .iter()
+                    .map(|(labels, distribution)| (labels.clone(), distribution.clone()))
+                    .collect();
+                Some((name, by_labels))
+            })
+            .collect();
+        if self.aggregate_histograms {
+            self.add_aggregate_histograms(&mut distributions);
+        }
+        if self.created_timestamps {
+            self.add_created_histograms(&kinds, &distributions, &mut created_times);
+        }
+
+        let exemplars = self
+            .exemplars
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter_map(|(name, by_labels)| {
+                let name = self.resolve_name(&kinds, name.clone(), MetricKind::Histogram)?;
+                Some((name, by_labels.clone()))
+            })
+            .collect();
+
+        let descriptions = self.descriptions.read().unwrap_or_else(PoisonError::into_inner).clone();
+        let units = self.units.read().unwrap_or_else(PoisonError::into_inner).clone();
+
+        let mut empty_distributions = BTreeMap::new();
+        if self.keep_registered_families {
+            for (name, kind) in kinds {
+                match kind {
+                    MetricKind::Counter => {
+                        counters.entry(name).or_default();
+                    }
+                    MetricKind::Gauge => {
+                        gauges.entry(name).or_default();
+                    }
+                    MetricKind::Histogram if !distributions.contains_key(&name) => {
+                        let kind = self.distribution_builder.kind_for(&name);
+                        empty_distributions.insert(name, kind);
+                    }
+                    MetricKind::Histogram => {}
                 }
+            }
+        }
+
+        Snapshot {
+            counters,
+            gauges,
+            distributions,
+            empty_distributions,
+            descriptions,
+            units,
+            exemplars,
+            created: created_times,
+        }
+    }
+
+    /// Records the current time as the creation time of the given series, if creation times are
+    /// tracked and it has none yet.
+    fn record_created(&self, kind: MetricKind, key: &Key) {
+        if !self.created_timestamps || self.created_time(kind, key).is_some() {
+            return;
+        }
+
+        let mut wg = self.created.write().unwrap_or_else(PoisonError::into_inner);
+        wg.entry((kind, key.clone())).or_insert_with(SystemTime::now);
+    }
+
+    /// Gets the creation time of the given series, if it is tracked.
+    fn created_time(&self, kind: MetricKind, key: &Key) -> Option<SystemTime> {
+        if !self.created_timestamps {
+            return None;
+        }
+
+        let created = self.created.read().unwrap_or_else(PoisonError::into_inner);
+        created.get(&(kind, key.clone())).copied()
+    }
+
+    /// Forgets the creation time of the given series, such that it gets a new one if it is ever
+    /// registered again.
+    fn forget_created(&self, kind: MetricKind, key: &Key) {
+        if self.created_timestamps {
+            let mut wg = self.created.write().unwrap_or_else(PoisonError::into_inner);
+            wg.remove(&(kind, key.clone()));
+        }
+    }
+
+    /// Adds the creation times of histogram and summary series which are being rendered.
+    fn add_created_histograms(
+        &self,
+        kinds: &HashMap<String, MetricKind>,
+        distributions: &BTreeMap<String, BTreeMap<Vec<String>, Distribution>>,
+        created_times: &mut HashMap<String, HashMap<Vec<String>, SystemTime>>,
+    ) {
+        let created = self.created.read().unwrap_or_else(PoisonError::into_inner);
+        for ((kind, key), created) in created.iter() {
+            if *kind != MetricKind::Histogram {
+                continue;
+            }
 
+            let (name, labels) = self.key_to_parts(key);
+            let Some(name) = self.resolve_name(kinds, name, MetricKind::Histogram) else {
                 continue;
+            };
+            if distributions.get(&name).map_or(false, |by_labels| by_labels.contains_key(&labels)) {
+                add_created(created_times, &name, &labels, *created);
             }
         }
+    }
 
-        let distributions =
-            self.distributions.read().unwrap_or_else(PoisonError::into_inner).clone();
+    /// Adds a series to each histogram family, labeled only with the global labels, whose buckets,
+    /// sum, and count are the sums of those of every series in the family.
+    ///
+    /// Summaries are skipped, as quantiles cannot be meaningfully summed, as are families which
+    /// already have a series labeled only with the global labels.
+    fn add_aggregate_histograms(
+        &self,
+        distributions: &mut BTreeMap<String, BTreeMap<Vec<String>, Distribution>>,
+    ) {
+        // Breaking down a key without any labels of its own leaves just the global labels.
+        let (_, aggregate_labels) = self.key_to_parts(&Key::from_static_name(""));
+        for by_labels in distributions.values_mut() {
+            if by_labels.contains_key(&aggregate_labels) {
+                continue;
+            }
 
-        Snapshot { counters, gauges, distributions }
+            let mut histograms = by_labels.values().map(|distribution| match distribution {
+                Distribution::Histogram(histogram) => Some(histogram),
+                Distribution::Summary(..) => None,
+            });
+            let Some(Some(first)) = histograms.next() else { continue };
+            let mut aggregate = first.clone();
+            if histograms.all(|histogram| histogram.map_or(false, |h| aggregate.merge(h))) {
+                by_labels.insert(aggregate_labels.clone(), Distribution::Histogram(aggregate));
+            }
+        }
     }
 
-    /// Drains histogram samples into distribution.
-    fn drain_histograms_to_distributions(&self) {
-        let histogram_handles = self.registry.get_histogram_handles();
-        for (key, histogram) in histogram_handles {
-            let (name, labels) = key_to_parts(&key, Some(&self.global_labels));
+    /// Creates the distribution for the given histogram key, if it doesn't exist yet.
+    ///
+    /// This allows histograms to be rendered as soon as they're registered, rather than once the
+    /// first sample is recorded.  It's only needed when the histogram is created in the registry,
+    /// as the distribution is removed along with it.
+    fn register_distribution(&self, key: &Key) {
+        let (name, labels) = self.key_to_parts(key);
+        let exists = self
+            .distributions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&name)
+            .map_or(false, |by_labels| by_labels.contains_key(&labels));
+        if exists {
+            return;
+        }
 
-            let mut wg = self.distributions.write().unwrap_or_else(PoisonError::into_inner);
-            let entry = wg
-                .entry(name.clone())
-                .or_default()
-                .entry(labels)
-                .or_insert_with(|| self.distribution_builder.get_distribution(name.as_str()));
+        let mut wg = self.distributions.write().unwrap_or_else(PoisonError::into_inner);
+        wg.entry(name.clone())
+            .or_default()
+            .entry(labels)
+            .or_insert_with(|| self.distribution_builder.get_distribution(name.as_str()));
+    }
+
+    /// Sets the series of the given histogram key to the given, already bucketed, histogram.
+    ///
+    /// The series is held in the registry like any registered histogram, such that it counts towards
+    /// the cardinality limit, and setting it counts as an update for the idle timeout.
+    fn set_histogram(&self, key: &Key, histogram: metrics_util::Histogram) {
+        let Some(series) = self.get_or_admit_histogram(key) else { return };
+        series.with_increment(|_| ());
+        let (name, labels) = self.key_to_parts(key);
 
-            histogram.get_inner().clear_with(|samples| entry.record_samples(samples));
+        let mut wg = self.distributions.write().unwrap_or_else(PoisonError::into_inner);
+        wg.entry(name).or_default().insert(labels, Distribution::Histogram(histogram));
+    }
+
+    /// Attaches an exemplar to the given histogram key, replacing any existing exemplar in the bucket
+    /// that the value falls into.
+    ///
+    /// Exemplars can only be attached to histogram buckets, so nothing is done if the key is
+    /// configured as a summary, or if the histogram is not registered.
+    fn add_exemplar(&self, key: &Key, value: f64, labels: &[(&str, &str)]) {
+        let (name, series) = self.key_to_parts(key);
+        let DistributionKind::Histogram(bounds) = self.distribution_builder.kind_for(&name) else {
+            return;
+        };
+        // The histogram itself may have been rejected by the cardinality limit.
+        if self.registry.get_histogram(key).is_none() {
+            return;
         }
+
+        let bucket = bucket_index(&bounds, value);
+        let labels = self.format_labels(labels).into_iter().map(|(_, label)| label).collect();
+        let exemplar = Exemplar { labels, value, timestamp: SystemTime::now() };
+
+        let mut wg = self.exemplars.write().unwrap_or_else(PoisonError::into_inner);
+        let exemplars = wg.entry(name).or_default().entry(series).or_default();
+        exemplars.retain(|existing| bucket_index(&bounds, existing.value) != bucket);
+        exemplars.push(exemplar);
     }
 
-    fn render(&self) -> String {
-        let Snapshot { mut counters, mut distributions, mut gauges } = self.get_recent_metrics();
+    /// Decides whether the given series, which doesn't exist yet, may be created, counting it
+    /// towards the cardinality limit if so.
+    ///
+    /// This is called while the registry is locked for creating the series, so series rejected by
+    /// the limit are only flagged in `dropped`, to be counted once the registry is unlocked.
+    fn admit_series(&self, key: &Key, dropped: &mut bool) -> bool {
+        if self.is_denied(key.name()) {
+            return false;
+        }
 
-        let mut output = String::new();
-        let descriptions = self.descriptions.read().unwrap_or_else(PoisonError::into_inner);
+        let Some(limit) = self.cardinality_limit else {
+            self.series_count.fetch_add(1, Ordering::Relaxed);
+            return true;
+        };
+        let admitted = self
+            .series_count
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < limit).then_some(count + 1)
+            })
+            .is_ok();
+        *dropped = !admitted;
+        admitted
+    }
 
-        for (name, mut by_labels) in counters.drain() {
-            if let Some(desc) = descriptions.get(name.as_str()) {
-                write_help_line(&mut output, name.as_str(), desc);
-            }
+    /// Counts a series rejected by the cardinality limit with the `prometheus_dropped_series_total`
+    /// counter.
+    fn count_dropped_series(&self) {
+        static DROPPED: Key = Key::from_static_name("prometheus_dropped_series_total");
+
+        self.own_counter(&DROPPED).increment(1);
+    }
+
+    /// Gets the counter with the given key, creating it if necessary, for metrics reported by the
+    /// recorder itself, which are exempt from the denylist and the cardinality limit.
+    fn own_counter(&self, key: &Key) -> Counter {
+        let mut created = false;
+        let create = || {
+            created = true;
+            self.series_count.fetch_add(1, Ordering::Relaxed);
+            true
+        };
+        let counter = self.registry.get_or_try_create_counter(key, create, Clone::clone);
+        if created {
+            self.register_kind(key, MetricKind::Counter);
+        }
+        counter.map_or_else(Counter::noop, Counter::from)
+    }
+
+    /// Gets the gauge with the given key, creating it if necessary, for metrics reported by the
+    /// recorder itself, which are exempt from the denylist and the cardinality limit.
+    fn own_gauge(&self, key: &Key) -> Gauge {
+        let mut created = false;
+        let create = || {
+            created = true;
+            self.series_count.fetch_add(1, Ordering::Relaxed);
+            true
+        };
+        let gauge = self.registry.get_or_try_create_gauge(key, create, Clone::clone);
+        if created {
+            self.register_kind(key, MetricKind::Gauge);
+        }
+        gauge.map_or_else(Gauge::noop, Gauge::from)
+    }
+
+    /// Forgets the given number of series, which were removed from the registry, such that they no
+    /// longer count towards the cardinality limit.
+    fn release_series(&self, removed: usize) {
+        if removed > 0 {
+            self.series_count.fetch_sub(removed, Ordering::Relaxed);
+        }
+    }
+
+    /// Checks whether the metric with the given name is denied from being exported.
+    fn is_denied(&self, name: &str) -> bool {
+        if self.denied_metrics.is_empty() {
+            return false;
+        }
+
+        let name = self.sanitize_metric_name(name);
+        self.denied_metrics.iter().any(|matcher| matcher.matches(&name))
+    }
+
+    fn register_counter(&self, key: &Key) -> Counter {
+        let (mut created, mut dropped) = (false, false);
+        let create = || {
+            created = self.admit_series(key, &mut dropped);
+            created
+        };
+        let counter = self.registry.get_or_try_create_counter(key, create, Clone::clone);
+        if dropped {
+            self.count_dropped_series();
+        }
+        let Some(counter) = counter else { return Counter::noop() };
+        if created {
+            self.register_kind(key, MetricKind::Counter);
+            self.record_created(MetricKind::Counter, key);
+        }
+        counter.into()
+    }
+
+    fn register_gauge(&self, key: &Key) -> Gauge {
+        let (mut created, mut dropped) = (false, false);
+        let create = || {
+            created = self.admit_series(key, &mut dropped);
+            created
+        };
+        let gauge = self.registry.get_or_try_create_gauge(key, create, Clone::clone);
+        if dropped {
+            self.count_dropped_series();
+        }
+        let Some(gauge) = gauge else { return Gauge::noop() };
+        if created {
+            self.register_kind(key, MetricKind::Gauge);
+        }
+        gauge.into()
+    }
+
+    /// Gets the histogram with the given key from the registry, creating it if it's admitted.
+    fn get_or_admit_histogram(
+        &self,
+        key: &Key,
+    ) -> Option<Generational<Arc<AtomicBucketInstant<f64>>>> {
+        let (mut created, mut dropped) = (false, false);
+        let create = || {
+            created = self.admit_series(key, &mut dropped);
+            created
+        };
+        let histogram = self.registry.get_or_try_create_histogram(key, create, Clone::clone);
+        if dropped {
+            self.count_dropped_series();
+        }
+        if created {
+            self.register_kind(key, MetricKind::Histogram);
+            self.record_created(MetricKind::Histogram, key);
+            self.register_distribution(key);
+        }
+        histogram
+    }
 
-            write_type_line(&mut output, name.as_str(), "counter");
-            for (labels, value) in by_labels.drain() {
-                write_metric_line::<&str, u64>(&mut output, &name, None, &labels, None, value);
+    fn register_histogram(&self, key: &Key) -> Histogram {
+        let Some(histogram) = self.get_or_admit_histogram(key) else { return Histogram::noop() };
+        if self.count_out_of_bounds {
+            histogram.get_inner().count_out_of_bounds_with(|| self.out_of_bounds_counter(key));
+        }
+        histogram.into()
+    }
+
+    /// Gets the highest bucket of the given histogram, along with the counter of the samples
+    /// exceeding it, if the histogram has any finite buckets.
+    fn out_of_bounds_counter(&self, key: &Key) -> Option<(f64, Counter)> {
+        let (name, _) = self.key_to_parts(key);
+        let DistributionKind::Histogram(bounds) = self.distribution_builder.kind_for(&name) else {
+            return None;
+        };
+        let max = bounds.iter().copied().filter(|bound| bound.is_finite()).last()?;
+        let out_of_bounds_key = Key::from_parts(
+            format!("{}_out_of_bounds_total", key.name()),
+            key.labels().cloned().collect::<Vec<_>>(),
+        );
+        Some((max, self.register_counter(&out_of_bounds_key)))
+    }
+
+    /// Checks counters against the values they were previously rendered with.
+    ///
+    /// Counters which went backwards are logged, if anomalies should be logged, and are rendered
+    /// with their previous value instead, if counters should be monotonic.
+    fn check_counters(&self, counters: &mut BTreeMap<String, BTreeMap<Vec<String>, u64>>) {
+        let mut exported = self.exported_counters.write().unwrap_or_else(PoisonError::into_inner);
+        for (name, by_labels) in counters.iter_mut() {
+            let exported = exported.entry(name.clone()).or_default();
+            for (labels, value) in by_labels.iter_mut() {
+                let previous = exported.entry(labels.clone()).or_default();
+                previous.missing_since = None;
+                if *value < previous.value {
+                    if self.log_on_anomaly {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(
+                            name,
+                            ?labels,
+                            previous = previous.value,
+                            current = *value,
+                            "Counter went backwards."
+                        );
+                    }
+                    if self.monotonic_counters {
+                        *value = previous.value;
+                    }
+                }
+                previous.value = *value;
             }
-            output.push('\n');
         }
 
-        for (name, mut by_labels) in gauges.drain() {
-            if let Some(desc) = descriptions.get(name.as_str()) {
-                write_help_line(&mut output, name.as_str(), desc);
+        self.prune_exported_counters(&mut exported, counters);
+    }
+
+    /// Forgets the previously rendered values of counter series which have been missing from
+    /// renders for longer than the retention, as well as, beyond the cardinality limit, of those
+    /// which have been missing the longest.
+    fn prune_exported_counters(
+        &self,
+        exported: &mut BTreeMap<String, BTreeMap<Vec<String>, ExportedCounter>>,
+        counters: &BTreeMap<String, BTreeMap<Vec<String>, u64>>,
+    ) {
+        let now = self.clock.now();
+        let retention = self.exported_counter_retention;
+        for (name, by_labels) in exported.iter_mut() {
+            let rendered = counters.get(name);
+            by_labels.retain(|labels, counter| {
+                if rendered.map_or(false, |rendered| rendered.contains_key(labels)) {
+                    return true;
+                }
+                let missing_since = *counter.missing_since.get_or_insert(now);
+                retention.map_or(true, |retention| now.duration_since(missing_since) <= retention)
+            });
+        }
+        exported.retain(|_, by_labels| !by_labels.is_empty());
+
+        let Some(limit) = self.cardinality_limit else { return };
+        let total = exported.values().map(BTreeMap::len).sum::<usize>();
+        if total <= limit {
+            return;
+        }
+        let mut missing = exported
+            .iter()
+            .flat_map(|(name, by_labels)| {
+                by_labels.iter().filter_map(move |(labels, counter)| {
+                    counter.missing_since.map(|since| (since, name.clone(), labels.clone()))
+                })
+            })
+            .collect::<Vec<_>>();
+        missing.sort_by(|(a, ..), (b, ..)| a.cmp(b));
+        for (_, name, labels) in missing.into_iter().take(total - limit) {
+            if let Some(by_labels) = exported.get_mut(&name) {
+                by_labels.remove(&labels);
             }
+        }
+        exported.retain(|_, by_labels| !by_labels.is_empty());
+    }
 
-            write_type_line(&mut output, name.as_str(), "gauge");
-            for (labels, value) in by_labels.drain() {
-                write_metric_line::<&str, f64>(&mut output, &name, None, &labels, None, value);
+    /// Replaces the value of each counter by its increase since it was last scraped, remembering
+    /// its current value for the next scrape.
+    fn subtract_counter_baselines(
+        &self,
+        counters: &mut BTreeMap<String, BTreeMap<Vec<String>, u64>>,
+    ) {
+        let mut baselines = self.counter_baselines.write().unwrap_or_else(PoisonError::into_inner);
+        // Only the counters rendered now are kept, so that those which went away don't linger.
+        let previous = std::mem::replace(&mut *baselines, counters.clone());
+        for (name, by_labels) in counters.iter_mut() {
+            let Some(previous) = previous.get(name) else { continue };
+            for (labels, value) in by_labels.iter_mut() {
+                // A counter lower than its baseline started over, so all of it is new.
+                match previous.get(labels) {
+                    Some(baseline) if *baseline <= *value => *value -= *baseline,
+                    _ => {}
+                }
             }
-            output.push('\n');
         }
+    }
+
+    /// Removes any histograms which have not been updated recently enough, along with their
+    /// distributions.
+    fn remove_idle_histograms(&self) {
+        let idle = self
+            .registry
+            .get_histogram_handles()
+            .into_iter()
+            .filter(|(key, histogram)| {
+                let gen = histogram.get_generation();
+                !self.recency.should_store_histogram(key, gen, &self.registry)
+            })
+            .map(|(key, _)| {
+                self.forget_created(MetricKind::Histogram, &key);
+                self.key_to_parts(&key)
+            })
+            .collect::<Vec<_>>();
+
+        if idle.is_empty() {
+            return;
+        }
+        self.release_series(idle.len());
 
-        for (name, mut by_labels) in distributions.drain() {
-            if let Some(desc) = descriptions.get(name.as_str()) {
-                write_help_line(&mut output, name.as_str(), desc);
+        // Since we store aggregated distributions directly, when we're told that a metric is not
+        // recent enough and should be/was deleted from the registry, we also need to delete it on
+        // our side as well.
+        let mut wg = self.distributions.write().unwrap_or_else(PoisonError::into_inner);
+        let mut exemplars = self.exemplars.write().unwrap_or_else(PoisonError::into_inner);
+        for (name, labels) in idle {
+            if let Some(by_labels) = exemplars.get_mut(&name) {
+                by_labels.remove(&labels);
+                if by_labels.is_empty() {
+                    exemplars.remove(&name);
+                }
             }
 
-            let distribution_type = self.distribution_builder.get_distribution_type(name.as_str());
-            write_type_line(&mut output, name.as_str(), distribution_type);
-            for (labels, distribution) in by_labels.drain(..) {
-                let (sum, count) = match distribution {
-                    Distribution::Summary(summary, quantiles, sum) => {
-                        let snapshot = summary.snapshot(Instant::now());
-                        for quantile in quantiles.iter() {
-                            let value = snapshot.quantile(quantile.value()).unwrap_or(0.0);
-                            write_metric_line(
-                                &mut output,
-                                &name,
-                                None,
-                                &labels,
-                                Some(("quantile", quantile.value())),
-                                value,
-                            );
-                        }
+            let delete_by_name = if let Some(by_name) = wg.get_mut(&name) {
+                by_name.swap_remove(&labels);
+                by_name.is_empty()
+            } else {
+                false
+            };
 
-                        (sum, summary.count() as u64)
-                    }
-                    Distribution::Histogram(histogram) => {
-                        for (le, count) in histogram.buckets() {
-                            write_metric_line(
-                                &mut output,
-                                &name,
-                                Some("bucket"),
-                                &labels,
-                                Some(("le", le)),
-                                count,
-                            );
-                        }
-                        write_metric_line(
-                            &mut output,
-                            &name,
-                            Some("bucket"),
-                            &labels,
-                            Some(("le", "+Inf")),
-                            histogram.count(),
-                        );
+            // If there's no more variants in the per-metric-name distribution map, then delete it
+            // entirely, otherwise we end up with weird empty output during render.
+            if delete_by_name {
+                wg.remove(&name);
+            }
+        }
+    }
 
-                        (histogram.sum(), histogram.count())
-                    }
-                };
-
-                write_metric_line::<&str, f64>(&mut output, &name, Some("sum"), &labels, None, sum);
-                write_metric_line::<&str, u64>(
-                    &mut output,
-                    &name,
-                    Some("count"),
-                    &labels,
-                    None,
-                    count,
-                );
+    /// Removes any metrics which have not been updated recently enough, without rendering them.
+    fn remove_idle_metrics(&self) {
+        for (key, counter) in self.registry.get_counter_handles() {
+            if !self.recency.should_store_counter(&key, counter.get_generation(), &self.registry) {
+                self.forget_created(MetricKind::Counter, &key);
+                self.release_series(1);
             }
+        }
 
-            output.push('\n');
+        for (key, gauge) in self.registry.get_gauge_handles() {
+            if !self.recency.should_store_gauge(&key, gauge.get_generation(), &self.registry) {
+                self.release_series(1);
+            }
+        }
+
+        self.remove_idle_histograms();
+    }
+
+    /// Drains histogram samples into distribution.
+    fn drain_histograms_to_distributions(&self) {
+        // Draining is serialized, so that samples drained by one caller are merged into the
+        // distributions before another caller drains, and then renders without them.  This also
+        // keeps samples merged in the order they were recorded, which summaries rely on.
+        let _drain = self.drain_lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Drain the samples before taking the write lock, so that it's only held while aggregating
+        // them, and only taken once per drain rather than once per histogram.
+        let mut drained = Vec::new();
+        for (key, histogram) in self.registry.get_histogram_handles() {
+            let mut samples = Vec::new();
+            histogram.get_inner().clear_with(|chunk| samples.extend_from_slice(chunk));
+            drained.push((key, samples));
+        }
+
+        if drained.is_empty() {
+            return;
+        }
+
+        let drained = drained
+            .into_iter()
+            .map(|(key, samples)| (self.key_to_parts(&key), samples))
+            .collect::<Vec<_>>();
+
+        let mut wg = self.distributions.write().unwrap_or_else(PoisonError::into_inner);
+        for ((name, labels), samples) in drained {
+            let entry = wg
+                .entry(name.clone())
+                .or_default()
+                .entry(labels)
+                .or_insert_with(|| self.distribution_builder.get_distribution(name.as_str()));
+
+            entry.record_samples(&samples);
+        }
+    }
+
+    fn render(&self, format: Format) -> String {
+        self.render_with_capacity(format, None)
+    }
+
+    /// Renders the full payload into a buffer of the given capacity, or, without one, of the
+    /// estimated size based on previous renders.
+    fn render_with_capacity(&self, format: Format, capacity: Option<usize>) -> String {
+        let start = self.clock.now();
+        self.scrape_count.fetch_add(1, Ordering::Relaxed);
+        *self.last_scrape.write().unwrap_or_else(PoisonError::into_inner) = Some(start);
+
+        let capacity =
+            capacity.unwrap_or_else(|| self.render_size_estimate.load(Ordering::Relaxed));
+        let mut output = String::with_capacity(capacity);
+        self.encoder(format)
+            .encode(&self.get_scrape_metrics(), &mut output)
+            .expect("writing to a String should never fail");
+        self.update_render_size_estimate(output.len());
+        if self.self_metrics {
+            self.record_render(self.clock.now().duration_since(start), output.len());
+        }
+        output
+    }
+
+    /// Folds the size of a render into the rolling estimate used to size subsequent renders.
+    ///
+    /// Concurrent renders may race here, which is harmless, as the estimate is only a hint.
+    fn update_render_size_estimate(&self, size: usize) {
+        let previous = self.render_size_estimate.load(Ordering::Relaxed);
+        let estimate = if previous == 0 { size } else { previous - previous / 4 + size / 4 };
+        self.render_size_estimate.store(estimate, Ordering::Relaxed);
+    }
+
+    /// Records the duration and size of a render as metrics of the recorder itself.
+    #[allow(clippy::cast_precision_loss)]
+    fn record_render(&self, duration: Duration, size: usize) {
+        static DURATION: Key = Key::from_static_name("prometheus_render_duration_seconds");
+        static SIZE: Key = Key::from_static_name("prometheus_render_size_bytes");
+
+        for (key, value) in [(&DURATION, duration.as_secs_f64()), (&SIZE, size as f64)] {
+            self.own_gauge(key).set(value);
+        }
+    }
+
+    /// Records standard metrics about the current process, if they can be collected.
+    #[cfg(feature = "process-metrics")]
+    fn record_process_metrics(&self) {
+        static RESIDENT_MEMORY: Key = Key::from_static_name("process_resident_memory_bytes");
+        static CPU: Key = Key::from_static_name("process_cpu_seconds_total");
+        static OPEN_FDS: Key = Key::from_static_name("process_open_fds");
+
+        let Some(metrics) = crate::process::collect() else { return };
+
+        #[allow(clippy::cast_precision_loss)]
+        let gauges = [
+            (&CPU, metrics.cpu_seconds),
+            (&RESIDENT_MEMORY, metrics.resident_memory_bytes as f64),
+            (&OPEN_FDS, metrics.open_fds as f64),
+        ];
+        for (key, value) in gauges {
+            self.own_gauge(key).set(value);
         }
+    }
 
+    fn render_snapshot(&self, format: Format, snapshot: &Snapshot) -> String {
+        let mut output = String::new();
+        self.encoder(format)
+            .encode(snapshot, &mut output)
+            .expect("writing to a String should never fail");
         output
     }
 
+    fn render_to<W>(&self, format: Format, output: &mut W) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        self.encoder(format).encode(&self.get_scrape_metrics(), output)
+    }
+
+    /// Sanitizes and formats the given labels, as pairs of label key and formatted label.
+    fn format_labels(&self, labels: &[(&str, &str)]) -> Vec<(String, String)> {
+        labels
+            .iter()
+            .map(|(key, value)| {
+                let key = self.sanitize_label_key(key);
+                let label = format!("{key}=\"{}\"", sanitize_label_value(value));
+                (key, label)
+            })
+            .collect()
+    }
+
+    /// Creates the encoder used to render the given format, as configured for this recorder.
+    fn encoder(&self, format: Format) -> PrometheusTextEncoder {
+        PrometheusTextEncoder::with_format(format)
+            .with_clock(self.clock.clone())
+            .counter_total_suffix(self.counter_total_suffix)
+            .with_timestamps(self.timestamps)
+            .compact_output(self.compact_output)
+            .emit_metadata(self.emit_metadata)
+            .append_unit_to_help(self.append_unit_to_help)
+    }
+
     fn run_upkeep(&self) {
         self.drain_histograms_to_distributions();
+        self.remove_idle_metrics();
+    }
+
+    /// Removes every series of the metric with the given sanitized name, along with its metadata.
+    fn remove_metric(&self, name: &str) {
+        let retain = |key: &Key| self.sanitize_metric_name(key.name()) != name;
+        self.retain_series(retain);
+        self.created
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(_, key), _| retain(key));
+
+        self.distributions.write().unwrap_or_else(PoisonError::into_inner).remove(name);
+        self.exemplars.write().unwrap_or_else(PoisonError::into_inner).remove(name);
+        self.descriptions.write().unwrap_or_else(PoisonError::into_inner).remove(name);
+        self.units.write().unwrap_or_else(PoisonError::into_inner).remove(name);
+        self.kinds.write().unwrap_or_else(PoisonError::into_inner).remove(name);
+        self.logged_kind_collisions.lock().unwrap_or_else(PoisonError::into_inner).remove(name);
+        self.exported_counters.write().unwrap_or_else(PoisonError::into_inner).remove(name);
+        self.counter_baselines.write().unwrap_or_else(PoisonError::into_inner).remove(name);
+    }
+
+    /// Removes every series from the registry for which `retain` returns `false`.
+    fn retain_series(&self, retain: impl Fn(&Key) -> bool) {
+        let mut removed = 0;
+        let mut retain = |key: &Key| {
+            let retained = retain(key);
+            removed += usize::from(!retained);
+            retained
+        };
+        self.registry.retain_counters(|key, _| retain(key));
+        self.registry.retain_gauges(|key, _| retain(key));
+        self.registry.retain_histograms(|key, _| retain(key));
+        self.release_series(removed);
+    }
+
+    fn clear(&self) {
+        // Series are removed one by one, rather than by clearing the registry, so that the count of
+        // series stays in line with those registered concurrently.
+        self.retain_series(|_| false);
+        self.distributions.write().unwrap_or_else(PoisonError::into_inner).clear();
+        self.exemplars.write().unwrap_or_else(PoisonError::into_inner).clear();
+        self.created.write().unwrap_or_else(PoisonError::into_inner).clear();
+        self.kinds.write().unwrap_or_else(PoisonError::into_inner).clear();
+        self.logged_kind_collisions.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        self.exported_counters.write().unwrap_or_else(PoisonError::into_inner).clear();
+        self.counter_baselines.write().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+/// Adds the creation time of a series, keeping the earliest one if several series collapse into it.
+fn add_created(
+    created_times: &mut HashMap<String, HashMap<Vec<String>, SystemTime>>,
+    name: &str,
+    labels: &[String],
+    created: SystemTime,
+) {
+    let by_labels = created_times.entry(name.to_owned()).or_default();
+    let entry = by_labels.entry(labels.to_vec()).or_insert(created);
+    *entry = (*entry).min(created);
+}
+
+/// Adapts an [`io::Write`] to [`fmt::Write`], holding on to the underlying I/O error, if any.
+struct IoWriter<'a, W: ?Sized> {
+    inner: &'a mut W,
+    error: Option<io::Error>,
+}
+
+impl<W> fmt::Write for IoWriter<'_, W>
+where
+    W: io::Write + ?Sized,
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.inner.write_all(s.as_bytes()).map_err(|e| {
+            self.error = Some(e);
+            fmt::Error
+        })
     }
 }
 
@@ -226,14 +1062,75 @@ impl PrometheusRecorder {
         PrometheusHandle { inner: self.inner.clone() }
     }
 
-    fn add_description_if_missing(&self, key_name: &KeyName, description: SharedString) {
-        let sanitized = sanitize_metric_name(key_name.as_str());
+    fn add_description(&self, key_name: &KeyName, unit: Option<Unit>, description: SharedString) {
+        let sanitized = self.inner.sanitize_metric_name(key_name.as_str());
+        if self.inner.denied_metrics.iter().any(|matcher| matcher.matches(&sanitized)) {
+            return;
+        }
+        let overwrite = self.inner.overwrite_descriptions;
+        let concatenate = !overwrite
+            && self.inner.description_collision_policy == DescriptionCollisionPolicy::Concatenate;
+
+        // Metrics are typically described over and over with the same description, so check under
+        // a read lock first, so that only the first description takes the write lock.
+        if !overwrite {
+            let has_unit = unit.is_none()
+                || self
+                    .inner
+                    .units
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .contains_key(&sanitized);
+            let has_description = self
+                .inner
+                .descriptions
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(&sanitized)
+                .map_or(false, |existing| !concatenate || has_description(existing, &description));
+            if has_unit && has_description {
+                return;
+            }
+        }
+
+        if let Some(unit) = unit {
+            let mut units = self.inner.units.write().unwrap_or_else(PoisonError::into_inner);
+            if overwrite {
+                units.insert(sanitized.clone(), unit);
+            } else {
+                units.entry(sanitized.clone()).or_insert(unit);
+            }
+        }
+
         let mut descriptions =
             self.inner.descriptions.write().unwrap_or_else(PoisonError::into_inner);
-        descriptions.entry(sanitized).or_insert(description);
+        if overwrite {
+            descriptions.insert(sanitized, description);
+        } else if concatenate {
+            match descriptions.get_mut(&sanitized) {
+                Some(existing) if !has_description(existing, &description) => {
+                    *existing = format!("{existing}; {description}").into();
+                }
+                Some(_) => {}
+                None => {
+                    descriptions.insert(sanitized, description);
+                }
+            }
+        } else {
+            descriptions.entry(sanitized).or_insert(description);
+        }
     }
 }
 
+/// Checks whether the given description is already part of a description concatenated by
+/// [`DescriptionCollisionPolicy::Concatenate`].
+fn has_description(existing: &str, description: &str) -> bool {
+    existing == description
+        || existing.starts_with(&format!("{description}; "))
+        || existing.ends_with(&format!("; {description}"))
+        || existing.contains(&format!("; {description}; "))
+}
+
 impl From<Inner> for PrometheusRecorder {
     fn from(inner: Inner) -> Self {
         PrometheusRecorder { inner: Arc::new(inner) }
@@ -241,33 +1138,28 @@ impl From<Inner> for PrometheusRecorder {
 }
 
 impl Recorder for PrometheusRecorder {
-    fn describe_counter(&self, key_name: KeyName, _unit: Option<Unit>, description: SharedString) {
-        self.add_description_if_missing(&key_name, description);
+    fn describe_counter(&self, key_name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.add_description(&key_name, unit, description);
     }
 
-    fn describe_gauge(&self, key_name: KeyName, _unit: Option<Unit>, description: SharedString) {
-        self.add_description_if_missing(&key_name, description);
+    fn describe_gauge(&self, key_name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.add_description(&key_name, unit, description);
     }
 
-    fn describe_histogram(
-        &self,
-        key_name: KeyName,
-        _unit: Option<Unit>,
-        description: SharedString,
-    ) {
-        self.add_description_if_missing(&key_name, description);
+    fn describe_histogram(&self, key_name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.add_description(&key_name, unit, description);
     }
 
     fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
-        self.inner.registry.get_or_create_counter(key, |c| c.clone().into())
+        self.inner.register_counter(key)
     }
 
     fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
-        self.inner.registry.get_or_create_gauge(key, |c| c.clone().into())
+        self.inner.register_gauge(key)
     }
 
     fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
-        self.inner.registry.get_or_create_histogram(key, |c| c.clone().into())
+        self.inner.register_histogram(key)
     }
 }
 
@@ -285,13 +1177,646 @@ pub struct PrometheusHandle {
 impl PrometheusHandle {
     /// Takes a snapshot of the metrics held by the recorder and generates a payload conforming to
     /// the Prometheus exposition format.
+    ///
+    /// Metrics whose names and labels are the same once sanitized, such as `foo.bar` and `foo-bar`,
+    /// are rendered as a single series: counters are summed, gauges take the value of the metric
+    /// whose original key sorts last, and histogram samples are merged into a single distribution.
     pub fn render(&self) -> String {
-        self.inner.render()
+        self.inner.render(Format::Prometheus)
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload conforming to
+    /// the Prometheus exposition format, into a buffer pre-allocated with the given capacity.
+    ///
+    /// The output is the same as that of [`render`][Self::render], which already sizes its buffer
+    /// from a rolling estimate of previous renders, so this is only useful when the size of the
+    /// payload is known better than that, such as for the first render of a large export.
+    pub fn render_to_string_capacity(&self, capacity: usize) -> String {
+        self.inner.render_with_capacity(Format::Prometheus, Some(capacity))
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload conforming to
+    /// the Prometheus exposition format, with precise control over how it ends.
+    ///
+    /// The output of [`render`][Self::render] ends with the blank line which separates metric
+    /// families.  Here, that is trimmed, such that the output ends with exactly one newline if
+    /// `trailing_newline` is `true`, or with none at all otherwise, which is useful when embedding
+    /// the output in templates.  If there are no metrics, the output is empty either way.
+    pub fn render_trimmed(&self, trailing_newline: bool) -> String {
+        let mut output = self.inner.render(Format::Prometheus);
+        output.truncate(output.trim_end_matches('\n').len());
+        if trailing_newline && !output.is_empty() {
+            output.push('\n');
+        }
+        output
+    }
+
+    /// Renders the given snapshot as configured for this recorder, in the Prometheus exposition
+    /// format.
+    pub(crate) fn render_snapshot(&self, snapshot: &Snapshot) -> String {
+        self.inner.render_snapshot(Format::Prometheus, snapshot)
+    }
+
+    /// Gets the time at which the metrics were last rendered, if ever.
+    ///
+    /// This tracks renders of the full payload, such as via [`render`][Self::render], or by the HTTP
+    /// listener or push gateway, which makes it useful for checking whether scrapes are happening.
+    /// The time is read from the clock the recorder was built with, as set by
+    /// [`PrometheusBuilder::with_clock`][crate::PrometheusBuilder::with_clock].
+    pub fn last_scrape(&self) -> Option<Instant> {
+        *self.inner.last_scrape.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Gets the number of times the metrics have been rendered.
+    ///
+    /// This counts the same renders as [`last_scrape`][Self::last_scrape].
+    pub fn scrape_count(&self) -> u64 {
+        self.inner.scrape_count.load(Ordering::Relaxed)
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder.
+    ///
+    /// This performs the same collection as rendering, including draining histogram samples and
+    /// removing idle metrics, but returns the raw data so that it can be exported in a custom format.
+    ///
+    /// ```
+    /// # use metrics_exporter_prometheus::PrometheusBuilder;
+    /// let recorder = PrometheusBuilder::new().build_recorder();
+    /// let handle = recorder.handle();
+    /// metrics::with_local_recorder(&recorder, || {
+    ///     metrics::counter!("requests", "method" => "GET").increment(3);
+    /// });
+    ///
+    /// let snapshot = handle.snapshot();
+    /// for (name, by_labels) in &snapshot.counters {
+    ///     for (labels, value) in by_labels {
+    ///         println!("{name}{{{}}} = {value}", labels.join(","));
+    ///     }
+    /// }
+    /// assert_eq!(snapshot.counters["requests"][&vec![r#"method="GET""#.to_owned()]], 3);
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        self.inner.get_recent_metrics()
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload with the given
+    /// encoder.
+    ///
+    /// Unlike [`render`][Self::render], the encoder is used as-is, so settings such as
+    /// [`PrometheusBuilder::counter_total_suffix`][crate::PrometheusBuilder::counter_total_suffix]
+    /// are not applied.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the encoder fails to write the payload, which only happens if the encoder itself
+    /// returns an error.
+    pub fn render_with<E>(&self, encoder: &E) -> String
+    where
+        E: Encoder + ?Sized,
+    {
+        let snapshot = self.inner.get_recent_metrics();
+        let mut output = String::new();
+        encoder.encode(&snapshot, &mut output).expect("encoder failed to write payload");
+        output
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload conforming to
+    /// the Prometheus exposition format, including only the metrics whose name starts with `prefix`.
+    ///
+    /// The prefix is matched against the sanitized metric name, as it would appear in the output of
+    /// [`render`][Self::render].
+    pub fn render_filtered(&self, prefix: &str) -> String {
+        let mut snapshot = self.inner.get_recent_metrics();
+        snapshot.retain_names(|name| name.starts_with(prefix));
+        self.inner.render_snapshot(Format::Prometheus, &snapshot)
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload conforming to
+    /// the Prometheus exposition format, adding the given labels to every series.
+    ///
+    /// This is useful for labels whose values can change between scrapes, such as the role of an
+    /// instance in a cluster, which is not possible with
+    /// [`add_global_label`][crate::PrometheusBuilder::add_global_label].  The labels only apply to
+    /// this render, and labels already present on a series, including global labels, take
+    /// precedence over them.
+    pub fn render_with_labels(&self, extra: &[(&str, &str)]) -> String {
+        let mut snapshot = self.inner.get_recent_metrics();
+        snapshot.add_labels(&self.inner.format_labels(extra), self.inner.sort_labels);
+        self.inner.render_snapshot(Format::Prometheus, &snapshot)
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload conforming to
+    /// the Prometheus exposition format, including only the metric family with the given name.
+    ///
+    /// The name is sanitized in the same way as metric names, so it can be given as it was when
+    /// registering the metric.  The output is identical to that metric family's block in the output
+    /// of [`render`][Self::render].  Returns `None` if there is no metric with the given name.
+    pub fn render_family(&self, name: &str) -> Option<String> {
+        let name = self.inner.sanitize_metric_name(name);
+        let mut snapshot = self.inner.get_recent_metrics();
+        snapshot.retain_names(|candidate| candidate == name);
+        if snapshot.is_empty() {
+            return None;
+        }
+
+        Some(self.inner.render_snapshot(Format::Prometheus, &snapshot))
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload conforming to
+    /// the Prometheus exposition format, one metric family at a time.
+    ///
+    /// Each chunk is self-contained, holding the metadata and samples of a single metric family,
+    /// and the chunks concatenated are identical to the output of [`render`][Self::render].  This
+    /// allows streaming the payload of very large registries, such as over HTTP, without buffering
+    /// all of it first.  The snapshot is taken up front, but each chunk is only rendered once it is
+    /// requested.  Like [`render`][Self::render], this counts as a scrape for
+    /// [delta counters][crate::PrometheusBuilder::delta_counters].
+    pub fn render_chunks(&self) -> impl Iterator<Item = String> {
+        let mut snapshot = self.inner.get_scrape_metrics();
+        let inner = Arc::clone(&self.inner);
+        std::iter::from_fn(move || {
+            let family = snapshot.pop_family()?;
+            Some(inner.render_snapshot(Format::Prometheus, &family))
+        })
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and writes a payload conforming to the
+    /// Prometheus exposition format to the given writer.
+    ///
+    /// The output is identical to [`render`][Self::render], but is written out incrementally rather
+    /// than being collected into a single `String` first.  As many small writes are performed, the
+    /// writer should generally be buffered.
+    ///
+    /// ## Errors
+    ///
+    /// If writing to `writer` fails, the underlying I/O error is returned.
+    pub fn render_to_writer<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: io::Write + ?Sized,
+    {
+        let mut adapter = IoWriter { inner: writer, error: None };
+        self.inner.render_to(Format::Prometheus, &mut adapter).map_err(|_| {
+            adapter
+                .error
+                .take()
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "formatter error"))
+        })
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a gzip-compressed payload
+    /// conforming to the Prometheus exposition format.
+    ///
+    /// The payload decompresses to the same output as [`render`][Self::render].  When serving this
+    /// payload over HTTP, the `Content-Encoding` header should be set to `gzip`.
+    ///
+    /// ## Panics
+    ///
+    /// Compression happens entirely in memory, so this method should never panic in practice.
+    #[cfg(feature = "compression")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "compression")))]
+    pub fn render_gzip(&self) -> Vec<u8> {
+        use flate2::{write::GzEncoder, Compression};
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        self.render_to_writer(&mut encoder).expect("writing to a Vec should never fail");
+        encoder.finish().expect("writing to a Vec should never fail")
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload conforming to
+    /// the Prometheus exposition format, as [`Bytes`][bytes::Bytes].
+    ///
+    /// The payload is rendered directly into the buffer, avoiding the copy of converting the output
+    /// of [`render`][Self::render] when serving it over HTTP.
+    ///
+    /// ## Panics
+    ///
+    /// Rendering happens entirely in memory, so this method should never panic in practice.
+    #[cfg(feature = "bytes")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "bytes")))]
+    pub fn render_bytes(&self) -> bytes::Bytes {
+        let mut output = bytes::BytesMut::new();
+        self.inner
+            .render_to(Format::Prometheus, &mut output)
+            .expect("writing to a BytesMut should never fail");
+        output.freeze()
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and serializes it to JSON.
+    ///
+    /// This is meant for debugging endpoints and tooling, rather than for Prometheus.  The object
+    /// holds `counters`, `gauges`, and `distributions`, each mapping metric names to an array of
+    /// series, where every series has its `labels` as an object, along with its `value`, or the
+    /// `buckets` or `quantiles`, `sum`, and `count` of distributions.
+    #[cfg(feature = "json")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+    pub fn render_json(&self) -> String {
+        crate::json::snapshot_to_json(&self.inner.get_recent_metrics(), self.inner.clock.now())
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and serializes it as a Prometheus
+    /// [remote write] request, for pushing directly to endpoints such as Cortex or Mimir.
+    ///
+    /// The payload is a snappy-compressed `WriteRequest` protobuf message, to be sent with the
+    /// `Content-Encoding: snappy` and `Content-Type: application/x-protobuf` headers.  Every sample
+    /// is timestamped with the current time, and histograms and summaries are broken down into the
+    /// same series as in the Prometheus exposition format.
+    ///
+    /// [remote write]: https://prometheus.io/docs/concepts/remote_write_spec/
+    #[cfg(feature = "remote-write")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "remote-write")))]
+    pub fn remote_write_payload(&self) -> Vec<u8> {
+        let snapshot = self.inner.get_recent_metrics();
+        let counter_total_suffix = self.inner.counter_total_suffix;
+        let (now, summary_time) = (SystemTime::now(), self.inner.clock.now());
+        crate::remote_write::snapshot_to_payload(&snapshot, counter_total_suffix, now, summary_time)
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload conforming to
+    /// the [OpenMetrics] text format.
+    ///
+    /// Counters are always rendered with the `_total` suffix, and the payload is terminated with
+    /// `# EOF`.  Units given when describing a metric are emitted as `# UNIT` lines, but only for
+    /// metrics whose name ends with the unit, i.e. `request_latency_seconds` for
+    /// [`Unit::Seconds`], as required by the specification.  When serving this payload over HTTP,
+    /// the `Content-Type` header should be set to
+    /// [`OPENMETRICS_CONTENT_TYPE`][crate::OPENMETRICS_CONTENT_TYPE].
+    ///
+    /// [OpenMetrics]: https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+    pub fn render_openmetrics(&self) -> String {
+        self.inner.render(Format::OpenMetrics)
+    }
+
+    /// Takes a snapshot of the metrics held by the recorder and generates a payload in the format
+    /// requested by the given HTTP `Accept` header.
+    ///
+    /// The payload is rendered as with [`render_openmetrics`][Self::render_openmetrics] if the
+    /// header accepts `application/openmetrics-text`, and as with [`render`][Self::render]
+    /// otherwise.  Returns the payload along with the value to use for the `Content-Type` header,
+    /// which is either [`OPENMETRICS_CONTENT_TYPE`][crate::OPENMETRICS_CONTENT_TYPE] or
+    /// [`PROMETHEUS_CONTENT_TYPE`][crate::PROMETHEUS_CONTENT_TYPE].
+    pub fn render_negotiated(&self, accept: &str) -> (String, &'static str) {
+        let format = Format::negotiate(accept);
+        (self.inner.render(format), format.content_type())
+    }
+
+    /// Starts timing, returning a guard which records the elapsed time to the histogram with the
+    /// given name and labels when dropped.
+    ///
+    /// The histogram is registered directly with the recorder this handle belongs to, regardless of
+    /// whether or not that recorder is installed globally.
+    pub fn time_histogram<N>(&self, name: N, labels: &[(&str, &str)]) -> TimerGuard
+    where
+        N: Into<KeyName>,
+    {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| Label::new((*key).to_owned(), (*value).to_owned()))
+            .collect::<Vec<_>>();
+        let key = Key::from_parts(name, labels);
+        TimerGuard::new(self.inner.register_histogram(&key), self.inner.clock.clone())
+    }
+
+    /// Increments the gauge with the given key by `delta`, registering it if necessary.
+    ///
+    /// The gauge is adjusted atomically, in the same way as via the `metrics` facade, so concurrent
+    /// increments and decrements are never lost.
+    pub fn increment_gauge(&self, key: &Key, delta: f64) {
+        self.inner.register_gauge(key).increment(delta);
+    }
+
+    /// Decrements the gauge with the given key by `delta`, registering it if necessary.
+    ///
+    /// The gauge is adjusted atomically, in the same way as via the `metrics` facade, so concurrent
+    /// increments and decrements are never lost.
+    pub fn decrement_gauge(&self, key: &Key, delta: f64) {
+        self.inner.register_gauge(key).decrement(delta);
+    }
+
+    /// Records a sample to the histogram with the given key, attaching an exemplar with the given
+    /// labels to it, such as the ID of the trace the sample was recorded in.
+    ///
+    /// Each histogram bucket holds the most recent exemplar recorded within it.  Exemplars are only
+    /// rendered in the `OpenMetrics` text format, and only for histograms: if the key is configured
+    /// as a summary, the sample is recorded without the exemplar.  `OpenMetrics` limits the combined
+    /// length of exemplar label keys and values to 128 characters, which is not enforced here.
+    pub fn record_histogram_with_exemplar(&self, key: &Key, value: f64, labels: &[(&str, &str)]) {
+        self.inner.register_histogram(key).record(value);
+        self.inner.add_exemplar(key, value, labels);
+    }
+
+    /// Sets the histogram with the given name and labels to already bucketed data, such as data
+    /// federated from another system, without recording individual samples.
+    ///
+    /// `buckets` are tuples of bucket limit and cumulative count, and are rendered in ascending
+    /// order of their limit, with the `+Inf` bucket always rendered from `count`.  Any existing
+    /// data for the series is replaced, including samples recorded through a registered histogram
+    /// with the same name and labels.  Series set this way are otherwise treated as registered
+    /// histograms: they count towards the cardinality limit, being dropped if it has been reached,
+    /// and are removed by the idle timeout unless set again in time.
+    ///
+    /// ## Errors
+    ///
+    /// If any bucket limit is NaN, or is given more than once, [`BuildError::InvalidBuckets`] is
+    /// returned.  If the counts are not cumulative, that is, if any count is lower than that of a
+    /// bucket with a smaller limit, or higher than `count`, [`BuildError::NonCumulativeBuckets`] is
+    /// returned.  The series is left as-is in either case.
+    pub fn set_histogram_buckets<N>(
+        &self,
+        name: N,
+        labels: &[(&str, &str)],
+        buckets: &[(f64, u64)],
+        sum: f64,
+        count: u64,
+    ) -> Result<(), BuildError>
+    where
+        N: Into<KeyName>,
+    {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| Label::new((*key).to_owned(), (*value).to_owned()))
+            .collect::<Vec<_>>();
+        let key = Key::from_parts(name, labels);
+        // Without any buckets, only the `+Inf` bucket is rendered.
+        let mut sorted =
+            if buckets.is_empty() { vec![(f64::INFINITY, count)] } else { buckets.to_vec() };
+        sorted.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+
+        // Once sorted, a limit which isn't lower than the next one is a duplicate.
+        let duplicate = sorted.windows(2).any(|pair| pair[0].0 >= pair[1].0);
+        if duplicate || sorted.iter().any(|(le, _)| le.is_nan()) {
+            return Err(BuildError::InvalidBuckets(buckets.iter().map(|(le, _)| *le).collect()));
+        }
+        let decreasing = sorted.windows(2).any(|pair| pair[0].1 > pair[1].1);
+        if decreasing || sorted.last().map_or(false, |(_, last)| *last > count) {
+            return Err(BuildError::NonCumulativeBuckets(buckets.to_vec()));
+        }
+
+        let Some(histogram) = metrics_util::Histogram::from_buckets(&sorted, sum, count) else {
+            return Err(BuildError::EmptyBucketsOrQuantiles);
+        };
+        self.inner.set_histogram(&key, histogram);
+        Ok(())
+    }
+
+    /// Gets the unit given when describing the metric with the given name, if any.
+    ///
+    /// The name is sanitized in the same way as when rendering.
+    pub fn unit(&self, name: &str) -> Option<Unit> {
+        let sanitized = self.inner.sanitize_metric_name(name);
+        self.inner.units.read().unwrap_or_else(PoisonError::into_inner).get(&sanitized).copied()
+    }
+
+    /// Gets the descriptions of all described metrics, as pairs of metric name and description.
+    ///
+    /// Names are sanitized in the same way as when rendering, and the pairs are sorted by name.
+    /// This is a snapshot taken under a read lock, so it can be used to build a catalog of live
+    /// metrics without blocking the recorder.
+    pub fn descriptions(&self) -> Vec<(String, String)> {
+        let descriptions = self.inner.descriptions.read().unwrap_or_else(PoisonError::into_inner);
+        let mut descriptions = descriptions
+            .iter()
+            .map(|(name, description)| (name.clone(), description.to_string()))
+            .collect::<Vec<_>>();
+        descriptions.sort_unstable();
+        descriptions
+    }
+
+    /// Gets the units of all described metrics, as pairs of metric name and unit.
+    ///
+    /// Names are sanitized in the same way as when rendering, and the pairs are sorted by name.
+    pub fn units(&self) -> Vec<(String, Unit)> {
+        let units = self.inner.units.read().unwrap_or_else(PoisonError::into_inner);
+        let mut units = units.iter().map(|(name, unit)| (name.clone(), *unit)).collect::<Vec<_>>();
+        units.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        units
+    }
+
+    /// Gets the number of buckets of each histogram, sorted by metric name.
+    ///
+    /// The count is that of the configured bucket bounds, not including the `+Inf` bucket, which is
+    /// always rendered in addition to them, so every series of a histogram with 10 buckets renders
+    /// 11 bucket samples.  Histograms set via [`set_histogram_buckets`][Self::set_histogram_buckets]
+    /// can have a different number of buckets per series, in which case the highest is given.
+    /// Summaries are not included.  This helps auditing which histograms multiply the number of
+    /// series the most.
+    pub fn histogram_bucket_counts(&self) -> Vec<(String, usize)> {
+        let distributions = self.inner.distributions.read().unwrap_or_else(PoisonError::into_inner);
+        let mut counts = distributions
+            .iter()
+            .filter_map(|(name, by_labels)| {
+                let count = by_labels
+                    .values()
+                    .filter_map(|distribution| match distribution {
+                        Distribution::Histogram(histogram) => {
+                            let buckets = histogram.buckets();
+                            Some(buckets.iter().filter(|(le, _)| *le != f64::INFINITY).count())
+                        }
+                        Distribution::Summary(..) => None,
+                    })
+                    .max()?;
+                Some((name.clone(), count))
+            })
+            .collect::<Vec<_>>();
+        counts.sort_unstable();
+        counts
+    }
+
+    /// Gets the number of distinct series currently tracked by the recorder.
+    ///
+    /// Each unique combination of metric name and labels registered as a counter, gauge, or
+    /// histogram counts as one series.  This reflects the registry directly, without rendering, so
+    /// series which have gone idle are still counted until they are removed by the next render or
+    /// upkeep run.
+    pub fn metric_count(&self) -> usize {
+        self.inner.series_count.load(Ordering::Relaxed)
+    }
+
+    /// Sets the description of the metric with the given name, replacing any existing description.
+    ///
+    /// Unlike describing a metric through the [`Recorder`] interface, the description can be built
+    /// at runtime without needing a `'static` lifetime, and always replaces any existing
+    /// description, regardless of [`overwrite_descriptions`][overwrite].  The name is sanitized in
+    /// the same way as when rendering.
+    ///
+    /// [overwrite]: crate::PrometheusBuilder::overwrite_descriptions
+    pub fn set_description(&self, name: impl Into<String>, description: impl Into<String>) {
+        let sanitized = self.inner.sanitize_metric_name(&name.into());
+        self.inner
+            .descriptions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(sanitized, SharedString::from(description.into()));
+    }
+
+    /// Removes all series of the metric with the given name, for every set of labels, along with
+    /// its description and unit.
+    ///
+    /// This stops exporting the metric immediately, rather than waiting for it to go idle.  The name
+    /// is sanitized in the same way as metric names, so it can be given as it was when registering
+    /// the metric.  As with [`clear`][Self::clear], any handles to the metric obtained before removing
+    /// it become detached from the recorder, so it must be registered again to be exported again.
+    pub fn remove_metric(&self, name: &str) {
+        let name = self.inner.sanitize_metric_name(name);
+        self.inner.remove_metric(&name);
+    }
+
+    /// Removes all metrics held by the recorder.
+    ///
+    /// Descriptions and units are left intact, so that metrics which are registered again are still
+    /// rendered with their metadata.
+    ///
+    /// This is primarily intended for test harnesses which need a clean slate between scenarios.
+    /// Any [`Counter`], [`Gauge`], or [`Histogram`] handles obtained before clearing become
+    /// detached from the recorder: updates to them will no longer be reflected in rendered output,
+    /// so metrics must be registered again after clearing.
+    pub fn clear(&self) {
+        self.inner.clear();
+    }
+
+    /// Aggregates any histogram samples which are still pending, such that they're reflected in
+    /// [`snapshot`][Self::snapshot], rendering, and anything else reading the recorder state.
+    ///
+    /// Rendering already does this itself, along with reading counters and gauges directly, so every
+    /// value recorded before rendering, on the same thread, is always reflected in the output.
+    /// Counters and gauges are updated with release semantics, and read with acquire semantics, so
+    /// values recorded on other threads are reflected once those threads synchronize with this one,
+    /// such as by being joined, or by releasing a lock which this thread then acquires.  Unlike
+    /// [`run_upkeep`][Self::run_upkeep], idle metrics are not removed.
+    pub fn flush(&self) {
+        self.inner.drain_histograms_to_distributions();
     }
 
     /// Performs upkeeping operations to ensure metrics held by recorder are up-to-date and do not
     /// grow unboundedly.
+    ///
+    /// This drains recorded histogram samples, and removes any metrics which have exceeded the idle
+    /// timeout, if one is configured.
     pub fn run_upkeep(&self) {
         self.inner.run_upkeep();
     }
+
+    /// Creates a weak reference to the recorder state, which does not keep it alive.
+    #[cfg(any(feature = "http-listener", feature = "push-gateway"))]
+    pub(crate) fn downgrade(&self) -> WeakPrometheusHandle {
+        WeakPrometheusHandle { inner: Arc::downgrade(&self.inner) }
+    }
+}
+
+/// A weak reference to the state behind a [`PrometheusHandle`].
+///
+/// Used by background tasks which should stop once the recorder, and all handles to it, are dropped.
+#[cfg(any(feature = "http-listener", feature = "push-gateway"))]
+#[derive(Clone, Debug)]
+pub(crate) struct WeakPrometheusHandle {
+    inner: Weak<Inner>,
+}
+
+#[cfg(any(feature = "http-listener", feature = "push-gateway"))]
+impl WeakPrometheusHandle {
+    /// Attempts to upgrade to a [`PrometheusHandle`], returning `None` if the recorder is gone.
+    pub(crate) fn upgrade(&self) -> Option<PrometheusHandle> {
+        self.inner.upgrade().map(|inner| PrometheusHandle { inner })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, PoisonError};
+    use std::time::Duration;
+
+    use metrics::{KeyName, Recorder};
+
+    use crate::PrometheusBuilder;
+
+    #[test]
+    fn test_repeated_description_does_not_take_write_lock() {
+        let recorder = Arc::new(PrometheusBuilder::new().build_recorder());
+        let describe = |recorder: &crate::PrometheusRecorder| {
+            recorder.describe_counter(
+                KeyName::from("requests"),
+                Some(metrics::Unit::Count),
+                "Number of requests".into(),
+            );
+        };
+        describe(&recorder);
+
+        // Holding read locks means any attempt to take the write locks would block.
+        let _descriptions =
+            recorder.inner.descriptions.read().unwrap_or_else(PoisonError::into_inner);
+        let _units = recorder.inner.units.read().unwrap_or_else(PoisonError::into_inner);
+
+        let (tx, rx) = mpsc::channel();
+        let thread_recorder = Arc::clone(&recorder);
+        std::thread::spawn(move || {
+            describe(&thread_recorder);
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok(), "describing again blocked");
+    }
+
+    #[test]
+    fn test_concurrent_renders_conserve_histogram_samples() {
+        const SAMPLES: u64 = 20_000;
+        static METADATA: metrics::Metadata =
+            metrics::Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
+
+        let recorder = Arc::new(
+            PrometheusBuilder::new()
+                .set_buckets_for_metric(crate::Matcher::Full("latency".into()), &[1.0])
+                .unwrap()
+                .build_recorder(),
+        );
+        let key = metrics::Key::from_name("latency");
+        let histogram = recorder.register_histogram(&key, &METADATA);
+        let summary_key = metrics::Key::from_name("size");
+        let summary = recorder.register_histogram(&summary_key, &METADATA);
+
+        let count_of = |rendered: &str, name: &str| {
+            rendered
+                .lines()
+                .find_map(|line| line.strip_prefix(name))
+                .and_then(|count| count.trim().parse::<u64>().ok())
+                .unwrap_or(0)
+        };
+
+        let done = Arc::new(AtomicBool::new(false));
+        let renderers = (0..4)
+            .map(|_| {
+                let (recorder, done) = (Arc::clone(&recorder), Arc::clone(&done));
+                std::thread::spawn(move || {
+                    let mut last = (0, 0);
+                    while !done.load(Ordering::Acquire) {
+                        let handle = recorder.handle();
+                        let rendered = handle.render();
+                        let counts = (
+                            count_of(&rendered, "latency_count"),
+                            count_of(&rendered, "size_count"),
+                        );
+                        // Samples are never lost or counted twice, so counts only ever grow
+                        // towards the number of samples recorded.
+                        assert!(counts.0 >= last.0 && counts.1 >= last.1, "{:?}", counts);
+                        assert!(counts.0 <= SAMPLES && counts.1 <= SAMPLES, "{:?}", counts);
+                        last = counts;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for i in 0..SAMPLES {
+            #[allow(clippy::cast_precision_loss)]
+            let value = i as f64;
+            histogram.record(value);
+            summary.record(value);
+        }
+        done.store(true, Ordering::Release);
+        for renderer in renderers {
+            renderer.join().unwrap();
+        }
+
+        let rendered = recorder.handle().render();
+        assert_eq!(count_of(&rendered, "latency_count"), SAMPLES);
+        assert_eq!(count_of(&rendered, "latency_bucket{le=\"+Inf\"}"), SAMPLES);
+        assert_eq!(count_of(&rendered, "size_count"), SAMPLES);
+    }
 }
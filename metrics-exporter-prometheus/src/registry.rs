@@ -1,14 +1,24 @@
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
-use metrics::{atomics::AtomicU64, HistogramFn};
+use metrics::{atomics::AtomicU64, Counter, HistogramFn};
 use metrics_util::{registry::GenerationalStorage, AtomicBucket};
-use quanta::Instant;
+use quanta::{Clock, Instant};
 
 pub type GenerationalAtomicStorage = GenerationalStorage<AtomicStorage>;
 
 /// Atomic metric storage for the prometheus exporter.
+///
+/// Histogram samples are timestamped with the given clock, which summaries use to age them out.
 #[derive(Debug)]
-pub struct AtomicStorage;
+pub struct AtomicStorage {
+    clock: Clock,
+}
+
+impl AtomicStorage {
+    pub fn new(clock: Clock) -> AtomicStorage {
+        Self { clock }
+    }
+}
 
 impl<K> metrics_util::registry::Storage<K> for AtomicStorage {
     type Counter = Arc<AtomicU64>;
@@ -24,19 +34,32 @@ impl<K> metrics_util::registry::Storage<K> for AtomicStorage {
     }
 
     fn histogram(&self, _: &K) -> Self::Histogram {
-        Arc::new(AtomicBucketInstant::new())
+        Arc::new(AtomicBucketInstant::new(self.clock.clone()))
     }
 }
 
 /// An `AtomicBucket` newtype wrapper that tracks the time of value insertion.
+///
+/// It can also count the values exceeding a given bound, once that bound has been set.
 #[derive(Debug)]
 pub struct AtomicBucketInstant<T> {
     inner: AtomicBucket<(T, Instant)>,
+    clock: Clock,
+    out_of_bounds: OnceLock<Option<(f64, Counter)>>,
 }
 
 impl<T> AtomicBucketInstant<T> {
-    fn new() -> AtomicBucketInstant<T> {
-        Self { inner: AtomicBucket::new() }
+    fn new(clock: Clock) -> AtomicBucketInstant<T> {
+        Self { inner: AtomicBucket::new(), clock, out_of_bounds: OnceLock::new() }
+    }
+
+    /// Counts values above a bound from now on, initializing the bound and counter with `init`
+    /// unless already done.  If `init` returns `None`, values are never counted.
+    pub fn count_out_of_bounds_with<F>(&self, init: F)
+    where
+        F: FnOnce() -> Option<(f64, Counter)>,
+    {
+        self.out_of_bounds.get_or_init(init);
     }
 
     pub fn clear_with<F>(&self, f: F)
@@ -49,7 +72,12 @@ impl<T> AtomicBucketInstant<T> {
 
 impl HistogramFn for AtomicBucketInstant<f64> {
     fn record(&self, value: f64) {
-        let now = Instant::now();
+        let now = self.clock.now();
         self.inner.push((value, now));
+        if let Some(Some((max, out_of_bounds))) = self.out_of_bounds.get() {
+            if value > *max {
+                out_of_bounds.increment(1);
+            }
+        }
     }
 }
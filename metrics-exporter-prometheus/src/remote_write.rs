@@ -0,0 +1,211 @@
+//! Serialization of snapshots to the Prometheus remote write protocol.
+
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prost::Message;
+use quanta::Instant;
+
+use crate::common::Snapshot;
+use crate::distribution::Distribution;
+use crate::encoder::with_total_suffix;
+use crate::formatting::{parse_label, FloatValue};
+
+/// A batch of series sent to a remote write endpoint.
+///
+/// This mirrors `WriteRequest` from the remote write [protocol definition], keeping only what is
+/// needed to send samples.
+///
+/// [protocol definition]: https://github.com/prometheus/prometheus/blob/main/prompb/remote.proto
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+/// A series, identified by its labels, along with its samples.
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct TimeSeries {
+    /// Labels of the series, including the metric name as `__name__`, sorted by name.
+    #[prost(message, repeated, tag = "1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    pub samples: Vec<Sample>,
+}
+
+/// A label of a series.
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Label {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub value: String,
+}
+
+/// A sample of a series.
+#[derive(Clone, PartialEq, Message)]
+pub(crate) struct Sample {
+    #[prost(double, tag = "1")]
+    pub value: f64,
+    /// Milliseconds since the Unix epoch.
+    #[prost(int64, tag = "2")]
+    pub timestamp: i64,
+}
+
+/// Serializes the given snapshot to a snappy-compressed remote write request, with every sample
+/// timestamped at `now`, and summaries snapshotted as of `summary_time`.
+pub(crate) fn snapshot_to_payload(
+    snapshot: &Snapshot,
+    counter_total_suffix: bool,
+    now: SystemTime,
+    summary_time: Instant,
+) -> Vec<u8> {
+    let request = snapshot_to_write_request(snapshot, counter_total_suffix, now, summary_time);
+    snap::raw::Encoder::new()
+        .compress_vec(&request.encode_to_vec())
+        .expect("compressing an in-memory buffer should never fail")
+}
+
+/// Converts the given snapshot to a remote write request.
+///
+/// Series are named and labeled just as in the Prometheus exposition format, with histograms and
+/// summaries broken down into their bucket or quantile, sum, and count series.
+// Remote write samples are always floats, so counters above 2^53 lose precision by design.
+#[allow(clippy::cast_precision_loss)]
+fn snapshot_to_write_request(
+    snapshot: &Snapshot,
+    counter_total_suffix: bool,
+    now: SystemTime,
+    summary_time: Instant,
+) -> WriteRequest {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let timestamp = i64::try_from(since_epoch.as_millis()).unwrap_or(i64::MAX);
+
+    let mut timeseries = Vec::new();
+    let mut push = |name: &str, labels: &[String], extra: Option<(&str, f64)>, value: f64| {
+        let labels = series_labels(name, labels, extra);
+        timeseries.push(TimeSeries { labels, samples: vec![Sample { value, timestamp }] });
+    };
+
+    for (name, by_labels) in &snapshot.counters {
+        let name = if counter_total_suffix { with_total_suffix(name) } else { name.clone() };
+        for (labels, value) in by_labels {
+            push(&name, labels, None, *value as f64);
+        }
+    }
+
+    for (name, by_labels) in &snapshot.gauges {
+        for (labels, value) in by_labels {
+            push(name, labels, None, *value);
+        }
+    }
+
+    for (name, by_labels) in &snapshot.distributions {
+        let (bucket_name, sum_name, count_name) =
+            (format!("{name}_bucket"), format!("{name}_sum"), format!("{name}_count"));
+        for (labels, distribution) in by_labels {
+            let (sum, count) = match distribution {
+                Distribution::Histogram(histogram) => {
+                    let buckets = histogram.buckets();
+                    let finite = buckets.iter().filter(|(le, _)| *le != f64::INFINITY);
+                    for (le, count) in finite {
+                        push(&bucket_name, labels, Some(("le", *le)), *count as f64);
+                    }
+                    let count = histogram.count() as f64;
+                    push(&bucket_name, labels, Some(("le", f64::INFINITY)), count);
+
+                    (histogram.sum(), count)
+                }
+                Distribution::Summary(summary, quantiles, sum) => {
+                    let snapshot = summary.snapshot(summary_time);
+                    for quantile in quantiles.iter() {
+                        let value = snapshot.quantile(quantile.value()).unwrap_or(0.0);
+                        push(name, labels, Some(("quantile", quantile.value())), value);
+                    }
+
+                    (*sum, summary.count() as f64)
+                }
+            };
+
+            push(&sum_name, labels, None, sum);
+            push(&count_name, labels, None, count);
+        }
+    }
+
+    WriteRequest { timeseries }
+}
+
+/// Builds the labels of a series from its name, its labels formatted as `key="value"`, and an
+/// additional label, such as a bucket bound, if any.
+fn series_labels(name: &str, labels: &[String], extra: Option<(&str, f64)>) -> Vec<Label> {
+    let mut series = vec![Label { name: "__name__".to_owned(), value: name.to_owned() }];
+    series.extend(labels.iter().filter_map(|label| {
+        let (key, value) = parse_label(label)?;
+        Some(Label { name: key.to_owned(), value })
+    }));
+    if let Some((key, value)) = extra {
+        series.push(Label { name: key.to_owned(), value: FloatValue(value).to_string() });
+    }
+
+    // The protocol requires labels to be sorted by name.
+    series.sort_by(|a, b| a.name.cmp(&b.name));
+    series
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics::{Key, Label, Level, Metadata, Recorder};
+    use prost::Message;
+
+    use super::WriteRequest;
+    use crate::PrometheusBuilder;
+
+    static METADATA: Metadata<'static> = Metadata::new(module_path!(), Level::INFO, None);
+
+    #[test]
+    fn test_remote_write_payload() {
+        let recorder = PrometheusBuilder::new()
+            .add_global_label("region", "us \"east\"")
+            .set_buckets(&[1.0])
+            .unwrap()
+            .build_recorder();
+        let key = Key::from_parts("requests", vec![Label::new("path", "/")]);
+        recorder.register_counter(&key, &METADATA).increment(42);
+        recorder.register_histogram(&Key::from_name("latency"), &METADATA).record(0.5);
+
+        let payload = recorder.handle().remote_write_payload();
+        let decompressed = snap::raw::Decoder::new().decompress_vec(&payload).unwrap();
+        let request = WriteRequest::decode(decompressed.as_slice()).unwrap();
+
+        let series = request
+            .timeseries
+            .iter()
+            .map(|series| {
+                let labels = series
+                    .labels
+                    .iter()
+                    .map(|label| format!("{}={}", label.name, label.value))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                (labels, series.samples[0].value)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(series[0], ("__name__=requests,path=/,region=us \"east\"".to_owned(), 42.0));
+        let names = series.iter().map(|(labels, _)| labels.split(',').next().unwrap());
+        assert_eq!(
+            names.collect::<Vec<_>>(),
+            [
+                "__name__=requests",
+                "__name__=latency_bucket",
+                "__name__=latency_bucket",
+                "__name__=latency_sum",
+                "__name__=latency_count",
+            ]
+        );
+        assert!(series[2].0.contains("le=+Inf"), "{:?}", series);
+
+        // Every sample is timestamped in milliseconds.
+        let timestamp = request.timeseries[0].samples[0].timestamp;
+        assert!(timestamp > 1_600_000_000_000, "unexpected timestamp: {}", timestamp);
+    }
+}
@@ -0,0 +1,275 @@
+//! Helpers for testing code which records metrics.
+//!
+//! Asserting on the output of [`PrometheusHandle::render`][crate::PrometheusHandle::render] as a
+//! string is brittle, as the order of series and the formatting of values are incidental.  Here,
+//! the output can instead be parsed back into metric families, which can be inspected directly.
+//!
+//! ```
+//! # use metrics_exporter_prometheus::PrometheusBuilder;
+//! use metrics_exporter_prometheus::testing::parse_exposition;
+//!
+//! let recorder = PrometheusBuilder::new().build_recorder();
+//! let handle = recorder.handle();
+//! metrics::with_local_recorder(&recorder, || {
+//!     metrics::counter!("requests", "method" => "GET").increment(3);
+//! });
+//!
+//! let families = parse_exposition(&handle.render());
+//! assert_eq!(families[0].name, "requests");
+//! assert_eq!(families[0].kind, "counter");
+//! assert_eq!(families[0].samples[0].labels, [("method".to_owned(), "GET".to_owned())]);
+//! assert_eq!(families[0].samples[0].value, 3.0);
+//! ```
+
+use crate::formatting::parse_label;
+
+/// Suffixes of the samples which belong to a metric family despite not sharing its exact name.
+const SAMPLE_SUFFIXES: &[&str] = &["_total", "_bucket", "_sum", "_count", "_created"];
+
+/// A metric family, as parsed from the exposition format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MetricFamily {
+    /// Name of the family, as given by its `# TYPE` line, or by its first sample if it has none.
+    pub name: String,
+    /// Type of the family, such as `counter` or `histogram`, or `untyped` if it has none.
+    pub kind: String,
+    /// Description of the family, with any escaping reversed, if it has one.
+    pub help: Option<String>,
+    /// Samples of the family, in the order they were rendered.
+    pub samples: Vec<Sample>,
+}
+
+/// A sample, as parsed from the exposition format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    /// Name of the sample, such as `latency_bucket` for a bucket of the `latency` histogram.
+    pub name: String,
+    /// Labels of the sample, as pairs of key and value, with any escaping reversed.
+    pub labels: Vec<(String, String)>,
+    /// Value of the sample.
+    pub value: f64,
+    /// Timestamp of the sample as rendered, if it has one: in milliseconds since the Unix epoch for
+    /// the Prometheus format, or in seconds, possibly fractional, for `OpenMetrics`.
+    pub timestamp: Option<f64>,
+}
+
+/// Parses the output of rendering metrics in the Prometheus or `OpenMetrics` text format into
+/// metric families.
+///
+/// This is a lightweight parser meant for tests, not a validating one: lines which cannot be
+/// parsed, as well as other comments such as `# UNIT` and exemplars, are skipped.
+pub fn parse_exposition(text: &str) -> Vec<MetricFamily> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+    for line in text.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (Some(keyword), Some(name)) = (parts.next(), parts.next()) else { continue };
+            let rest = parts.next().unwrap_or_default();
+            match keyword {
+                "HELP" => family_for(&mut families, name).help = Some(unescape(rest)),
+                "TYPE" => family_for(&mut families, name).kind = rest.to_owned(),
+                _ => {}
+            }
+            continue;
+        }
+
+        let Some(sample) = parse_sample(line) else { continue };
+        match families.last_mut() {
+            Some(family) if belongs_to(&sample, family) => family.samples.push(sample),
+            _ => {
+                let mut family = new_family(&sample.name);
+                family.samples.push(sample);
+                families.push(family);
+            }
+        }
+    }
+    families
+}
+
+/// Gets the family being parsed if it has the given name, or starts a new one otherwise.
+fn family_for<'a>(families: &'a mut Vec<MetricFamily>, name: &str) -> &'a mut MetricFamily {
+    if families.last().map_or(true, |family| family.name != name) {
+        families.push(new_family(name));
+    }
+    families.last_mut().expect("family was just pushed")
+}
+
+/// Checks whether the sample belongs to the given family, based on its name.
+fn belongs_to(sample: &Sample, family: &MetricFamily) -> bool {
+    sample.name == family.name
+        || SAMPLE_SUFFIXES.iter().any(|suffix| {
+            sample.name.strip_suffix(suffix).map_or(false, |name| name == family.name)
+        })
+}
+
+fn new_family(name: &str) -> MetricFamily {
+    MetricFamily { name: name.to_owned(), kind: "untyped".to_owned(), help: None, samples: vec![] }
+}
+
+/// Parses a sample line, such as `requests_total{method="GET"} 3 1700000000000`.
+fn parse_sample(line: &str) -> Option<Sample> {
+    // Exemplars follow the value and timestamp, and are not part of the sample itself.
+    let line = line.split(" # ").next()?;
+    let (name, labels, rest) = match line.find(['{', ' ']) {
+        Some(start) if line[start..].starts_with('{') => {
+            let end = start + label_set_len(&line[start..])?;
+            (&line[..start], split_labels(&line[start + 1..end - 1])?, &line[end..])
+        }
+        Some(start) => (&line[..start], Vec::new(), &line[start..]),
+        None => return None,
+    };
+
+    let mut fields = rest.split_whitespace();
+    let value = fields.next()?.parse::<f64>().ok()?;
+    let timestamp = match fields.next() {
+        Some(timestamp) => Some(timestamp.parse::<f64>().ok()?),
+        None => None,
+    };
+    Some(Sample { name: name.to_owned(), labels, value, timestamp })
+}
+
+/// Gets the length of the label set at the start of `text`, including its braces.
+fn label_set_len(text: &str) -> Option<usize> {
+    let (mut quoted, mut escaped) = (false, false);
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '}' if !quoted => return Some(i + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Splits the contents of a label set into pairs of key and value.
+fn split_labels(text: &str) -> Option<Vec<(String, String)>> {
+    let mut labels = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in text.char_indices().chain(std::iter::once((text.len(), ','))) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                let label = text[start..i].trim();
+                if !label.is_empty() {
+                    let (key, value) = parse_label(label)?;
+                    labels.push((key.to_owned(), value));
+                }
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    Some(labels)
+}
+
+/// Reverses the escaping of a description.
+fn unescape(text: &str) -> String {
+    parse_label(&format!("_=\"{text}\"")).map(|(_, value)| value).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use metrics::{Key, Label, Recorder};
+
+    use super::{parse_exposition, MetricFamily, Sample};
+    use crate::PrometheusBuilder;
+
+    static METADATA: metrics::Metadata =
+        metrics::Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
+
+    fn sample(name: &str, labels: &[(&str, &str)], value: f64) -> Sample {
+        let labels = labels.iter().map(|(k, v)| ((*k).to_owned(), (*v).to_owned())).collect();
+        Sample { name: name.to_owned(), labels, value, timestamp: None }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets(&[1.0, 5.0])
+            .expect("buckets should be valid")
+            .build_recorder();
+        let handle = recorder.handle();
+
+        recorder.describe_counter(
+            "requests".into(),
+            None,
+            "Requests, by \"path\"\nand method".into(),
+        );
+        let labels = vec![Label::new("path", "/a,b"), Label::new("quote", "say \"hi\"")];
+        recorder.register_counter(&Key::from_parts("requests", labels), &METADATA).increment(3);
+        recorder.register_gauge(&Key::from_name("temperature"), &METADATA).set(-1.5);
+        let latency = recorder.register_histogram(&Key::from_name("latency"), &METADATA);
+        latency.record(0.5);
+        latency.record(10.0);
+
+        let families = parse_exposition(&handle.render());
+        let expected = vec![
+            MetricFamily {
+                name: "requests".to_owned(),
+                kind: "counter".to_owned(),
+                help: Some("Requests, by \"path\"\nand method".to_owned()),
+                samples: vec![sample(
+                    "requests",
+                    &[("path", "/a,b"), ("quote", "say \"hi\"")],
+                    3.0,
+                )],
+            },
+            MetricFamily {
+                name: "temperature".to_owned(),
+                kind: "gauge".to_owned(),
+                help: None,
+                samples: vec![sample("temperature", &[], -1.5)],
+            },
+            MetricFamily {
+                name: "latency".to_owned(),
+                kind: "histogram".to_owned(),
+                help: None,
+                samples: vec![
+                    sample("latency_bucket", &[("le", "1")], 1.0),
+                    sample("latency_bucket", &[("le", "5")], 1.0),
+                    sample("latency_bucket", &[("le", "+Inf")], 2.0),
+                    sample("latency_sum", &[], 10.5),
+                    sample("latency_count", &[], 2.0),
+                ],
+            },
+        ];
+        assert_eq!(families, expected);
+    }
+
+    #[test]
+    fn test_untyped_and_timestamped_samples() {
+        let families = parse_exposition("foo 1 1700000000000\nfoo_total 2\nbar{a=\"b\"} NaN\n");
+        assert_eq!(families.len(), 2);
+        assert_eq!(families[0].name, "foo");
+        assert_eq!(families[0].kind, "untyped");
+        assert_eq!(families[0].samples[0].timestamp, Some(1_700_000_000_000.0));
+        assert_eq!(families[0].samples[1].name, "foo_total");
+        assert_eq!(families[1].samples[0].labels, [("a".to_owned(), "b".to_owned())]);
+        assert!(families[1].samples[0].value.is_nan());
+    }
+
+    #[test]
+    fn test_openmetrics_timestamps() {
+        let recorder = PrometheusBuilder::new().with_timestamps(true).build_recorder();
+        let handle = recorder.handle();
+        recorder.register_counter(&Key::from_name("requests"), &METADATA).increment(3);
+
+        let seconds = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+        let before = seconds(SystemTime::now());
+        let rendered = handle.render_openmetrics();
+        let after = seconds(SystemTime::now());
+
+        let families = parse_exposition(&rendered);
+        assert_eq!(families.len(), 1, "{rendered}");
+        assert_eq!(families[0].samples[0].name, "requests_total");
+        let timestamp = families[0].samples[0].timestamp.expect("sample should be timestamped");
+        // Timestamps are rendered with millisecond precision.
+        assert!(before - 0.001 <= timestamp && timestamp <= after, "{}", rendered);
+    }
+}
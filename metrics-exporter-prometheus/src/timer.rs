@@ -0,0 +1,48 @@
+//! Scope guards for timing sections of code.
+
+use metrics::{Histogram, Unit};
+use quanta::{Clock, Instant};
+
+/// A guard which records the time elapsed since it was created to a histogram when dropped.
+///
+/// Created by [`PrometheusHandle::time_histogram`][crate::PrometheusHandle::time_histogram].  The
+/// elapsed time is recorded in seconds by default, which can be changed with
+/// [`with_unit`][Self::with_unit].
+#[derive(Debug)]
+#[must_use = "the elapsed time is recorded when the guard is dropped"]
+pub struct TimerGuard {
+    histogram: Histogram,
+    clock: Clock,
+    start: Instant,
+    unit: Unit,
+}
+
+impl TimerGuard {
+    pub(crate) fn new(histogram: Histogram, clock: Clock) -> Self {
+        let start = clock.now();
+        Self { histogram, clock, start, unit: Unit::Seconds }
+    }
+
+    /// Sets the unit the elapsed time is recorded in.
+    ///
+    /// Only [`Unit::Seconds`], [`Unit::Milliseconds`], [`Unit::Microseconds`], and
+    /// [`Unit::Nanoseconds`] are meaningful here; any other unit records the elapsed time in seconds.
+    pub fn with_unit(mut self, unit: Unit) -> Self {
+        self.unit = unit;
+        self
+    }
+}
+
+impl Drop for TimerGuard {
+    fn drop(&mut self) {
+        let elapsed = self.clock.now().duration_since(self.start).as_secs_f64();
+        let value = match self.unit {
+            Unit::Milliseconds => elapsed * 1e3,
+            Unit::Microseconds => elapsed * 1e6,
+            Unit::Nanoseconds => elapsed * 1e9,
+            _ => elapsed,
+        };
+
+        self.histogram.record(value);
+    }
+}
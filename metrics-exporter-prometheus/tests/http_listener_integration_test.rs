@@ -3,7 +3,8 @@ mod http_listener_test {
     use http_body_util::{BodyExt, Collected, Empty};
     use hyper::{
         body::{Buf, Bytes},
-        Request, StatusCode, Uri,
+        header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+        Method, Request, StatusCode, Uri,
     };
     use hyper_util::client::legacy::{connect::HttpConnector, Client};
     use metrics::{Key, Label, Recorder};
@@ -41,7 +42,7 @@ mod http_listener_test {
             runtime.spawn(exporter); //async { exporter.await});
             tokio::time::sleep(Duration::from_millis(200)).await;
 
-            let uri = format!("http://{socket_address}")
+            let uri = format!("http://{socket_address}/metrics")
                 .parse::<Uri>()
                 .unwrap_or_else(|e| panic!("Error parsing URI: {:?}", e));
 
@@ -52,6 +53,282 @@ mod http_listener_test {
         });
     }
 
+    #[test]
+    fn test_http_listener_metrics_path() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap_or_else(|e| panic!("Failed to create test runtime: {:?}", e));
+
+        runtime.block_on(async {
+            let local = [127, 0, 0, 1];
+            let port = get_available_port(local).await;
+            let socket_address = SocketAddr::from((local, port));
+
+            let (recorder, exporter) = {
+                PrometheusBuilder::new().with_http_listener(socket_address).build().unwrap_or_else(
+                    |e| panic!("failed to create Prometheus recorder and http listener: {:?}", e),
+                )
+            };
+
+            let key = Key::from_name("basic_counter");
+            let counter = recorder.register_counter(&key, &METADATA);
+            counter.increment(42);
+
+            runtime.spawn(exporter);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            let uri = format!("http://{socket_address}/metrics")
+                .parse::<Uri>()
+                .unwrap_or_else(|e| panic!("Error parsing URI: {:?}", e));
+
+            let client =
+                Client::builder(hyper_util::rt::TokioExecutor::new()).build(HttpConnector::new());
+
+            let req = Request::builder()
+                .uri(uri.clone())
+                .body(Empty::<Bytes>::new())
+                .unwrap_or_else(|e| panic!("Failed building request: {:?}", e));
+            let response = client
+                .request(req)
+                .await
+                .unwrap_or_else(|e| panic!("Failed requesting data from {uri}: {:?}", e));
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                response.headers().get(CONTENT_TYPE).map(|v| v.as_bytes()),
+                Some(&b"text/plain; version=0.0.4"[..])
+            );
+
+            let mut body = response
+                .into_body()
+                .collect()
+                .await
+                .map(Collected::aggregate)
+                .unwrap_or_else(|e| panic!("Error reading response: {:?}", e));
+            let body = String::from_utf8(body.copy_to_bytes(body.remaining()).to_vec())
+                .unwrap_or_else(|e| panic!("Error decoding response body: {:?}", e));
+            assert!(body.contains("basic_counter 42"));
+
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(uri.clone())
+                .body(Empty::<Bytes>::new())
+                .unwrap_or_else(|e| panic!("Failed building request: {:?}", e));
+            let response = client
+                .request(req)
+                .await
+                .unwrap_or_else(|e| panic!("Failed requesting data from {uri}: {:?}", e));
+
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            for (path, expected_status) in [
+                ("/health", StatusCode::OK),
+                ("/", StatusCode::NOT_FOUND),
+                ("/metrics/extra", StatusCode::NOT_FOUND),
+                ("/other", StatusCode::NOT_FOUND),
+            ] {
+                let uri = format!("http://{socket_address}{path}")
+                    .parse::<Uri>()
+                    .unwrap_or_else(|e| panic!("Error parsing URI: {:?}", e));
+
+                let (status, body) = read_from(uri).await;
+
+                assert_eq!(status, expected_status, "path: {path}");
+                assert!(!body.contains("basic_counter"), "path: {}", path);
+            }
+        });
+    }
+
+    #[test]
+    fn test_http_listener_basic_auth() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap_or_else(|e| panic!("Failed to create test runtime: {:?}", e));
+
+        runtime.block_on(async {
+            let local = [127, 0, 0, 1];
+            let port = get_available_port(local).await;
+            let socket_address = SocketAddr::from((local, port));
+
+            let (recorder, exporter) = {
+                PrometheusBuilder::new()
+                    .with_http_listener(socket_address)
+                    .with_basic_auth("metrics", "hunter2")
+                    .build()
+                    .unwrap_or_else(|e| {
+                        panic!("failed to create Prometheus recorder and http listener: {:?}", e)
+                    })
+            };
+
+            let key = Key::from_name("basic_counter");
+            recorder.register_counter(&key, &METADATA).increment(42);
+
+            runtime.spawn(exporter);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            let uri = format!("http://{socket_address}/metrics")
+                .parse::<Uri>()
+                .unwrap_or_else(|e| panic!("Error parsing URI: {:?}", e));
+            let client =
+                Client::builder(hyper_util::rt::TokioExecutor::new()).build(HttpConnector::new());
+
+            // "metrics:hunter2" and "metrics:wrong", respectively.
+            let cases = [
+                (None, StatusCode::UNAUTHORIZED),
+                (Some("Basic bWV0cmljczp3cm9uZw=="), StatusCode::UNAUTHORIZED),
+                (Some("Basic bWV0cmljczpodW50ZXIy"), StatusCode::OK),
+            ];
+
+            for (auth, expected_status) in cases {
+                let mut builder = Request::builder().uri(uri.clone());
+                if let Some(auth) = auth {
+                    builder = builder.header(AUTHORIZATION, auth);
+                }
+                let req = builder
+                    .body(Empty::<Bytes>::new())
+                    .unwrap_or_else(|e| panic!("Failed building request: {:?}", e));
+                let response = client
+                    .request(req)
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed requesting data from {uri}: {:?}", e));
+
+                assert_eq!(response.status(), expected_status, "authorization: {auth:?}");
+                if expected_status == StatusCode::UNAUTHORIZED {
+                    assert_eq!(
+                        response.headers().get(WWW_AUTHENTICATE).map(|v| v.as_bytes()),
+                        Some(&b"Basic"[..])
+                    );
+                    continue;
+                }
+
+                let mut body = response
+                    .into_body()
+                    .collect()
+                    .await
+                    .map(Collected::aggregate)
+                    .unwrap_or_else(|e| panic!("Error reading response: {:?}", e));
+                let body = String::from_utf8(body.copy_to_bytes(body.remaining()).to_vec())
+                    .unwrap_or_else(|e| panic!("Error decoding response body: {:?}", e));
+                assert!(body.contains("basic_counter 42"));
+            }
+        });
+    }
+
+    #[test]
+    fn test_http_listener_allowed_addresses() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap_or_else(|e| panic!("Failed to create test runtime: {:?}", e));
+
+        runtime.block_on(async {
+            let local = [127, 0, 0, 1];
+
+            // Only the first allowlist covers the loopback address we connect from.
+            for (allowed, expected_status) in
+                [("127.0.0.0/8", StatusCode::OK), ("10.0.0.0/8", StatusCode::FORBIDDEN)]
+            {
+                let port = get_available_port(local).await;
+                let socket_address = SocketAddr::from((local, port));
+
+                let (recorder, exporter) = {
+                    PrometheusBuilder::new()
+                        .with_http_listener(socket_address)
+                        .add_allowed_address(allowed)
+                        .and_then(PrometheusBuilder::build)
+                        .unwrap_or_else(|e| {
+                            panic!(
+                                "failed to create Prometheus recorder and http listener: {:?}",
+                                e
+                            )
+                        })
+                };
+
+                let key = Key::from_name("basic_counter");
+                recorder.register_counter(&key, &METADATA).increment(42);
+
+                runtime.spawn(exporter);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+
+                let uri = format!("http://{socket_address}/metrics")
+                    .parse::<Uri>()
+                    .unwrap_or_else(|e| panic!("Error parsing URI: {:?}", e));
+
+                let (status, body) = read_from(uri).await;
+
+                assert_eq!(status, expected_status, "allowlist: {allowed}");
+                assert_eq!(body.contains("basic_counter 42"), status == StatusCode::OK);
+            }
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "uds-listener")]
+    fn test_http_uds_listener() {
+        use hyper_util::rt::TokioIo;
+        use tokio::net::UnixStream;
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap_or_else(|e| panic!("Failed to create test runtime: {:?}", e));
+
+        let path =
+            std::env::temp_dir().join(format!("metrics-uds-test-{}.sock", std::process::id()));
+
+        runtime.block_on(async {
+            let (recorder, exporter) = {
+                PrometheusBuilder::new().with_http_uds_listener(&path).build().unwrap_or_else(|e| {
+                    panic!("failed to create Prometheus recorder and http listener: {:?}", e)
+                })
+            };
+
+            let key = Key::from_name("basic_counter");
+            recorder.register_counter(&key, &METADATA).increment(42);
+
+            let exporter = runtime.spawn(exporter);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            let stream = UnixStream::connect(&path)
+                .await
+                .unwrap_or_else(|e| panic!("Failed connecting to {}: {:?}", path.display(), e));
+            let (mut sender, connection) =
+                hyper::client::conn::http1::handshake(TokioIo::new(stream))
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed HTTP handshake: {:?}", e));
+            let connection = runtime.spawn(connection);
+
+            let req = Request::builder()
+                .uri("/metrics")
+                .header(hyper::header::HOST, "localhost")
+                .body(Empty::<Bytes>::new())
+                .unwrap_or_else(|e| panic!("Failed building request: {:?}", e));
+            let response = sender
+                .send_request(req)
+                .await
+                .unwrap_or_else(|e| panic!("Failed requesting data: {:?}", e));
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let mut body = response
+                .into_body()
+                .collect()
+                .await
+                .map(Collected::aggregate)
+                .unwrap_or_else(|e| panic!("Error reading response: {:?}", e));
+            let body = String::from_utf8(body.copy_to_bytes(body.remaining()).to_vec())
+                .unwrap_or_else(|e| panic!("Error decoding response body: {:?}", e));
+            assert!(body.contains("basic_counter 42"));
+
+            // Stopping the exporter removes the socket file.
+            connection.abort();
+            exporter.abort();
+            let _ = exporter.await;
+            assert!(!path.exists());
+        });
+    }
+
     async fn get_available_port(listen_address: [u8; 4]) -> u16 {
         let socket_address = SocketAddr::from((listen_address, 0));
         TcpListener::bind(socket_address)
@@ -0,0 +1,88 @@
+#[cfg(all(test, feature = "push-gateway", feature = "http-listener"))]
+mod push_gateway_test {
+    use std::net::SocketAddr;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use http_body_util::{BodyExt, Collected, Full};
+    use hyper::{
+        body::{Bytes, Incoming},
+        server::conn::http1::Builder as HyperHttpBuilder,
+        service::service_fn,
+        Request, Response,
+    };
+    use hyper_util::rt::TokioIo;
+    use metrics::{Key, Label, Recorder};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use tokio::net::TcpListener;
+
+    static METADATA: metrics::Metadata =
+        metrics::Metadata::new(module_path!(), metrics::Level::INFO, Some(module_path!()));
+
+    #[test]
+    fn test_push_gateway() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap_or_else(|e| panic!("Failed to create test runtime: {:?}", e));
+
+        runtime.block_on(async {
+            let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap_or_else(|e| panic!("Unable to bind mock push gateway: {:?}", e));
+            let socket_address = listener.local_addr().expect("listener should have an address");
+
+            // Run a mock push gateway which forwards every request it receives to the test.
+            let (tx, rx) = mpsc::channel::<(String, String, String)>();
+            runtime.spawn(async move {
+                loop {
+                    let Ok((stream, _)) = listener.accept().await else { continue };
+                    let tx = tx.clone();
+                    let service = service_fn(move |req: Request<Incoming>| {
+                        let tx = tx.clone();
+                        async move {
+                            let method = req.method().to_string();
+                            let path = req.uri().path().to_string();
+                            let body = req.into_body().collect().await.map(Collected::to_bytes)?;
+                            let body = String::from_utf8(body.to_vec())
+                                .unwrap_or_else(|e| panic!("Error decoding pushed body: {:?}", e));
+                            let _ = tx.send((method, path, body));
+                            Ok::<_, hyper::Error>(Response::new(Full::<Bytes>::default()))
+                        }
+                    });
+
+                    tokio::spawn(async move {
+                        let _ = HyperHttpBuilder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await;
+                    });
+                }
+            });
+
+            let endpoint = format!("http://{socket_address}/metrics/job/test/instance/local");
+            let (recorder, exporter) = PrometheusBuilder::new()
+                .with_push_gateway(endpoint, Duration::from_millis(50), None, None)
+                .unwrap_or_else(|e| panic!("push gateway endpoint should be valid: {:?}", e))
+                .build()
+                .unwrap_or_else(|e| panic!("failed to create Prometheus recorder: {:?}", e));
+
+            let key = Key::from_parts("basic_counter", vec![Label::new("wutang", "forever")]);
+            recorder.register_counter(&key, &METADATA).increment(42);
+            let expected = recorder.handle().render();
+
+            runtime.spawn(exporter);
+
+            let (method, path, body) = tokio::task::spawn_blocking(move || {
+                rx.recv_timeout(Duration::from_secs(5))
+                    .unwrap_or_else(|e| panic!("No push received by mock push gateway: {:?}", e))
+            })
+            .await
+            .expect("receiving task should not panic");
+
+            assert_eq!(method, "PUT");
+            assert_eq!(path, "/metrics/job/test/instance/local");
+            assert_eq!(body, expected);
+            assert!(body.contains("basic_counter{wutang=\"forever\"} 42"));
+        });
+    }
+}
@@ -46,6 +46,15 @@ struct Block<T> {
     // would involve more complex logic to read all of the atomics.
     read: AtomicUsize,
 
+    // Seal marker.
+    //
+    // When a block is detached from the bucket by clearing it, writers which loaded it as the tail
+    // beforehand may still attempt to write to it.  Sealing the block makes any such writes fail,
+    // such that the writer retries against the bucket's current tail instead of writing a value
+    // that would never be read.  This is zero while the block is unsealed, and one more than the
+    // number of slots claimed before it was sealed otherwise.
+    sealed: AtomicUsize,
+
     // The individual slots.
     slots: [MaybeUninit<UnsafeCell<T>>; BLOCK_SIZE],
 
@@ -59,7 +68,7 @@ impl<T> Block<T> {
         // SAFETY:
         // At a high level, all types inherent to  `Block<T>` can be safely zero initialized.
         //
-        // `write`/`read` are meant to start at zero (`AtomicUsize`)
+        // `write`/`read`/`sealed` are meant to start at zero (`AtomicUsize`)
         // `slots` is an array of `MaybeUninit`, which is zero init safe
         // `next` is meant to start as "null", where the pointer (`AtomicUsize`) is zero
         unsafe { MaybeUninit::zeroed().assume_init() }
@@ -88,11 +97,26 @@ impl<T> Block<T> {
             return true;
         }
 
+        // Once sealed, only the slots claimed before sealing will ever be written.
+        let sealed = self.sealed.load(Ordering::Acquire);
+        if sealed != 0 {
+            return len == sealed - 1;
+        }
+
         // We have to clamp self.write since multiple threads might race on filling the last block,
         // so the value could actually exceed BLOCK_SIZE.
         min(self.write.load(Ordering::Acquire), BLOCK_SIZE) == len
     }
 
+    /// Seals this block, such that any further writes to it fail.
+    ///
+    /// Writes which claimed a slot before the block was sealed still complete, and can be waited
+    /// for with [`is_quiesced`](Block::is_quiesced).
+    pub fn seal(&self) {
+        let claimed = min(self.write.swap(BLOCK_SIZE, Ordering::AcqRel), BLOCK_SIZE);
+        self.sealed.store(claimed + 1, Ordering::Release);
+    }
+
     /// Gets a slice of the data written to this block.
     pub fn data(&self) -> &[T] {
         // SAFETY:
@@ -241,18 +265,20 @@ impl<T> AtomicBucket<T> {
                 Ok(_) => return,
                 // The block was full, so we've been given the value back and we need to install a new block.
                 Err(value) => {
+                    // Link the new block to the previous block before installing it, so that
+                    // anyone who loads the new block, such as when clearing the bucket, also sees
+                    // the previous block.
+                    let new_tail = Owned::new(Block::new());
+                    new_tail.next.store(tail, Ordering::Relaxed);
                     match self.tail.compare_exchange(
                         tail,
-                        Owned::new(Block::new()),
+                        new_tail,
                         Ordering::AcqRel,
                         Ordering::Acquire,
                         guard,
                     ) {
-                        // We managed to install the block, so we need to link this new block to
-                        // the nextious block.
                         Ok(ptr) => {
                             let new_tail = unsafe { ptr.deref() };
-                            new_tail.next.store(tail, Ordering::Release);
 
                             // Now push into our new block.
                             match new_tail.push(value) {
@@ -265,7 +291,8 @@ impl<T> AtomicBucket<T> {
                                 }
                             }
                         }
-                        // Somebody else installed the block before us, so let's just start over.
+                        // Somebody else installed the block before us, or the bucket was cleared,
+                        // so let's just start over.
                         Err(_) => original = value,
                     }
                 }
@@ -374,6 +401,10 @@ impl<T> AtomicBucket<T> {
             let backoff = Backoff::new();
             let mut freeable_blocks = Vec::new();
 
+            // Writers may still be holding on to the block that was the tail, so seal it to make
+            // them retry against the new tail.  Any other blocks are already full.
+            unsafe { block_ptr.deref() }.seal();
+
             // While we have a valid block -- either `tail` or the next block as we keep reading -- we
             // load the data from each block and process it by calling `f`.
             while !block_ptr.is_null() {
@@ -435,6 +466,7 @@ impl<T> Default for AtomicBucket<T> {
 mod tests {
     use super::{AtomicBucket, Block, BLOCK_SIZE};
     use crossbeam_utils::thread::scope;
+    use std::sync::atomic::{AtomicBool, Ordering};
 
     #[test]
     fn test_create_new_block() {
@@ -648,6 +680,37 @@ mod tests {
         assert_eq!(snapshot.len(), 0);
     }
 
+    #[test]
+    fn test_clear_with_mt() {
+        let bucket = AtomicBucket::new();
+        let count = BLOCK_SIZE as u64 * 10_000;
+        let done = AtomicBool::new(false);
+
+        let res = scope(|s| {
+            let writer = s.spawn(|_| {
+                let mut total = 0;
+                for i in 0..count {
+                    bucket.push(i);
+                    total += i;
+                }
+                done.store(true, Ordering::Release);
+                total
+            });
+
+            // Clear concurrently with the writer: every value must be seen exactly once.
+            let mut total_accumulated = 0;
+            while !done.load(Ordering::Acquire) {
+                bucket.clear_with(|xs| total_accumulated += xs.iter().sum::<u64>());
+            }
+            bucket.clear_with(|xs| total_accumulated += xs.iter().sum::<u64>());
+
+            (writer.join().unwrap(), total_accumulated)
+        });
+
+        let (total_pushed, total_accumulated) = res.unwrap();
+        assert_eq!(total_pushed, total_accumulated);
+    }
+
     #[test]
     fn test_bucket_len_and_next_len() {
         let bucket = AtomicBucket::new();
@@ -29,6 +29,22 @@ impl Histogram {
         Some(Histogram { count: 0, bounds: Vec::from(bounds), buckets, sum: 0.0 })
     }
 
+    /// Creates a `Histogram` from already bucketed data.
+    ///
+    /// `buckets` are tuples of bucket limit and cumulative count, in the same form as returned by
+    /// [`buckets`][Self::buckets], and `sum` and `count` are the sum and count of all samples.
+    ///
+    /// If `buckets` is empty, returns `None`.
+    pub fn from_buckets(buckets: &[(f64, u64)], sum: f64, count: u64) -> Option<Histogram> {
+        if buckets.is_empty() {
+            return None;
+        }
+
+        let (bounds, buckets) = buckets.iter().copied().unzip();
+
+        Some(Histogram { count, bounds, buckets, sum })
+    }
+
     /// Gets the sum of all samples.
     pub fn sum(&self) -> f64 {
         self.sum
@@ -42,7 +58,9 @@ impl Histogram {
     /// Gets the buckets.
     ///
     /// Buckets are tuples, where the first element is the bucket limit itself, and the second
-    /// element is the count of samples in that bucket.
+    /// element is the count of samples in that bucket.  Counts are cumulative: each bucket counts
+    /// all samples that are less than or equal to its limit, including those counted by buckets
+    /// with a smaller limit.
     pub fn buckets(&self) -> Vec<(f64, u64)> {
         self.bounds.iter().cloned().zip(self.buckets.iter().cloned()).collect()
     }
@@ -96,6 +114,23 @@ impl Histogram {
         self.sum += sum;
         self.count += count;
     }
+
+    /// Merges the samples of another histogram into this one.
+    ///
+    /// If the bounds of the two histograms differ, their buckets cannot be merged, so this
+    /// histogram is left untouched and `false` is returned.
+    pub fn merge(&mut self, other: &Histogram) -> bool {
+        if self.bounds != other.bounds {
+            return false;
+        }
+
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+        true
+    }
 }
 
 #[cfg(test)]
@@ -129,4 +164,35 @@ mod tests {
         assert_eq!(histogram.count(), values.len() as u64 + 1);
         assert_eq!(histogram.sum(), 581.0);
     }
+
+    #[test]
+    fn test_histogram_merge() {
+        let buckets = &[10.0, 25.0, 100.0];
+
+        let mut first = Histogram::new(buckets).expect("histogram should have been created");
+        first.record_many(&[3.0, 12.0, 56.0]);
+        let mut second = Histogram::new(buckets).expect("histogram should have been created");
+        second.record_many(&[2.0, 202.0]);
+
+        assert!(first.merge(&second));
+        assert_eq!(first.buckets(), vec![(10.0, 2), (25.0, 3), (100.0, 4)]);
+        assert_eq!(first.count(), 5);
+        assert_eq!(first.sum(), 275.0);
+
+        let other = Histogram::new(&[10.0]).expect("histogram should have been created");
+        assert!(!first.merge(&other));
+        assert_eq!(first.count(), 5);
+    }
+
+    #[test]
+    fn test_histogram_from_buckets() {
+        assert!(Histogram::from_buckets(&[], 0.0, 0).is_none());
+
+        let buckets = &[(10.0, 2), (25.0, 3), (100.0, 4)];
+        let histogram =
+            Histogram::from_buckets(buckets, 275.0, 5).expect("histogram should have been created");
+        assert_eq!(histogram.buckets(), buckets.to_vec());
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.sum(), 275.0);
+    }
 }
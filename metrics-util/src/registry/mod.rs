@@ -440,6 +440,115 @@ where
             op(v)
         }
     }
+
+    /// Gets the given counter, or creates it if `create` allows it.
+    ///
+    /// The `op` function will be called for the counter under the given `key`.  If the counter does
+    /// not already exist, `create` is called first, while holding the lock that the counter is
+    /// created under, such that it is called exactly once for every counter that is created, even
+    /// when racing with other callers.  If `create` returns `false`, the counter is not created, and
+    /// `None` is returned.
+    pub fn get_or_try_create_counter<C, O, V>(&self, key: &K, create: C, op: O) -> Option<V>
+    where
+        C: FnOnce() -> bool,
+        O: FnOnce(&S::Counter) -> V,
+    {
+        let (hash, shard) = self.get_hash_and_shard_for_counter(key);
+
+        // Try and get the handle if it exists, running our operation if we succeed.
+        let shard_read = shard.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, v)) = shard_read.raw_entry().from_key_hashed_nocheck(hash, key) {
+            return Some(op(v));
+        }
+
+        // Switch to write guard, and only create the handle if it still doesn't exist.
+        drop(shard_read);
+        let mut shard_write = shard.write().unwrap_or_else(PoisonError::into_inner);
+        let v = match shard_write.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(entry) => entry.into_mut(),
+            RawEntryMut::Vacant(entry) => {
+                if !create() {
+                    return None;
+                }
+                entry.insert_hashed_nocheck(hash, key.clone(), self.storage.counter(key)).1
+            }
+        };
+
+        Some(op(v))
+    }
+
+    /// Gets the given gauge, or creates it if `create` allows it.
+    ///
+    /// The `op` function will be called for the gauge under the given `key`.  If the gauge does
+    /// not already exist, `create` is called first, while holding the lock that the gauge is
+    /// created under, such that it is called exactly once for every gauge that is created, even
+    /// when racing with other callers.  If `create` returns `false`, the gauge is not created, and
+    /// `None` is returned.
+    pub fn get_or_try_create_gauge<C, O, V>(&self, key: &K, create: C, op: O) -> Option<V>
+    where
+        C: FnOnce() -> bool,
+        O: FnOnce(&S::Gauge) -> V,
+    {
+        let (hash, shard) = self.get_hash_and_shard_for_gauge(key);
+
+        // Try and get the handle if it exists, running our operation if we succeed.
+        let shard_read = shard.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, v)) = shard_read.raw_entry().from_key_hashed_nocheck(hash, key) {
+            return Some(op(v));
+        }
+
+        // Switch to write guard, and only create the handle if it still doesn't exist.
+        drop(shard_read);
+        let mut shard_write = shard.write().unwrap_or_else(PoisonError::into_inner);
+        let v = match shard_write.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(entry) => entry.into_mut(),
+            RawEntryMut::Vacant(entry) => {
+                if !create() {
+                    return None;
+                }
+                entry.insert_hashed_nocheck(hash, key.clone(), self.storage.gauge(key)).1
+            }
+        };
+
+        Some(op(v))
+    }
+
+    /// Gets the given histogram, or creates it if `create` allows it.
+    ///
+    /// The `op` function will be called for the histogram under the given `key`.  If the histogram does
+    /// not already exist, `create` is called first, while holding the lock that the histogram is
+    /// created under, such that it is called exactly once for every histogram that is created, even
+    /// when racing with other callers.  If `create` returns `false`, the histogram is not created, and
+    /// `None` is returned.
+    pub fn get_or_try_create_histogram<C, O, V>(&self, key: &K, create: C, op: O) -> Option<V>
+    where
+        C: FnOnce() -> bool,
+        O: FnOnce(&S::Histogram) -> V,
+    {
+        let (hash, shard) = self.get_hash_and_shard_for_histogram(key);
+
+        // Try and get the handle if it exists, running our operation if we succeed.
+        let shard_read = shard.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some((_, v)) = shard_read.raw_entry().from_key_hashed_nocheck(hash, key) {
+            return Some(op(v));
+        }
+
+        // Switch to write guard, and only create the handle if it still doesn't exist.
+        drop(shard_read);
+        let mut shard_write = shard.write().unwrap_or_else(PoisonError::into_inner);
+        let v = match shard_write.raw_entry_mut().from_key_hashed_nocheck(hash, key) {
+            RawEntryMut::Occupied(entry) => entry.into_mut(),
+            RawEntryMut::Vacant(entry) => {
+                if !create() {
+                    return None;
+                }
+                entry.insert_hashed_nocheck(hash, key.clone(), self.storage.histogram(key)).1
+            }
+        };
+
+        Some(op(v))
+    }
+
     /// Gets a map of all present counters, mapped by key.
     ///
     /// This map is a point-in-time snapshot of the registry.
@@ -534,4 +643,36 @@ mod tests {
         let entries = registry.get_counter_handles();
         assert_eq!(entries.len(), 0);
     }
+
+    #[test]
+    fn test_get_or_try_create() {
+        let registry = Registry::atomic();
+        let key = Key::from_name("foobar");
+
+        let rejected = registry.get_or_try_create_counter(&key, || false, |_| ());
+        assert!(rejected.is_none());
+        assert!(registry.get_counter(&key).is_none());
+
+        let mut created = 0;
+        for _ in 0..2 {
+            let value = registry.get_or_try_create_counter(
+                &key,
+                || {
+                    created += 1;
+                    true
+                },
+                |c: &Arc<AtomicU64>| {
+                    c.increment(1);
+                    c.load(Ordering::SeqCst)
+                },
+            );
+            assert!(value.is_some());
+        }
+        assert_eq!(created, 1);
+
+        // Existing metrics are returned without asking whether they may be created.
+        let value =
+            registry.get_or_try_create_counter(&key, || false, |c| c.load(Ordering::SeqCst));
+        assert_eq!(value, Some(2));
+    }
 }